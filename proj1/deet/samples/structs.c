@@ -0,0 +1,52 @@
+#include <stdio.h>
+#include <stdlib.h>
+
+enum color { RED, GREEN, BLUE = 10 };
+
+typedef struct node {
+    int value;
+    enum color color;
+    struct node *next;
+} node_t;
+
+struct point {
+    double x, y;
+};
+
+int primes[8] = {2, 3, 5, 7, 11, 13, 17, 19};
+const char *greeting = "Hello, structs!";
+
+int sum_list(node_t *head) {
+    int sum = 0;
+    for (node_t *n = head; n != NULL; n = n->next) {
+        sum += n->value;
+    }
+    return sum;
+}
+
+void dump_list(node_t *head) {
+    for (node_t *n = head; n != NULL; n = n->next) {
+        printf("%d -> ", n->value);
+    }
+    printf("NULL\n");
+}
+
+double scale(double value, int factor) {
+    return value * factor;
+}
+
+int main() {
+    node_t *head = NULL;
+    for (int i = 0; i < 3; i++) {
+        node_t *n = malloc(sizeof(node_t));
+        n->value = primes[i + 2];
+        n->color = (enum color)i;
+        n->next = head;
+        head = n;
+    }
+    struct point origin = {1.5, -2.25};
+    int grid[2][3] = {{1, 2, 3}, {4, 5, 6}};
+    printf("sum = %d, origin = (%g, %g), grid[1][2] = %d\n", sum_list(head), origin.x, origin.y,
+           grid[1][2]);
+    return 0;
+}
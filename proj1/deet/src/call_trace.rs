@@ -0,0 +1,291 @@
+//! Function call tracing (`--trace-calls`): runs a program with a breakpoint at the entry of
+//! every function whose name matches a pattern and another at the return address of each call,
+//! printing the tree of calls with their arguments and return values, or writing it as a Chrome
+//! trace (chrome://tracing, Perfetto) with timestamps.
+//!
+//! Arguments are read from the registers the System V ABI passes them in, since at the entry of
+//! a function they have not been stored in its frame yet. Timestamps include the time spent
+//! stopped at the breakpoints, so short calls look longer than they are.
+
+use regex::Regex;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::time::Instant;
+
+use crate::dwarf_data::{DwarfData, Function, TypeKind};
+use crate::expr::{CType, Evaluator};
+use crate::inferior::Inferior;
+use crate::target::Target;
+
+/// A call that has not returned yet.
+struct Call<'a> {
+    function: &'a Function,
+    return_addr: u64,
+    /// Stack pointer once the call has returned
+    caller_rsp: u64,
+}
+
+/// Where the trace goes: an indented tree on stderr, or Chrome trace events for a file.
+enum Output {
+    Tree,
+    Chrome { file: File, events: Vec<serde_json::Value>, pid: i32 },
+}
+
+/// Runs `target` to completion, tracing calls to the functions matching `pattern` (all functions
+/// with debugging information if None). The trace is printed to stderr, or written to
+/// `chrome_path` as Chrome trace events. Returns the exit status deet should exit with (see
+/// `Status::exit_code`).
+pub fn trace_calls(
+    target: &str,
+    args: &Vec<String>,
+    pattern: Option<&str>,
+    chrome_path: Option<&str>,
+) -> i32 {
+    let debug_data = match DwarfData::load_for_tool(target) {
+        Some(debug_data) => debug_data,
+        None => return 1,
+    };
+    let regex = match pattern.map(Regex::new).transpose() {
+        Ok(regex) => regex,
+        Err(err) => {
+            eprintln!("Invalid pattern: {}", err);
+            return 1;
+        }
+    };
+    let file = match chrome_path.map(File::create).transpose() {
+        Ok(file) => file,
+        Err(err) => {
+            eprintln!("Could not create {}: {}", chrome_path.unwrap(), err);
+            return 1;
+        }
+    };
+    // Declarations have no code
+    let entries: HashMap<u64, &Function> = debug_data
+        .get_functions()
+        .into_iter()
+        .filter(|func| func.address != 0)
+        .filter(|func| regex.as_ref().map_or(true, |regex| regex.is_match(&func.name)))
+        .map(|func| (func.address as u64, func))
+        .collect();
+    let breaks = entries.keys().cloned().collect();
+    let mut inferior = match Inferior::start(target, args, &breaks) {
+        Some(inferior) => inferior,
+        None => return 1,
+    };
+    let mut output = match file {
+        Some(file) => Output::Chrome { file, events: Vec::new(), pid: inferior.pid().as_raw() },
+        None => Output::Tree,
+    };
+    let start = Instant::now();
+    let mut stack: Vec<Call> = Vec::new();
+    let status = inferior.run_to_exit(|inferior, sig, rip| {
+        let timestamp = start.elapsed().as_secs_f64() * 1e6;
+        let addr = (rip as u64).wrapping_sub(1);
+        if sig != nix::sys::signal::SIGTRAP || !inferior.bp_map.contains_key(&addr) {
+            // Not ours
+            return Ok(Some(sig));
+        }
+        let result = match entries.get(&addr) {
+            Some(function) => {
+                enter(inferior, &debug_data, function, &mut stack, &mut output, timestamp)
+            }
+            None => leave(inferior, &debug_data, addr, &mut stack, &mut output, timestamp),
+        };
+        result.map(|_| None).map_err(|err| err.to_string())
+    });
+    let code = match status {
+        Ok(status) => status.exit_code(),
+        Err(err) => {
+            eprintln!("Error tracing subprocess: {}", err);
+            return 1;
+        }
+    };
+    // Calls that never returned, e.g. to exit()
+    let timestamp = start.elapsed().as_secs_f64() * 1e6;
+    while let Some(call) = stack.pop() {
+        output.end(&call, stack.len(), None, timestamp);
+    }
+    if let Output::Chrome { mut file, events, .. } = output {
+        let trace = json!({ "traceEvents": events, "displayTimeUnit": "ms" });
+        if let Err(err) = writeln!(file, "{}", trace) {
+            eprintln!("Could not write {}: {}", chrome_path.unwrap(), err);
+            return 1;
+        }
+    }
+    code
+}
+
+/// Handles a stop at the entry of `function`: records the call and sets a breakpoint at its
+/// return address.
+fn enter<'a>(
+    inferior: &mut Inferior,
+    debug_data: &DwarfData,
+    function: &'a Function,
+    stack: &mut Vec<Call<'a>>,
+    output: &mut Output,
+    timestamp: f64,
+) -> Result<(), nix::Error> {
+    let regs = inferior.regs()?;
+    let return_addr = inferior.read_word(regs.rsp)?;
+    let int_regs = [regs.rdi, regs.rsi, regs.rdx, regs.rcx, regs.r8, regs.r9];
+    let xmm = inferior.xmm_registers()?;
+    let evaluator = Evaluator::new(&*inferior, debug_data, function.address, 0);
+    let (mut next_int, mut next_float) = (0, 0);
+    // Set once an argument's registers can't be told, which puts the rest out of step
+    let mut lost = false;
+    let mut args = Vec::new();
+    for (i, offset) in function.parameters.iter().enumerate() {
+        let ctype = CType::from_dwarf(debug_data, Some(*offset));
+        // Parameters come first among the variables of a function
+        let name = function.variables.get(i).map_or("?", |var| var.name.as_str());
+        let bits = match ctype {
+            _ if lost => None,
+            CType::Float { .. } => {
+                next_float += 1;
+                xmm.get(next_float - 1)
+            }
+            CType::Struct(_) => {
+                match int_registers(debug_data, &ctype) {
+                    Some(count) if next_int + count <= int_regs.len() => next_int += count,
+                    _ => lost = true,
+                }
+                None
+            }
+            _ => {
+                next_int += 1;
+                int_regs.get(next_int - 1)
+            }
+        };
+        let value = match bits.map(|bits| evaluator.register_value(ctype, *bits)) {
+            Some(Ok(value)) => evaluator.format(&value),
+            _ => "...".to_string(),
+        };
+        args.push((name.to_string(), value));
+    }
+    if !inferior.bp_map.contains_key(&return_addr) {
+        let orig_byte = inferior.write_byte(return_addr, 0xcc)?;
+        inferior.bp_map.insert(return_addr, orig_byte);
+    }
+    let call = Call { function, return_addr, caller_rsp: regs.rsp + 8 };
+    output.begin(&call, stack.len(), &args, timestamp);
+    stack.push(call);
+    Ok(())
+}
+
+/// Returns how many integer registers the System V ABI passes a struct of type `ctype` in, if
+/// it is 16 bytes at most and its members are all integers or pointers. Other structs go in SSE
+/// registers, in both kinds or on the stack, which isn't worked out.
+fn int_registers(debug_data: &DwarfData, ctype: &CType) -> Option<usize> {
+    let members = match ctype {
+        CType::Struct(offset) => match debug_data.get_type(*offset).map(|dtype| &dtype.kind) {
+            Some(TypeKind::Struct(members)) => members,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    let integral = members.iter().all(|member| {
+        let member_type = CType::from_dwarf(debug_data, Some(member.type_offset));
+        matches!(member_type, CType::Int { .. } | CType::Pointer(_) | CType::Enum(..))
+    });
+    let size = ctype.size(debug_data);
+    if integral && size <= 16 {
+        Some((size + 7) / 8)
+    } else {
+        None
+    }
+}
+
+/// Handles a stop at a return address: reports the call that returned there, if any. The same
+/// address is also reached by other calls from the same place, so the stack pointer tells which.
+fn leave(
+    inferior: &Inferior,
+    debug_data: &DwarfData,
+    addr: u64,
+    stack: &mut Vec<Call>,
+    output: &mut Output,
+    timestamp: f64,
+) -> Result<(), nix::Error> {
+    let regs = inferior.regs()?;
+    // Calls whose frames are gone without returning, e.g. through longjmp
+    while stack.last().map_or(false, |call| call.caller_rsp < regs.rsp) {
+        let call = stack.pop().unwrap();
+        output.end(&call, stack.len(), None, timestamp);
+    }
+    match stack.last() {
+        Some(call) if call.return_addr == addr && call.caller_rsp == regs.rsp => {}
+        _ => return Ok(()),
+    }
+    let call = stack.pop().unwrap();
+    let return_type = CType::from_dwarf(debug_data, call.function.return_type);
+    let value = match return_type {
+        CType::Void => None,
+        CType::Float { .. } => Some(inferior.xmm_registers()?[0]),
+        _ => Some(regs.rax),
+    };
+    let evaluator = Evaluator::new(inferior, debug_data, addr as usize, regs.rbp as usize);
+    let value = value.map(|bits| match evaluator.register_value(return_type, bits) {
+        Ok(value) => evaluator.format(&value),
+        Err(_) => "...".to_string(),
+    });
+    output.end(&call, stack.len(), value, timestamp);
+    Ok(())
+}
+
+impl Output {
+    fn begin(&mut self, call: &Call, depth: usize, args: &[(String, String)], timestamp: f64) {
+        match self {
+            Output::Tree => {
+                let args: Vec<String> =
+                    args.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+                let indent = "  ".repeat(depth);
+                eprintln!("{}{}({}) {{", indent, call.function.name, args.join(", "));
+            }
+            Output::Chrome { events, pid, .. } => {
+                let args: serde_json::Map<String, serde_json::Value> =
+                    args.iter().map(|(name, value)| (name.clone(), json!(value))).collect();
+                events.push(chrome_event(call, *pid, "B", timestamp, json!(args)));
+            }
+        }
+    }
+
+    /// Reports the end of a call, with its return value unless it is void or never returned.
+    fn end(&mut self, call: &Call, depth: usize, value: Option<String>, timestamp: f64) {
+        match self {
+            Output::Tree => {
+                let indent = "  ".repeat(depth);
+                match value {
+                    Some(value) => eprintln!("{}}} = {}", indent, value),
+                    None => eprintln!("{}}}", indent),
+                }
+            }
+            Output::Chrome { events, pid, .. } => {
+                let args = match value {
+                    Some(value) => json!({ "return": value }),
+                    None => json!({}),
+                };
+                events.push(chrome_event(call, *pid, "E", timestamp, args));
+            }
+        }
+    }
+}
+
+/// Makes a duration event: phase B begins a call and E ends it. Timestamps are in microseconds.
+fn chrome_event(
+    call: &Call,
+    pid: i32,
+    phase: &str,
+    timestamp: f64,
+    args: serde_json::Value,
+) -> serde_json::Value {
+    json!({
+        "name": call.function.name,
+        "cat": "function",
+        "ph": phase,
+        "ts": timestamp,
+        "pid": pid,
+        "tid": pid,
+        "args": args,
+    })
+}
@@ -0,0 +1,82 @@
+//! Tab completion at the `(deet) ` prompt: command names, function names for `break`, and paths
+//! for the commands that take a file.
+
+use crate::debugger_command::{self, COMMANDS};
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+
+pub struct DeetHelper {
+    /// Names of the functions in the target, for `break`
+    functions: Vec<String>,
+    files: FilenameCompleter,
+}
+
+impl DeetHelper {
+    pub fn new(functions: Vec<String>) -> DeetHelper {
+        DeetHelper { functions, files: FilenameCompleter::new() }
+    }
+}
+
+/// Turns the names starting with `word` into completion candidates.
+fn candidates<'a>(names: impl Iterator<Item = &'a str>, word: &str) -> Vec<Pair> {
+    let mut names: Vec<&str> = names.filter(|name| name.starts_with(word)).collect();
+    names.sort();
+    names.dedup();
+    names
+        .into_iter()
+        .map(|name| Pair { display: name.to_string(), replacement: name.to_string() })
+        .collect()
+}
+
+fn command_names() -> impl Iterator<Item = &'static str> {
+    COMMANDS
+        .iter()
+        .flat_map(|info| std::iter::once(info.name).chain(info.aliases.iter().copied()))
+}
+
+impl Completer for DeetHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        ctx: &Context<'_>,
+    ) -> Result<(usize, Vec<Pair>), ReadlineError> {
+        let before = &line[..pos];
+        let start = before.rfind(char::is_whitespace).map(|i| i + 1).unwrap_or(0);
+        let word = &before[start..];
+        let mut args = before[..start].split_whitespace();
+        let command = match args.next() {
+            Some(command) => command,
+            None => return Ok((start, candidates(command_names(), word))),
+        };
+        let command = command.split('/').next().unwrap();
+        // Only the first argument of these commands is completed
+        let first_arg = args.next().is_none();
+        match debugger_command::lookup(command).map(|info| info.name) {
+            Ok("break") if first_arg => {
+                Ok((start, candidates(self.functions.iter().map(|s| s.as_str()), word)))
+            }
+            Ok("help") if first_arg => Ok((start, candidates(command_names(), word))),
+            Ok("info") if first_arg => {
+                let topics = ["threads", "breakpoints", "display", "checkpoints", "record"];
+                Ok((start, candidates(topics.iter().copied(), word)))
+            }
+            Ok("source") | Ok("gcore") if first_arg => self.files.complete(line, pos, ctx),
+            _ => Ok((pos, Vec::new())),
+        }
+    }
+}
+
+impl Hinter for DeetHelper {}
+
+impl Highlighter for DeetHelper {}
+
+impl Validator for DeetHelper {}
+
+impl Helper for DeetHelper {}
@@ -0,0 +1,440 @@
+//! Post-mortem debugging support: reads an ELF core file so that its registers and memory can be
+//! inspected through the same `Target` interface as a live inferior, and writes core files of a
+//! live inferior (`gcore`).
+//!
+//! Only 64-bit little-endian x86_64 cores are supported. The ELF structures are small enough that
+//! they are parsed by hand rather than through `object`.
+
+use nix::libc::user_regs_struct;
+use nix::sys::ptrace;
+use nix::unistd::Pid;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::slice;
+
+use crate::target::{self, Target};
+
+const ET_CORE: u16 = 4;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+const NT_PRPSINFO: u32 = 3;
+const NT_AUXV: u32 = 6;
+const EM_X86_64: u16 = 62;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const PAGE_SIZE: u64 = 4096;
+/// sizeof(struct elf_prstatus) on x86_64
+const PRSTATUS_SIZE: usize = 336;
+/// sizeof(struct elf_prpsinfo) on x86_64
+const PRPSINFO_SIZE: usize = 136;
+
+/// Offset of pr_cursig within struct elf_prstatus on x86_64
+const PRSTATUS_CURSIG_OFFSET: usize = 12;
+/// Offset of pr_pid within struct elf_prstatus on x86_64
+const PRSTATUS_PID_OFFSET: usize = 32;
+/// Offset of pr_reg (laid out like user_regs_struct) within struct elf_prstatus on x86_64
+const PRSTATUS_REG_OFFSET: usize = 112;
+
+#[derive(Debug)]
+pub enum Error {
+    ErrorOpeningFile,
+    FormatError(&'static str),
+}
+
+/// A PT_LOAD segment of one of the mapped ELF images.
+struct Segment {
+    image: usize,
+    vaddr: u64,
+    memsz: u64,
+    offset: u64,
+    filesz: u64,
+}
+
+pub struct CoreFile {
+    /// The core file itself, followed by the executable it was generated from. Code segments of
+    /// the executable are usually left out of the core, so reads fall back to the executable.
+    images: Vec<memmap::Mmap>,
+    segments: Vec<Segment>,
+    regs: user_regs_struct,
+    signal: i32,
+    pid: i32,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().unwrap()))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().unwrap()))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(offset..offset + 8)?.try_into().unwrap()))
+}
+
+fn map_file(path: &str) -> Result<memmap::Mmap, Error> {
+    let file = fs::File::open(path).or(Err(Error::ErrorOpeningFile))?;
+    unsafe { memmap::Mmap::map(&file).or(Err(Error::ErrorOpeningFile)) }
+}
+
+/// Returns (e_type, [(p_type, p_offset, p_vaddr, p_filesz, p_memsz)]) for a 64-bit ELF image.
+fn program_headers(data: &[u8]) -> Result<(u16, Vec<(u32, u64, u64, u64, u64)>), Error> {
+    if data.get(0..4) != Some(&b"\x7fELF"[..]) {
+        return Err(Error::FormatError("not an ELF file"));
+    }
+    if data.get(4..6) != Some(&[2u8, 1][..]) {
+        return Err(Error::FormatError("only 64-bit little-endian ELF files are supported"));
+    }
+    let header = (
+        read_u16(data, 0x10),
+        read_u64(data, 0x20),
+        read_u16(data, 0x36),
+        read_u16(data, 0x38),
+    );
+    let (e_type, phoff, phentsize, phnum) = match header {
+        (Some(e_type), Some(phoff), Some(phentsize), Some(phnum)) => {
+            (e_type, phoff as usize, phentsize as usize, phnum as usize)
+        }
+        _ => return Err(Error::FormatError("truncated ELF header")),
+    };
+    let mut headers = Vec::new();
+    for i in 0..phnum {
+        let ph = phoff + i * phentsize;
+        let field = |offset| read_u64(data, ph + offset);
+        match (read_u32(data, ph), field(8), field(16), field(32), field(40)) {
+            (Some(p_type), Some(offset), Some(vaddr), Some(filesz), Some(memsz)) => {
+                headers.push((p_type, offset, vaddr, filesz, memsz))
+            }
+            _ => return Err(Error::FormatError("truncated program header table")),
+        }
+    }
+    Ok((e_type, headers))
+}
+
+/// Iterates over the (type, descriptor) pairs of a PT_NOTE segment.
+fn notes(data: &[u8]) -> Vec<(u32, &[u8])> {
+    let align4 = |n: usize| (n + 3) & !3;
+    let mut notes = Vec::new();
+    let mut pos = 0;
+    while let (Some(namesz), Some(descsz), Some(n_type)) =
+        (read_u32(data, pos), read_u32(data, pos + 4), read_u32(data, pos + 8))
+    {
+        let desc_start = pos + 12 + align4(namesz as usize);
+        let desc_end = desc_start + descsz as usize;
+        match data.get(desc_start..desc_end) {
+            Some(desc) => notes.push((n_type, desc)),
+            None => break,
+        }
+        pos = align4(desc_end);
+    }
+    notes
+}
+
+impl CoreFile {
+    /// Loads a core file generated by `executable`.
+    pub fn open(path: &str, executable: &str) -> Result<CoreFile, Error> {
+        let core = map_file(path)?;
+        let (e_type, headers) = program_headers(&core)?;
+        if e_type != ET_CORE {
+            return Err(Error::FormatError("not a core file"));
+        }
+
+        let mut segments = Vec::new();
+        let mut prstatus = None;
+        for &(p_type, offset, vaddr, filesz, memsz) in &headers {
+            match p_type {
+                PT_LOAD => segments.push(Segment { image: 0, vaddr, memsz, offset, filesz }),
+                PT_NOTE => {
+                    let data = offset
+                        .checked_add(filesz)
+                        .and_then(|end| core.get(offset as usize..end as usize))
+                        .ok_or(Error::FormatError("truncated PT_NOTE segment"))?;
+                    // The first NT_PRSTATUS belongs to the thread that received the signal
+                    if let Some((_, desc)) = notes(data).into_iter().find(|n| n.0 == NT_PRSTATUS) {
+                        if prstatus.is_none() {
+                            prstatus = Some(desc.to_vec());
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        let prstatus = prstatus.ok_or(Error::FormatError("core file has no NT_PRSTATUS note"))?;
+        let reg_bytes = prstatus
+            .get(PRSTATUS_REG_OFFSET..PRSTATUS_REG_OFFSET + size_of::<user_regs_struct>())
+            .ok_or(Error::FormatError("NT_PRSTATUS note is too short"))?;
+        let regs =
+            unsafe { std::ptr::read_unaligned(reg_bytes.as_ptr() as *const user_regs_struct) };
+        let signal = read_u16(&prstatus, PRSTATUS_CURSIG_OFFSET).unwrap() as i32;
+        let pid = read_u32(&prstatus, PRSTATUS_PID_OFFSET).unwrap() as i32;
+
+        let mut images = vec![core];
+        if let Ok(exe) = map_file(executable) {
+            if let Ok((_, exe_headers)) = program_headers(&exe) {
+                for (p_type, offset, vaddr, filesz, memsz) in exe_headers {
+                    if p_type == PT_LOAD {
+                        segments.push(Segment { image: 1, vaddr, memsz, offset, filesz });
+                    }
+                }
+                images.push(exe);
+            }
+        }
+
+        Ok(CoreFile { images, segments, regs, signal, pid })
+    }
+
+    /// Returns the number of the signal that caused the core dump.
+    pub fn signal(&self) -> i32 {
+        self.signal
+    }
+
+    pub fn pid(&self) -> i32 {
+        self.pid
+    }
+
+    fn read_byte(&self, addr: u64) -> Option<u8> {
+        // Segments of the core come first, so they take precedence over the executable
+        for seg in &self.segments {
+            if addr < seg.vaddr || addr >= seg.vaddr + seg.memsz {
+                continue;
+            }
+            let offset_in_seg = addr - seg.vaddr;
+            if offset_in_seg < seg.filesz {
+                return self.images[seg.image].get((seg.offset + offset_in_seg) as usize).copied();
+            }
+            // Not backed by the file. In the executable that means .bss, which is zeroed; in the
+            // core it means the kernel chose not to dump the mapping, so try the executable.
+            if seg.image != 0 {
+                return Some(0);
+            }
+        }
+        None
+    }
+}
+
+impl Target for CoreFile {
+    fn regs(&self) -> Result<user_regs_struct, nix::Error> {
+        Ok(self.regs)
+    }
+
+    fn read_memory(&self, addr: u64, len: usize) -> Result<Vec<u8>, nix::Error> {
+        (addr..addr + len as u64)
+            .map(|a| self.read_byte(a).ok_or_else(target::unmapped))
+            .collect()
+    }
+}
+
+/// A readable mapping from /proc/<pid>/maps.
+struct Mapping {
+    start: u64,
+    end: u64,
+    flags: u32,
+}
+
+fn read_mappings(pid: Pid) -> io::Result<Vec<Mapping>> {
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid))?;
+    let mut mappings = Vec::new();
+    for line in maps.lines() {
+        // e.g. "00400000-00401000 r--p 00000000 08:01 1234   /path/to/binary"
+        let mut fields = line.split_whitespace();
+        let (range, perms) = match (fields.next(), fields.next()) {
+            (Some(range), Some(perms)) => (range, perms),
+            _ => continue,
+        };
+        let name = fields.nth(3).unwrap_or("");
+        // [vvar] can't be read through /proc/<pid>/mem and [vsyscall] lives in kernel space
+        if !perms.starts_with('r') || name == "[vvar]" || name == "[vsyscall]" {
+            continue;
+        }
+        let mut bounds = range.split('-').map(|n| u64::from_str_radix(n, 16).ok());
+        if let (Some(Some(start)), Some(Some(end))) = (bounds.next(), bounds.next()) {
+            let mut flags = PF_R;
+            if perms.as_bytes()[1] == b'w' {
+                flags |= PF_W;
+            }
+            if perms.as_bytes()[2] == b'x' {
+                flags |= PF_X;
+            }
+            mappings.push(Mapping { start, end, flags });
+        }
+    }
+    Ok(mappings)
+}
+
+fn push_note(buf: &mut Vec<u8>, n_type: u32, desc: &[u8]) {
+    let name = b"CORE\0";
+    buf.extend_from_slice(&(name.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&n_type.to_le_bytes());
+    buf.extend_from_slice(name);
+    buf.resize((buf.len() + 3) & !3, 0);
+    buf.extend_from_slice(desc);
+    buf.resize((buf.len() + 3) & !3, 0);
+}
+
+fn put(desc: &mut [u8], offset: usize, bytes: &[u8]) {
+    desc[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
+
+/// Builds the PT_NOTE contents (NT_PRSTATUS, NT_PRPSINFO and NT_AUXV) for a stopped process.
+fn build_notes(pid: Pid, regs: &user_regs_struct) -> io::Result<Vec<u8>> {
+    // /proc/<pid>/stat: "pid (comm) state ppid pgrp session ..."
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    let comm_end = stat.rfind(')').unwrap_or(0);
+    let comm = stat.get(stat.find('(').map_or(0, |i| i + 1)..comm_end).unwrap_or("");
+    let stat_fields: Vec<&str> = stat[comm_end + 1..].split_whitespace().collect();
+    let stat_field = |i: usize| stat_fields.get(i).and_then(|f| f.parse().ok()).unwrap_or(0i32);
+    let (state, ppid, pgrp, sid) = (
+        stat_fields.get(0).and_then(|s| s.bytes().next()).unwrap_or(b'?'),
+        stat_field(1),
+        stat_field(2),
+        stat_field(3),
+    );
+    // The signal that put the process in its current stop, if it's a signal-delivery-stop
+    let signal = ptrace::getsiginfo(pid).map(|info| info.si_signo).unwrap_or(0);
+
+    let mut prstatus = vec![0u8; PRSTATUS_SIZE];
+    put(&mut prstatus, 0, &signal.to_le_bytes());
+    put(&mut prstatus, PRSTATUS_CURSIG_OFFSET, &(signal as u16).to_le_bytes());
+    put(&mut prstatus, PRSTATUS_PID_OFFSET, &pid.as_raw().to_le_bytes());
+    put(&mut prstatus, PRSTATUS_PID_OFFSET + 4, &ppid.to_le_bytes());
+    put(&mut prstatus, PRSTATUS_PID_OFFSET + 8, &pgrp.to_le_bytes());
+    put(&mut prstatus, PRSTATUS_PID_OFFSET + 12, &sid.to_le_bytes());
+    let reg_bytes = unsafe {
+        let regs_ptr = regs as *const user_regs_struct as *const u8;
+        slice::from_raw_parts(regs_ptr, size_of::<user_regs_struct>())
+    };
+    put(&mut prstatus, PRSTATUS_REG_OFFSET, reg_bytes);
+
+    let mut prpsinfo = vec![0u8; PRPSINFO_SIZE];
+    prpsinfo[1] = state;
+    put(&mut prpsinfo, 24, &pid.as_raw().to_le_bytes());
+    put(&mut prpsinfo, 28, &ppid.to_le_bytes());
+    put(&mut prpsinfo, 32, &pgrp.to_le_bytes());
+    put(&mut prpsinfo, 36, &sid.to_le_bytes());
+    let fname = comm.as_bytes();
+    put(&mut prpsinfo, 40, &fname[..fname.len().min(15)]);
+    let mut psargs = fs::read(format!("/proc/{}/cmdline", pid)).unwrap_or_default();
+    for byte in psargs.iter_mut() {
+        if *byte == 0 {
+            *byte = b' ';
+        }
+    }
+    let psargs = String::from_utf8_lossy(&psargs).trim_end().to_string();
+    put(&mut prpsinfo, 56, &psargs.as_bytes()[..psargs.len().min(79)]);
+
+    let auxv = fs::read(format!("/proc/{}/auxv", pid)).unwrap_or_default();
+
+    let mut notes = Vec::new();
+    push_note(&mut notes, NT_PRSTATUS, &prstatus);
+    push_note(&mut notes, NT_PRPSINFO, &prpsinfo);
+    push_note(&mut notes, NT_AUXV, &auxv);
+    Ok(notes)
+}
+
+fn push_program_header(
+    buf: &mut Vec<u8>,
+    p_type: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+    memsz: u64,
+) {
+    let align = if p_type == PT_LOAD { PAGE_SIZE } else { 4 };
+    buf.extend_from_slice(&p_type.to_le_bytes());
+    buf.extend_from_slice(&flags.to_le_bytes());
+    buf.extend_from_slice(&offset.to_le_bytes());
+    buf.extend_from_slice(&vaddr.to_le_bytes());
+    buf.extend_from_slice(&0u64.to_le_bytes()); // p_paddr
+    buf.extend_from_slice(&filesz.to_le_bytes());
+    buf.extend_from_slice(&memsz.to_le_bytes());
+    buf.extend_from_slice(&align.to_le_bytes());
+}
+
+/// Writes an ELF core file of the stopped process `pid` to `path`, with one PT_LOAD segment per
+/// readable mapping. Mappings whose memory can't be read are recorded with no file contents.
+/// The original bytes of `breakpoints` are restored in the dump, and if the process is stopped
+/// right after one of them, rip is rewound onto it. Returns the number of PT_LOAD segments written.
+pub fn generate(
+    pid: Pid,
+    regs: &user_regs_struct,
+    breakpoints: &HashMap<u64, u8>,
+    path: &str,
+) -> io::Result<usize> {
+    let mappings = read_mappings(pid)?;
+    let mut regs = *regs;
+    if breakpoints.contains_key(&(regs.rip.wrapping_sub(1))) {
+        regs.rip -= 1;
+    }
+    let notes = build_notes(pid, &regs)?;
+    let mut mem = fs::File::open(format!("/proc/{}/mem", pid))?;
+
+    let phnum = 1 + mappings.len();
+    let notes_offset = (ELF_HEADER_SIZE + phnum * PROGRAM_HEADER_SIZE) as u64;
+    let align_page = |n: u64| (n + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+    // Read the contents of every mapping up front so that unreadable ones can be given an empty
+    // file image in the program header table
+    let mut contents = Vec::new();
+    for mapping in &mappings {
+        let mut data = vec![0u8; (mapping.end - mapping.start) as usize];
+        let readable = mem.seek(SeekFrom::Start(mapping.start)).is_ok()
+            && mem.read_exact(&mut data).is_ok();
+        if !readable {
+            contents.push(Vec::new());
+            continue;
+        }
+        for (&addr, &orig_byte) in breakpoints {
+            if mapping.start <= addr && addr < mapping.end {
+                data[(addr - mapping.start) as usize] = orig_byte;
+            }
+        }
+        contents.push(data);
+    }
+
+    let mut header = Vec::with_capacity(notes_offset as usize);
+    header.extend_from_slice(b"\x7fELF");
+    header.extend_from_slice(&[2, 1, 1, 0]); // 64-bit, little-endian, version 1, System V ABI
+    header.resize(16, 0);
+    header.extend_from_slice(&ET_CORE.to_le_bytes());
+    header.extend_from_slice(&EM_X86_64.to_le_bytes());
+    header.extend_from_slice(&1u32.to_le_bytes()); // e_version
+    header.extend_from_slice(&0u64.to_le_bytes()); // e_entry
+    header.extend_from_slice(&(ELF_HEADER_SIZE as u64).to_le_bytes()); // e_phoff
+    header.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+    header.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+    header.extend_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+    header.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    header.extend_from_slice(&(phnum as u16).to_le_bytes());
+    header.extend_from_slice(&[0; 6]); // e_shentsize, e_shnum, e_shstrndx
+
+    push_program_header(&mut header, PT_NOTE, 0, notes_offset, 0, notes.len() as u64, 0);
+    let mut offset = align_page(notes_offset + notes.len() as u64);
+    for (mapping, data) in mappings.iter().zip(&contents) {
+        let memsz = mapping.end - mapping.start;
+        let filesz = data.len() as u64;
+        push_program_header(
+            &mut header, PT_LOAD, mapping.flags, offset, mapping.start, filesz, memsz,
+        );
+        offset += filesz;
+    }
+
+    let mut file = io::BufWriter::new(fs::File::create(path)?);
+    file.write_all(&header)?;
+    file.write_all(&notes)?;
+    let notes_end = notes_offset + notes.len() as u64;
+    file.write_all(&vec![0u8; (align_page(notes_end) - notes_end) as usize])?;
+    for data in &contents {
+        file.write_all(data)?;
+    }
+    file.flush()?;
+    Ok(mappings.len())
+}
@@ -0,0 +1,132 @@
+//! Line coverage (`--coverage`) of unmodified `-g` binaries: the program runs with a one-shot
+//! breakpoint at the address of every line in the debugging information, each removed the first
+//! time it is hit, so the program runs at nearly full speed once its hot paths have been seen.
+//! The result is written as an lcov tracefile (for genhtml and editors) and summarized per file.
+//!
+//! Since breakpoints are one-shot, lines are only known to be executed or not: lcov execution
+//! counts are 1 for every line that ran.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::dwarf_data::DwarfData;
+use crate::inferior::Inferior;
+
+/// Runs `target` to completion, recording which lines run, then writes the lcov report to
+/// `output_path` and prints a summary to stderr. Returns the exit status deet should exit with
+/// (see `Status::exit_code`).
+pub fn coverage(target: &str, args: &Vec<String>, output_path: &str) -> i32 {
+    let debug_data = match DwarfData::load_for_tool(target) {
+        Some(debug_data) => debug_data,
+        None => return 1,
+    };
+    let breaks: HashSet<u64> = debug_data
+        .get_files()
+        .iter()
+        .flat_map(|file| file.lines.iter())
+        .filter(|line| line.number != 0)
+        .map(|line| line.address as u64)
+        .collect();
+    let mut inferior = match Inferior::start(target, args, &breaks.into_iter().collect()) {
+        Some(inferior) => inferior,
+        None => return 1,
+    };
+    let mut executed = HashSet::new();
+    let status = inferior.run_to_exit(|inferior, sig, rip| {
+        let addr = (rip as u64).wrapping_sub(1);
+        match inferior.bp_map.remove(&addr) {
+            Some(orig_byte) if sig == nix::sys::signal::SIGTRAP => {
+                executed.insert(addr);
+                // Run the original instruction as if the breakpoint had never been there
+                inferior.write_byte(addr, orig_byte).map_err(|err| err.to_string())?;
+                inferior.set_pc(addr).map_err(|err| err.to_string())?;
+                Ok(None)
+            }
+            other => {
+                if let Some(orig_byte) = other {
+                    inferior.bp_map.insert(addr, orig_byte);
+                }
+                Ok(Some(sig))
+            }
+        }
+    });
+    let code = match status {
+        Ok(status) => status.exit_code(),
+        Err(err) => {
+            eprintln!("Error running subprocess: {}", err);
+            return 1;
+        }
+    };
+    if let Err(err) = write_report(&debug_data, &executed, output_path) {
+        eprintln!("Could not write {}: {}", output_path, err);
+        return 1;
+    }
+    code
+}
+
+/// Writes the lcov tracefile and prints the summary.
+fn write_report(
+    debug_data: &DwarfData,
+    executed: &HashSet<u64>,
+    output_path: &str,
+) -> Result<(), io::Error> {
+    let mut out = BufWriter::new(File::create(output_path)?);
+    let (mut total_found, mut total_hit) = (0, 0);
+    eprintln!("{:<40} {:>8} {:>8} {:>8}", "File", "Lines", "Executed", "Cover");
+    for file in debug_data.get_files() {
+        // A line is executed if any of its addresses is
+        let mut lines: BTreeMap<usize, bool> = BTreeMap::new();
+        for line in file.lines.iter().filter(|line| line.number != 0) {
+            *lines.entry(line.number).or_insert(false) |= executed.contains(&(line.address as u64));
+        }
+        if lines.is_empty() {
+            continue;
+        }
+        let hits: Vec<(&str, bool)> = file
+            .functions
+            .iter()
+            .filter(|func| func.address != 0)
+            .map(|func| (func.name.as_str(), executed.contains(&(func.address as u64))))
+            .collect();
+        writeln!(out, "TN:")?;
+        writeln!(out, "SF:{}", file.name)?;
+        for func in file.functions.iter().filter(|func| func.address != 0) {
+            writeln!(out, "FN:{},{}", func.line_number, func.name)?;
+        }
+        for (name, hit) in &hits {
+            writeln!(out, "FNDA:{},{}", *hit as u8, name)?;
+        }
+        writeln!(out, "FNF:{}", hits.len())?;
+        writeln!(out, "FNH:{}", hits.iter().filter(|(_, hit)| *hit).count())?;
+        for (number, hit) in &lines {
+            writeln!(out, "DA:{},{}", number, *hit as u8)?;
+        }
+        let found = lines.len();
+        let hit = lines.values().filter(|hit| **hit).count();
+        writeln!(out, "LF:{}", found)?;
+        writeln!(out, "LH:{}", hit)?;
+        writeln!(out, "end_of_record")?;
+        eprintln!("{:<40} {:>8} {:>8} {:>7.1}%", file.name, found, hit, percent(hit, found));
+        total_found += found;
+        total_hit += hit;
+    }
+    out.flush()?;
+    eprintln!(
+        "{:<40} {:>8} {:>8} {:>7.1}%",
+        "Total",
+        total_found,
+        total_hit,
+        percent(total_hit, total_found)
+    );
+    eprintln!("Wrote {}", output_path);
+    Ok(())
+}
+
+fn percent(part: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / total as f64
+    }
+}
@@ -0,0 +1,385 @@
+//! Crash reports for programs stopped by a fault signal. The siginfo the kernel queued with the
+//! signal (PTRACE_GETSIGINFO) gives the kind of fault (si_code) and, for memory faults, the
+//! address that was accessed, which is classified using the memory mappings of the process:
+//! NULL pointer dereferences, stack overflows, unmapped addresses and permission faults. The
+//! variables of the innermost function with debugging information are searched for one whose
+//! memory was accessed, or a pointer to it.
+
+use serde_json::json;
+use std::convert::TryInto;
+use std::fs;
+
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+
+use crate::dwarf_data::{DwarfData, Line};
+use crate::expr::{CType, Evaluator};
+use crate::inferior::Inferior;
+use crate::output;
+use crate::target::{Frame, Target};
+
+/// si_code values that don't depend on the signal
+const SI_USER: i32 = 0;
+const SI_KERNEL: i32 = 0x80;
+const SI_QUEUE: i32 = -1;
+const SI_TKILL: i32 = -6;
+
+/// Addresses below this are taken as an offset from a NULL pointer
+const NULL_PAGE: u64 = 4096;
+
+/// How far below the stack pointer an access counts as overflowing the stack
+const STACK_SLACK: u64 = 65536;
+
+/// How much of the stack to search for a return address into the program
+const STACK_SCAN: u64 = 16384;
+
+/// What the program was doing when it crashed.
+pub struct Crash {
+    /// Name and meaning of si_code, e.g. ("SEGV_MAPERR", "address not mapped to object")
+    code: Option<(&'static str, &'static str)>,
+    /// Classification of the fault, e.g. "null-pointer"
+    kind: &'static str,
+    /// The classification in words
+    summary: String,
+    /// Memory address accessed, for memory faults
+    fault_addr: Option<u64>,
+    /// Process that sent the signal, if it was sent rather than caused by a fault
+    sender: Option<i32>,
+    /// Address of the faulting instruction
+    rip: u64,
+    /// Innermost function with debugging information, and the offset reached in it
+    function: Option<(String, usize)>,
+    /// Whether that function is a caller of the one that crashed, such as a library function
+    in_caller: bool,
+    line: Option<Line>,
+    /// Text of the source line
+    source: Option<String>,
+    /// How variables relate to the fault address
+    variables: Vec<String>,
+}
+
+/// A mapping from /proc/<pid>/maps.
+struct Mapping {
+    start: u64,
+    end: u64,
+    perms: String,
+    name: String,
+}
+
+impl Mapping {
+    fn describe(&self) -> String {
+        match self.name.as_str() {
+            "" => "anonymous memory".to_string(),
+            name => name.to_string(),
+        }
+    }
+}
+
+fn read_mappings(pid: Pid) -> Vec<Mapping> {
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid)).unwrap_or_default();
+    let mut mappings = Vec::new();
+    for line in maps.lines() {
+        // e.g. "00400000-00401000 r--p 00000000 08:01 1234   /path/to/binary"
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let bounds: Vec<Option<u64>> = fields
+            .first()
+            .map_or(Vec::new(), |range| {
+                range.split('-').map(|n| u64::from_str_radix(n, 16).ok()).collect()
+            });
+        if let (Some(Some(start)), Some(Some(end)), Some(perms)) =
+            (bounds.get(0), bounds.get(1), fields.get(1))
+        {
+            let name = fields.get(5).unwrap_or(&"").to_string();
+            mappings.push(Mapping { start: *start, end: *end, perms: perms.to_string(), name });
+        }
+    }
+    mappings
+}
+
+/// Returns the name and meaning of a signal specific si_code.
+fn code_name(signal: Signal, code: i32) -> Option<(&'static str, &'static str)> {
+    let name = match (signal, code) {
+        (_, SI_USER) => ("SI_USER", "sent by kill"),
+        (_, SI_KERNEL) => ("SI_KERNEL", "sent by the kernel"),
+        (_, SI_QUEUE) => ("SI_QUEUE", "sent by sigqueue"),
+        (_, SI_TKILL) => ("SI_TKILL", "sent by tkill or raise"),
+        (Signal::SIGSEGV, 1) => ("SEGV_MAPERR", "address not mapped to object"),
+        (Signal::SIGSEGV, 2) => ("SEGV_ACCERR", "invalid permissions for mapped object"),
+        (Signal::SIGSEGV, 3) => ("SEGV_BNDERR", "failed address bound checks"),
+        (Signal::SIGSEGV, 4) => ("SEGV_PKUERR", "access denied by memory protection keys"),
+        (Signal::SIGBUS, 1) => ("BUS_ADRALN", "invalid address alignment"),
+        (Signal::SIGBUS, 2) => ("BUS_ADRERR", "nonexistent physical address"),
+        (Signal::SIGBUS, 3) => ("BUS_OBJERR", "object-specific hardware error"),
+        (Signal::SIGFPE, 1) => ("FPE_INTDIV", "integer divide by zero"),
+        (Signal::SIGFPE, 2) => ("FPE_INTOVF", "integer overflow"),
+        (Signal::SIGFPE, 3) => ("FPE_FLTDIV", "floating-point divide by zero"),
+        (Signal::SIGFPE, 4) => ("FPE_FLTOVF", "floating-point overflow"),
+        (Signal::SIGFPE, 5) => ("FPE_FLTUND", "floating-point underflow"),
+        (Signal::SIGFPE, 6) => ("FPE_FLTRES", "floating-point inexact result"),
+        (Signal::SIGFPE, 7) => ("FPE_FLTINV", "floating-point invalid operation"),
+        (Signal::SIGFPE, 8) => ("FPE_FLTSUB", "subscript out of range"),
+        (Signal::SIGILL, 1) => ("ILL_ILLOPC", "illegal opcode"),
+        (Signal::SIGILL, 2) => ("ILL_ILLOPN", "illegal operand"),
+        (Signal::SIGILL, 3) => ("ILL_ILLADR", "illegal addressing mode"),
+        (Signal::SIGILL, 4) => ("ILL_ILLTRP", "illegal trap"),
+        (Signal::SIGILL, 5) => ("ILL_PRVOPC", "privileged opcode"),
+        (Signal::SIGILL, 6) => ("ILL_PRVREG", "privileged register"),
+        (Signal::SIGILL, 7) => ("ILL_COPROC", "coprocessor error"),
+        (Signal::SIGILL, 8) => ("ILL_BADSTK", "internal stack error"),
+        _ => return None,
+    };
+    Some(name)
+}
+
+impl Crash {
+    /// Works out why `inferior` stopped with `signal`. Returns None if the inferior isn't
+    /// stopped by that signal, e.g. when it is a replayed copy of a program that was.
+    pub fn analyze(inferior: &Inferior, debug_data: &DwarfData, signal: Signal) -> Option<Crash> {
+        let info = inferior.siginfo().ok()?;
+        if info.si_signo != signal as i32 {
+            return None;
+        }
+        let code = info.si_code;
+        // On x86_64 the union of siginfo_t starts at byte 16: si_addr for faults, si_pid first
+        // for signals sent by a process
+        let bytes: [u8; 128] = unsafe { std::mem::transmute(info) };
+        let word = u64::from_le_bytes(bytes[16..24].try_into().unwrap());
+        let regs = inferior.regs().ok()?;
+        // SI_USER, SI_QUEUE and SI_TKILL are all sent by a process
+        let sent = code <= 0;
+        let fault_addr = match signal {
+            Signal::SIGSEGV | Signal::SIGBUS if !sent && code != SI_KERNEL => Some(word),
+            _ => None,
+        };
+        let sender = if sent { Some(word as u32 as i32) } else { None };
+        let (kind, summary) = classify(inferior, signal, code, fault_addr, sender, regs.rsp);
+        let mut crash = Crash {
+            code: code_name(signal, code),
+            kind,
+            summary,
+            fault_addr,
+            sender,
+            rip: regs.rip,
+            function: None,
+            in_caller: false,
+            line: None,
+            source: None,
+            variables: Vec::new(),
+        };
+        // The crash may be in a library function, called with bad arguments from the program
+        let mut frames = inferior.backtrace(debug_data).unwrap_or_default();
+        if frames.iter().all(|frame| frame.function.is_none()) {
+            if let Some(caller) = scan_for_caller(inferior, debug_data, regs.rsp, regs.rbp) {
+                frames.push(caller);
+            }
+        }
+        if let Some((index, frame)) =
+            frames.iter().enumerate().find(|(_, frame)| frame.function.is_some())
+        {
+            // Callers are at the call instruction, just before their return address
+            let addr = if index == 0 { frame.rip } else { frame.rip - 1 };
+            let function = debug_data.get_function_at(addr);
+            crash.function = function.map(|func| (func.name.clone(), addr - func.address));
+            crash.in_caller = index > 0;
+            crash.line = debug_data.get_line_from_addr(addr);
+            crash.source = crash.line.as_ref().and_then(|line| {
+                let text = fs::read_to_string(&line.file).ok()?;
+                text.lines().nth(line.number.checked_sub(1)?).map(|text| text.trim().to_string())
+            });
+            if let (Some(function), Some(fault_addr)) = (function, fault_addr) {
+                let evaluator = Evaluator::new(inferior, debug_data, addr, frame.rbp);
+                // Locals of blocks further down the function aren't in scope yet
+                let line_number = crash.line.as_ref().map_or(usize::MAX, |line| line.number);
+                let locals = function.variables.iter().filter(|var| var.line_number <= line_number);
+                for var in locals.chain(debug_data.get_global_variables()) {
+                    let value = match evaluator.variable(var) {
+                        Ok(value) => value,
+                        Err(_) => continue,
+                    };
+                    let size = value.ctype.size(debug_data) as u64;
+                    let address = value.address.filter(|addr| in_range(fault_addr, *addr, size));
+                    if let Some(addr) = address {
+                        crash.variables.push(format!(
+                            "{} bytes into the memory of `{}` at {:#x}",
+                            fault_addr - addr,
+                            var.name,
+                            addr
+                        ));
+                    }
+                    if let CType::Pointer(target) = &value.ctype {
+                        let pointer = value.bits();
+                        if in_range(fault_addr, pointer, target.size(debug_data).max(1) as u64) {
+                            let offset = match fault_addr - pointer {
+                                0 => String::new(),
+                                offset => format!(", {} bytes into *{}", offset, var.name),
+                            };
+                            crash.variables.push(format!(
+                                "`{}` = {:#x} points to it{}",
+                                var.name, pointer, offset
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        Some(crash)
+    }
+
+    /// Prints the report, before the usual stop message.
+    pub fn print(&self) {
+        match self.code {
+            Some((name, meaning)) => println!("Crash: {} ({}, {})", self.summary, name, meaning),
+            None => println!("Crash: {}", self.summary),
+        }
+        if let Some(fault_addr) = self.fault_addr {
+            println!("Fault address: {:#x}", fault_addr);
+        }
+        if let Some(sender) = self.sender {
+            println!("Sent by process {}", sender);
+        }
+        let location = match (&self.function, &self.line) {
+            (Some((name, offset)), Some(line)) => {
+                let place = if self.in_caller { "called from" } else { "in" };
+                format!(" {} {}+{} ({})", place, name, offset, line)
+            }
+            _ => String::new(),
+        };
+        println!("Faulting instruction: {:#x}{}", self.rip, location);
+        if let (Some(line), Some(source)) = (&self.line, &self.source) {
+            println!("{:>8}  {}", line.number, source);
+        }
+        for variable in &self.variables {
+            println!("Involved: {}", variable);
+        }
+    }
+
+    /// Returns the report for the stopped record of the JSON interface.
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "kind": self.kind,
+            "summary": self.summary,
+            "code": self.code.map(|(name, _)| name),
+            "code-meaning": self.code.map(|(_, meaning)| meaning),
+            "fault-address": self.fault_addr,
+            "sender": self.sender,
+            "rip": self.rip,
+            "function": self.function.as_ref().map(|(name, _)| name),
+            "in-caller": self.in_caller,
+            "line": self.line.as_ref().map(output::line),
+            "source": self.source,
+            "variables": self.variables,
+        })
+    }
+}
+
+/// Finds the return address into code with debugging information nearest the top of the stack,
+/// for crashes in library code that walking the frame pointers can't get out of. rbp is only
+/// kept if it can still be the frame pointer of that caller.
+fn scan_for_caller(
+    inferior: &Inferior,
+    debug_data: &DwarfData,
+    rsp: u64,
+    rbp: u64,
+) -> Option<Frame> {
+    for slot in (rsp..rsp + STACK_SCAN).step_by(8) {
+        let word = inferior.read_word(slot).ok()? as usize;
+        if word < 5 || debug_data.get_function_at(word - 1).is_none() {
+            continue;
+        }
+        // Return addresses follow a call instruction, usually a direct call (e8 rel32)
+        if inferior.read_memory(word as u64 - 5, 1).ok()? != [0xe8] {
+            continue;
+        }
+        return Some(Frame {
+            rip: word,
+            rbp: if rbp > slot { rbp as usize } else { 0 },
+            function: debug_data.get_function_from_addr(word - 1),
+            line: debug_data.get_line_from_addr(word - 1),
+            is_return_addr: true,
+        });
+    }
+    None
+}
+
+fn in_range(addr: u64, start: u64, size: u64) -> bool {
+    start <= addr && addr - start < size.max(1)
+}
+
+/// Classifies a crash, returning a short name for tools and a description for people.
+fn classify(
+    inferior: &Inferior,
+    signal: Signal,
+    code: i32,
+    fault_addr: Option<u64>,
+    sender: Option<i32>,
+    rsp: u64,
+) -> (&'static str, String) {
+    if let Some(sender) = sender {
+        return match signal {
+            Signal::SIGABRT if sender == inferior.pid().as_raw() => {
+                ("abort", "the program aborted, e.g. from abort() or a failed assert".to_string())
+            }
+            _ if sender == inferior.pid().as_raw() => {
+                ("signal", format!("the program sent itself {}", signal))
+            }
+            _ => ("signal", format!("{} was sent by process {}", signal, sender)),
+        };
+    }
+    let fault_addr = match fault_addr {
+        Some(fault_addr) => fault_addr,
+        None => {
+            return match (signal, code) {
+                (Signal::SIGSEGV, SI_KERNEL) => (
+                    "general-protection",
+                    "general protection fault, e.g. from a non-canonical pointer (the kernel \
+                     doesn't report the address)"
+                        .to_string(),
+                ),
+                (Signal::SIGFPE, 1) => ("divide-by-zero", "integer division by zero".to_string()),
+                (Signal::SIGFPE, 2) => ("overflow", "integer overflow".to_string()),
+                (Signal::SIGFPE, _) => ("arithmetic", "floating-point exception".to_string()),
+                (Signal::SIGILL, _) => ("illegal-instruction", "illegal instruction".to_string()),
+                _ => ("other", format!("stopped by {}", signal)),
+            };
+        }
+    };
+    if signal == Signal::SIGBUS {
+        return match code {
+            1 => ("misaligned", "misaligned memory access".to_string()),
+            _ => (
+                "bus-error",
+                "access to memory with nothing behind it, e.g. past the end of a mapped file"
+                    .to_string(),
+            ),
+        };
+    }
+    if fault_addr < NULL_PAGE {
+        return ("null-pointer", "NULL pointer dereference".to_string());
+    }
+    let mappings = read_mappings(inferior.pid());
+    let mapping =
+        mappings.iter().find(|mapping| mapping.start <= fault_addr && fault_addr < mapping.end);
+    match mapping {
+        Some(mapping) => {
+            let place = mapping.describe();
+            if !mapping.perms.starts_with('r') {
+                ("permission", format!("access to protected memory in {}", place))
+            } else if mapping.perms.as_bytes().get(1) == Some(&b'w') {
+                ("permission", format!("access denied to {}", place))
+            } else if fault_addr == inferior.regs().map_or(0, |regs| regs.rip) {
+                ("permission", format!("execution of non-executable memory in {}", place))
+            } else {
+                ("permission", format!("write to read-only memory in {}", place))
+            }
+        }
+        None => {
+            let stack = mappings.iter().find(|mapping| mapping.name == "[stack]");
+            let below_stack = stack.map_or(false, |stack| fault_addr < stack.start);
+            if below_stack && fault_addr + STACK_SLACK >= rsp {
+                ("stack-overflow", "stack overflow, e.g. from infinite recursion".to_string())
+            } else {
+                ("unmapped", "access to unmapped memory".to_string())
+            }
+        }
+    }
+}
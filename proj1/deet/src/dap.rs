@@ -0,0 +1,528 @@
+//! A Debug Adapter Protocol frontend (`deet --dap`), so that editors such as VS Code and Neovim
+//! can drive deet directly. Requests and responses are JSON bodies framed by a Content-Length
+//! header on stdin and stdout. Since stdout carries the protocol, the inferior's output is
+//! captured and forwarded as `output` events.
+
+use nix::sys::signal::Signal;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Read, Write};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::dwarf_data::DwarfData;
+use crate::inferior::{Inferior, Status};
+use crate::expr::Evaluator;
+use crate::target::{Frame, Target};
+
+/// variablesReference of the "Globals" scope. Locals of frame N use N + 1.
+const GLOBALS_REFERENCE: usize = 1_000_000;
+
+/// Writes framed messages to stdout. Shared with the threads forwarding the inferior's output.
+#[derive(Clone)]
+struct Sender {
+    out: Arc<Mutex<io::Stdout>>,
+    seq: Arc<AtomicI64>,
+}
+
+impl Sender {
+    fn send(&self, mut message: Value) {
+        message["seq"] = json!(self.seq.fetch_add(1, Ordering::SeqCst));
+        let body = message.to_string();
+        let mut out = self.out.lock().unwrap();
+        let _ = write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+        let _ = out.flush();
+    }
+
+    fn respond(&self, request: &Value, success: bool, body: Value) {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": success,
+            "body": body,
+        });
+        if !success {
+            response["message"] = body["error"].clone();
+        }
+        self.send(response);
+    }
+
+    fn event(&self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+}
+
+/// Reads Content-Length framed messages from stdin on a separate thread, so that the main loop
+/// can keep polling a running inferior while waiting for requests.
+fn spawn_reader() -> Receiver<Value> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        let mut input = stdin.lock();
+        loop {
+            let mut content_length = None;
+            loop {
+                let mut header = String::new();
+                if input.read_line(&mut header).unwrap_or(0) == 0 {
+                    return;
+                }
+                let header = header.trim();
+                if header.is_empty() {
+                    break;
+                }
+                if header.starts_with("Content-Length:") {
+                    content_length = header["Content-Length:".len()..].trim().parse::<usize>().ok();
+                }
+            }
+            let mut body = vec![0u8; content_length.unwrap_or(0)];
+            if input.read_exact(&mut body).is_err() {
+                return;
+            }
+            if let Ok(message) = serde_json::from_slice(&body) {
+                if tx.send(message).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    rx
+}
+
+/// Forwards everything read from `pipe` as `output` events.
+fn forward_output(mut pipe: impl Read + Send + 'static, category: &'static str, sender: Sender) {
+    thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        while let Ok(n) = pipe.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            let output = String::from_utf8_lossy(&buf[..n]).to_string();
+            sender.event("output", json!({ "category": category, "output": output }));
+        }
+    });
+}
+
+struct DapServer {
+    sender: Sender,
+    target: Option<String>,
+    debug_data: Option<DwarfData>,
+    inferior: Option<Inferior>,
+    /// Breakpoint addresses set through setBreakpoints, per source path
+    breakpoints: HashMap<String, Vec<u64>>,
+    stop_on_entry: bool,
+    launched: bool,
+    configured: bool,
+    /// Frames reported by the last stackTrace, looked up again by scopes and variables
+    frames: Vec<Frame>,
+}
+
+/// Runs the adapter until the client disconnects. `target` is the program to debug, unless the
+/// launch request names one.
+pub fn serve(target: Option<&str>) {
+    let mut server = DapServer {
+        sender: Sender {
+            out: Arc::new(Mutex::new(io::stdout())),
+            seq: Arc::new(AtomicI64::new(1)),
+        },
+        target: target.map(|t| t.to_string()),
+        debug_data: None,
+        inferior: None,
+        breakpoints: HashMap::new(),
+        stop_on_entry: false,
+        launched: false,
+        configured: false,
+        frames: Vec::new(),
+    };
+    let requests = spawn_reader();
+    loop {
+        let running = server.inferior.as_ref().map_or(false, |inferior| inferior.is_running());
+        let request = if running {
+            match requests.recv_timeout(Duration::from_millis(20)) {
+                Ok(request) => request,
+                Err(RecvTimeoutError::Timeout) => {
+                    server.poll_inferior();
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        } else {
+            match requests.recv() {
+                Ok(request) => request,
+                Err(_) => break,
+            }
+        };
+        if !server.handle(&request) {
+            break;
+        }
+    }
+    if let Some(inferior) = &mut server.inferior {
+        let _ = inferior.kill();
+    }
+}
+
+impl DapServer {
+    /// Handles one request. Returns false once the client has disconnected.
+    fn handle(&mut self, request: &Value) -> bool {
+        let command = request["command"].as_str().unwrap_or("");
+        let args = &request["arguments"];
+        let sender = self.sender.clone();
+        let fail = |message: &str| sender.respond(request, false, json!({ "error": message }));
+        match command {
+            "initialize" => self.sender.respond(
+                request,
+                true,
+                json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsEvaluateForHovers": true,
+                }),
+            ),
+            "launch" => match self.launch(args) {
+                Ok(()) => {
+                    self.sender.respond(request, true, json!({}));
+                    self.sender.event("initialized", json!({}));
+                }
+                Err(message) => fail(&message),
+            },
+            "setBreakpoints" => {
+                let body = self.set_breakpoints(args);
+                self.sender.respond(request, true, body);
+            }
+            "configurationDone" => {
+                self.sender.respond(request, true, json!({}));
+                self.configured = true;
+                self.start();
+            }
+            "threads" => {
+                let threads = match &self.inferior {
+                    Some(inferior) => vec![json!({
+                        "id": inferior.pid().as_raw(),
+                        "name": self.target.clone().unwrap_or_default(),
+                    })],
+                    None => Vec::new(),
+                };
+                self.sender.respond(request, true, json!({ "threads": threads }));
+            }
+            "stackTrace" => match self.stack_trace() {
+                Some(body) => self.sender.respond(request, true, body),
+                None => fail("process is not stopped"),
+            },
+            "scopes" => {
+                let frame_id = args["frameId"].as_u64().unwrap_or(0) as usize;
+                let scopes = json!({ "scopes": [
+                    { "name": "Locals", "variablesReference": frame_id + 1 },
+                    { "name": "Globals", "variablesReference": GLOBALS_REFERENCE },
+                ]});
+                self.sender.respond(request, true, scopes);
+            }
+            "variables" => {
+                let reference = args["variablesReference"].as_u64().unwrap_or(0) as usize;
+                match self.variables(reference) {
+                    Ok(variables) => {
+                        self.sender.respond(request, true, json!({ "variables": variables }))
+                    }
+                    Err(message) => fail(&message),
+                }
+            }
+            "evaluate" => {
+                let frame_id = args["frameId"].as_u64().unwrap_or(0) as usize;
+                match self.evaluate(args["expression"].as_str().unwrap_or(""), frame_id) {
+                    Some(result) => self.sender.respond(
+                        request,
+                        true,
+                        json!({ "result": result, "variablesReference": 0 }),
+                    ),
+                    None => fail("cannot evaluate expression"),
+                }
+            }
+            "continue" | "next" | "stepIn" | "stepOut" => {
+                let stopped = self.inferior.as_ref().map_or(false, |i| !i.is_running());
+                if !stopped {
+                    fail("process is not stopped");
+                    return true;
+                }
+                self.sender.respond(request, true, json!({ "allThreadsContinued": true }));
+                self.execute(command);
+            }
+            "pause" => match &mut self.inferior {
+                Some(inferior) if inferior.is_running() => {
+                    self.sender.respond(request, true, json!({}));
+                    let status = inferior.interrupt();
+                    self.report(status, "pause");
+                }
+                _ => fail("process is not running"),
+            },
+            "disconnect" | "terminate" => {
+                if let Some(inferior) = &mut self.inferior {
+                    let _ = inferior.kill();
+                }
+                self.inferior = None;
+                self.sender.respond(request, true, json!({}));
+                if command == "terminate" {
+                    self.sender.event("terminated", json!({}));
+                }
+                return command != "disconnect";
+            }
+            _ => fail(&format!("unsupported request {}", command)),
+        }
+        true
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<(), String> {
+        if let Some(program) = args["program"].as_str() {
+            self.target = Some(program.to_string());
+        }
+        let target = self.target.clone().ok_or("no program to debug")?;
+        let debug_data = DwarfData::from_file(&target)
+            .map_err(|err| format!("could not load debugging symbols from {}: {:?}", target, err))?;
+        let program_args: Vec<String> = args["args"]
+            .as_array()
+            .map(|a| a.iter().filter_map(|arg| arg.as_str()).map(|arg| arg.to_string()).collect())
+            .unwrap_or_default();
+        let breaks: Vec<u64> = self.breakpoints.values().flatten().copied().collect();
+        let mut inferior = Inferior::new_with_captured_output(&target, &program_args, &breaks)
+            .ok_or(format!("error starting {}", target))?;
+        let (stdout, stderr) = inferior.take_output();
+        if let Some(stdout) = stdout {
+            forward_output(stdout, "stdout", self.sender.clone());
+        }
+        if let Some(stderr) = stderr {
+            forward_output(stderr, "stderr", self.sender.clone());
+        }
+        self.sender.event(
+            "process",
+            json!({
+                "name": target,
+                "systemProcessId": inferior.pid().as_raw(),
+                "startMethod": "launch",
+            }),
+        );
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        self.debug_data = Some(debug_data);
+        self.inferior = Some(inferior);
+        self.launched = true;
+        Ok(())
+    }
+
+    /// Starts the program once it has been launched and the client is done configuring it.
+    fn start(&mut self) {
+        if !self.launched || !self.configured {
+            return;
+        }
+        if self.stop_on_entry {
+            let pid = self.inferior.as_ref().unwrap().pid().as_raw();
+            self.sender.event(
+                "stopped",
+                json!({ "reason": "entry", "threadId": pid, "allThreadsStopped": true }),
+            );
+        } else {
+            self.execute("continue");
+        }
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Value {
+        let path = args["source"]["path"].as_str().unwrap_or("").to_string();
+        let lines: Vec<usize> = args["breakpoints"]
+            .as_array()
+            .map(|bps| bps.iter().filter_map(|bp| bp["line"].as_u64()).collect::<Vec<_>>())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|line| line as usize)
+            .collect();
+
+        // Replace whatever was set in this file before
+        let old = self.breakpoints.remove(&path).unwrap_or_default();
+        if let Some(inferior) = &mut self.inferior {
+            let rip = if inferior.is_running() {
+                None
+            } else {
+                inferior.regs().ok().map(|regs| regs.rip)
+            };
+            for addr in old {
+                if let Some(orig_byte) = inferior.bp_map.remove(&addr) {
+                    let _ = inferior.write_byte(addr, orig_byte);
+                    // Stopped just past it: go back to run the original instruction
+                    if rip == Some(addr + 1) {
+                        let _ = inferior.set_pc(addr);
+                    }
+                }
+            }
+        }
+
+        let mut addrs = Vec::new();
+        let mut results = Vec::new();
+        for (id, line) in lines.into_iter().enumerate() {
+            let addr =
+                self.debug_data.as_ref().and_then(|d| d.get_addr_for_line(Some(&path), line));
+            match addr {
+                Some(addr) => {
+                    let addr = addr as u64;
+                    let actual_line = self
+                        .debug_data
+                        .as_ref()
+                        .and_then(|d| d.get_line_from_addr(addr as usize))
+                        .map_or(line, |l| l.number);
+                    if let Some(inferior) = &mut self.inferior {
+                        if !inferior.bp_map.contains_key(&addr) {
+                            if let Ok(orig_byte) = inferior.write_byte(addr, 0xcc) {
+                                inferior.bp_map.insert(addr, orig_byte);
+                            }
+                        }
+                    }
+                    addrs.push(addr);
+                    results.push(json!({ "id": id, "verified": true, "line": actual_line }));
+                }
+                None => results.push(json!({
+                    "id": id,
+                    "verified": false,
+                    "line": line,
+                    "message": "no code at this line",
+                })),
+            }
+        }
+        self.breakpoints.insert(path, addrs);
+        json!({ "breakpoints": results })
+    }
+
+    fn stack_trace(&mut self) -> Option<Value> {
+        let inferior = self.inferior.as_ref().filter(|inferior| !inferior.is_running())?;
+        self.frames = inferior.backtrace(self.debug_data.as_ref()?).ok()?;
+        let frames: Vec<Value> = self
+            .frames
+            .iter()
+            .enumerate()
+            .map(|(id, frame)| {
+                let name = frame.function.clone().unwrap_or_else(|| format!("{:#x}", frame.rip));
+                let mut json_frame =
+                    json!({ "id": id, "name": name, "line": 0, "column": 0 });
+                if let Some(line) = &frame.line {
+                    json_frame["line"] = json!(line.number);
+                    json_frame["column"] = json!(1);
+                    let file_name = line.file.rsplit('/').next().unwrap_or(&line.file);
+                    json_frame["source"] = json!({ "name": file_name, "path": line.file });
+                }
+                json_frame
+            })
+            .collect();
+        Some(json!({ "stackFrames": frames, "totalFrames": frames.len() }))
+    }
+
+    fn variables(&self, reference: usize) -> Result<Vec<Value>, String> {
+        let (inferior, debug_data) = match (&self.inferior, &self.debug_data) {
+            (Some(inferior), Some(debug_data)) if !inferior.is_running() => (inferior, debug_data),
+            _ => return Err("process is not stopped".to_string()),
+        };
+        let (vars, rbp) = if reference == GLOBALS_REFERENCE {
+            (debug_data.get_global_variables(), 0)
+        } else {
+            let frame = reference
+                .checked_sub(1)
+                .and_then(|id| self.frames.get(id))
+                .ok_or(format!("invalid variablesReference {}", reference))?;
+            match debug_data.get_function_at(frame.lookup_addr()) {
+                Some(func) => (func.variables.iter().collect(), frame.rbp),
+                None => return Ok(Vec::new()),
+            }
+        };
+        Ok(vars
+            .into_iter()
+            .map(|var| {
+                let value = inferior
+                    .read_variable(debug_data, var, rbp)
+                    .unwrap_or_else(|_| "<unavailable>".to_string());
+                json!({
+                    "name": var.name,
+                    "value": value,
+                    "type": var.entity_type.name,
+                    "variablesReference": 0,
+                })
+            })
+            .collect())
+    }
+
+    /// Evaluates a C expression in the context of the given frame.
+    fn evaluate(&self, expression: &str, frame_id: usize) -> Option<String> {
+        let inferior = self.inferior.as_ref().filter(|inferior| !inferior.is_running())?;
+        let frame = self.frames.get(frame_id)?;
+        let debug_data = self.debug_data.as_ref()?;
+        let evaluator = Evaluator::new(inferior, debug_data, frame.lookup_addr(), frame.rbp);
+        let value = evaluator.evaluate(expression).ok()?;
+        Some(evaluator.format(&value))
+    }
+
+    fn execute(&mut self, command: &str) {
+        let debug_data = self.debug_data.as_ref().unwrap();
+        let inferior = self.inferior.as_mut().unwrap();
+        let (status, reason) = match command {
+            "next" => (inferior.step_line(debug_data, true), "step"),
+            "stepIn" => (inferior.step_line(debug_data, false), "step"),
+            "stepOut" => (inferior.finish(), "step"),
+            _ => match inferior.resume(None) {
+                // The stop is reported once poll_inferior sees it
+                Ok(None) => return,
+                Ok(Some(status)) => (Ok(status), "breakpoint"),
+                Err(err) => (Err(err), "breakpoint"),
+            },
+        };
+        self.report(status, reason);
+    }
+
+    fn poll_inferior(&mut self) {
+        let status = match &mut self.inferior {
+            Some(inferior) => inferior.try_wait(),
+            None => return,
+        };
+        match status {
+            Ok(None) => {}
+            Ok(Some(status)) => self.report(Ok(status), "breakpoint"),
+            Err(err) => self.report(Err(err), "breakpoint"),
+        }
+    }
+
+    /// Sends the events for a change of the inferior's state. `reason` is used for SIGTRAP stops
+    /// that weren't caused by one of the breakpoints.
+    fn report(&mut self, status: Result<Status, nix::Error>, reason: &str) {
+        self.frames.clear();
+        let pid = self.inferior.as_ref().map_or(0, |inferior| inferior.pid().as_raw());
+        match status {
+            Ok(Status::Stopped(signal, rip)) => {
+                let trap_addr = (rip as u64).wrapping_sub(1);
+                let at_breakpoint = self
+                    .inferior
+                    .as_ref()
+                    .map_or(false, |inferior| inferior.bp_map.contains_key(&trap_addr));
+                let mut body = json!({ "threadId": pid, "allThreadsStopped": true });
+                if signal == Signal::SIGTRAP {
+                    body["reason"] = json!(if at_breakpoint { "breakpoint" } else { reason });
+                } else if signal == Signal::SIGINT && reason == "pause" {
+                    body["reason"] = json!("pause");
+                } else {
+                    body["reason"] = json!("exception");
+                    body["description"] = json!(format!("Process stopped with signal {}", signal));
+                    body["text"] = json!(signal.as_ref());
+                }
+                self.sender.event("stopped", body);
+            }
+            Ok(Status::Exited(exit_code)) => {
+                self.inferior = None;
+                self.sender.event("exited", json!({ "exitCode": exit_code }));
+                self.sender.event("terminated", json!({}));
+            }
+            Ok(Status::Signaled(signal)) => {
+                self.inferior = None;
+                let output = format!("Process exited by signal {}\n", signal);
+                self.sender.event("output", json!({ "category": "console", "output": output }));
+                self.sender.event("terminated", json!({}));
+            }
+            Err(err) => {
+                self.sender.event(
+                    "output",
+                    json!({ "category": "console", "output": format!("Error: {}\n", err) }),
+                );
+            }
+        }
+    }
+}
@@ -1,8 +1,27 @@
-use crate::debugger_command::DebuggerCommand;
+use crate::completion::DeetHelper;
+use crate::core_file::{self, CoreFile, Error as CoreError};
+use crate::crash::Crash;
+use crate::debugger_command::{self, DebuggerCommand, COMMANDS};
 use crate::dwarf_data::{DwarfData, Error as DwarfError};
-use crate::inferior::{Inferior, Status};
+use crate::expr::{CType, Evaluator, Session};
+use crate::inferior::{Inferior, Status, SyscallStop};
+use crate::output;
+use crate::record::{self, Recording};
+use crate::syscall;
+use crate::target::{self, NoProcess, Target};
+use nix::sys::signal::Signal;
+use std::convert::TryFrom;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
+use serde_json::json;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// How deeply user macros may call each other, like gdb's max-user-call-depth. Stops a macro
+/// that calls itself from running forever.
+const MAX_USER_CALL_DEPTH: usize = 1024;
 
 fn parse_address(addr: &str) -> Option<u64> {
     let addr_without_0x = if addr.to_lowercase().starts_with("0x") {
@@ -13,34 +32,204 @@ fn parse_address(addr: &str) -> Option<u64> {
     u64::from_str_radix(addr_without_0x, 16).ok()
 }
 
+/// Whether a stop signal means the program crashed, as opposed to a breakpoint or interrupt.
+fn is_crash(signal: Signal) -> bool {
+    match signal {
+        Signal::SIGSEGV | Signal::SIGBUS | Signal::SIGFPE | Signal::SIGILL | Signal::SIGABRT
+        | Signal::SIGSYS => true,
+        _ => false,
+    }
+}
+
+/// A user breakpoint and the commands to run whenever it is hit.
+struct Breakpoint {
+    addr: u64,
+    commands: Vec<String>,
+}
+
+/// A command line waiting to run, from a script, a macro or a breakpoint's command list.
+struct PendingLine {
+    text: String,
+    /// Script files being sourced when the line was queued, outermost first
+    sources: Vec<String>,
+    /// Number of user macros being run when the line was queued
+    macro_depth: usize,
+}
+
+/// A paused copy of the program, made by `checkpoint`.
+struct Checkpoint {
+    number: usize,
+    inferior: Inferior,
+}
+
+/// An expression printed every time the program stops (`display/x ptr`).
+struct Display {
+    number: usize,
+    format: Option<char>,
+    expression: String,
+}
+
+impl Display {
+    /// Returns the expression as given to `display`, e.g. "/x ptr".
+    fn label(&self) -> String {
+        match self.format {
+            Some(format) => format!("/{} {}", format, self.expression),
+            None => self.expression.clone(),
+        }
+    }
+}
+
+/// Describes the system calls a catchpoint catches, gdb style: "syscall 'write' [1]".
+fn describe_catchpoint(numbers: &[u64]) -> String {
+    let calls: Vec<_> = numbers
+        .iter()
+        .map(|number| format!("'{}' [{}]", syscall::describe(*number), number))
+        .collect();
+    match calls.len() {
+        0 => "any syscall".to_string(),
+        1 => format!("syscall {}", calls[0]),
+        _ => format!("syscalls {}", calls.join(" ")),
+    }
+}
+
+/// Prints the values of displays, gdb style: "1: /x ptr = 0x4052a0".
+fn print_displays(values: &[(&Display, Result<String, String>)]) {
+    for (display, value) in values {
+        match value {
+            Ok(value) => println!("{}: {} = {}", display.number, display.label(), value),
+            Err(msg) => println!("{}: {} = <error: {}>", display.number, display.label(), msg),
+        }
+    }
+}
+
+fn display_json(display: &Display) -> serde_json::Value {
+    json!({
+        "number": display.number,
+        "expression": display.expression,
+        "format": display.format.map(|format| format.to_string()),
+    })
+}
+
+fn displays_json(values: &[(&Display, Result<String, String>)]) -> Vec<serde_json::Value> {
+    values
+        .iter()
+        .map(|(display, value)| {
+            let mut record = display_json(display);
+            match value {
+                Ok(value) => record["value"] = json!(value),
+                Err(msg) => record["error"] = json!(msg),
+            }
+            record
+        })
+        .collect()
+}
+
 pub struct Debugger {
     target: String,
     history_path: String,
-    readline: Editor<()>,
+    readline: Editor<DeetHelper>,
     inferior: Option<Inferior>,
+    /// Core file being debugged post-mortem; only inspected while no inferior is running
+    core: Option<CoreFile>,
     debug_data: DwarfData,
-    breaks: Vec<u64>,
+    breaks: Vec<Breakpoint>,
+    /// System call numbers caught by each catchpoint, where an empty list catches any call
+    catchpoints: Vec<Vec<u64>>,
+    displays: Vec<Display>,
+    /// Paused copies of the program that `restart` can go back to
+    checkpoints: Vec<Checkpoint>,
+    /// Number of the next checkpoint; like displays, numbers are not reused
+    next_checkpoint: usize,
+    /// Log of the program's execution while it is recorded, for the reverse commands
+    recording: Option<Recording>,
+    /// Most instructions a recording keeps, if limited
+    record_limit: Option<usize>,
+    /// Value history of `print` and convenience variables
+    session: RefCell<Session>,
+    /// Number of the next display; numbers of deleted displays are not reused
+    next_display: usize,
+    /// Bumped whenever the debugger handles an inferior state change itself, so that background
+    /// stop notifiers know their announcement is stale.
+    stop_epoch: Arc<AtomicUsize>,
+    /// Command lines from -x/-ex, `source` and ~/.deetinit, executed before prompting the user
+    pending: VecDeque<PendingLine>,
+    /// Script files being sourced by the line being executed, to catch a script sourcing itself
+    sources: Vec<String>,
+    /// Number of user macros the line being executed comes from
+    macro_depth: usize,
+    /// Quit once the pending commands run out instead of prompting
+    batch: bool,
+    /// Whether the line being executed was typed by the user rather than queued from a script
+    interactive: bool,
+    /// User aliases (`alias xs = x/16xb`), mapping a name to the text it stands for
+    aliases: HashMap<String, String>,
+    /// User macros (`define name ... end`), mapping a name to its command lines
+    macros: HashMap<String, Vec<String>>,
+    /// Exit status of deet itself: 128 + signal number if the last inferior crashed
+    exit_code: i32,
 }
 
 impl Debugger {
-    /// Initializes the debugger.
-    pub fn new(target: &str) -> Debugger {
+    /// Initializes the debugger, optionally loading a core file generated by the target. In batch
+    /// mode the debugger quits after the queued commands instead of prompting for more.
+    pub fn new(target: &str, core_path: Option<&str>, batch: bool) -> Debugger {
         let debug_data = match DwarfData::from_file(target) {
             Ok(val) => val,
             Err(DwarfError::ErrorOpeningFile) => {
-                println!("Could not open file {}", target);
+                output::console(&format!("Could not open file {}", target));
                 std::process::exit(1);
             }
+            // Still debuggable at the instruction level, with functions found by their symbols
             Err(DwarfError::DwarfFormatError(err)) => {
-                println!("Could not debugging symbols from {}: {:?}", target, err);
-                std::process::exit(1);
+                output::console(&format!(
+                    "Could not load debugging symbols from {}: {:?}",
+                    target, err
+                ));
+                match DwarfData::from_symbols(target) {
+                    Ok(val) => val,
+                    Err(err) => {
+                        output::console(&format!("Could not load {}: {:?}", target, err));
+                        std::process::exit(1);
+                    }
+                }
             }
         };
+        if debug_data.get_files().is_empty() {
+            output::console(&format!("No debugging symbols found in {}", target));
+        }
 
-        debug_data.print();
+        if !batch && !output::is_json() {
+            debug_data.print();
+        }
+
+        let core = core_path.map(|path| match CoreFile::open(path, target) {
+            Ok(core) => core,
+            Err(CoreError::ErrorOpeningFile) => {
+                output::console(&format!("Could not open core file {}", path));
+                std::process::exit(1);
+            }
+            Err(CoreError::FormatError(err)) => {
+                output::console(&format!("Could not load core file {}: {}", path, err));
+                std::process::exit(1);
+            }
+        });
+        if let Some(core) = &core {
+            match Signal::try_from(core.signal()) {
+                Ok(signal) => {
+                    output::console(&format!(
+                        "Core was generated by pid {}, signal {}",
+                        core.pid(),
+                        signal
+                    ))
+                }
+                Err(_) => output::console(&format!("Core was generated by pid {}", core.pid())),
+            }
+            core.print_stop(&debug_data).unwrap();
+        }
 
         let history_path = format!("{}/.deet_history", std::env::var("HOME").unwrap());
-        let mut readline = Editor::<()>::new();
+        let mut readline = Editor::<DeetHelper>::new();
+        readline.set_helper(Some(DeetHelper::new(debug_data.get_function_names())));
         // Attempt to load history from ~/.deet_history if it exists
         let _ = readline.load_history(&history_path);
 
@@ -49,160 +238,1181 @@ impl Debugger {
             history_path,
             readline,
             inferior: None,
+            core,
             debug_data,
-            breaks: Vec::<u64>::new(),
+            breaks: Vec::<Breakpoint>::new(),
+            catchpoints: Vec::new(),
+            displays: Vec::new(),
+            checkpoints: Vec::new(),
+            next_checkpoint: 1,
+            recording: None,
+            record_limit: Some(record::DEFAULT_MAX_INSTRUCTIONS),
+            session: RefCell::new(Session::default()),
+            next_display: 1,
+            stop_epoch: Arc::new(AtomicUsize::new(0)),
+            pending: VecDeque::new(),
+            sources: Vec::new(),
+            macro_depth: 0,
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            batch,
+            interactive: false,
+            exit_code: 0,
         }
     }
 
-    pub fn run(&mut self) {
+    /// Queues a command to run before the user is prompted, e.g. from `-ex`.
+    pub fn queue_command(&mut self, line: &str) {
+        self.pending.push_back(PendingLine {
+            text: line.to_string(),
+            sources: Vec::new(),
+            macro_depth: 0,
+        });
+    }
+
+    /// Queues the commands of a script file, e.g. from `-x`. Commands queued earlier run first.
+    pub fn queue_file(&mut self, path: &str) -> std::io::Result<()> {
+        let script = std::fs::read_to_string(path)?;
+        let sources = vec![std::fs::canonicalize(path)?.to_string_lossy().to_string()];
+        let lines = script.lines().map(|line| line.to_string());
+        self.pending.extend(lines.map(|text| PendingLine {
+            text,
+            sources: sources.clone(),
+            macro_depth: 0,
+        }));
+        Ok(())
+    }
+
+    /// Runs the commands of a script file before any other pending command, for `source`.
+    fn source(&mut self, path: &str) {
+        let file = match std::fs::canonicalize(path) {
+            Ok(file) => file.to_string_lossy().to_string(),
+            Err(err) => return output::error(&format!("{}: {}", path, err)),
+        };
+        if self.sources.contains(&file) {
+            return output::error(&format!("{}: the file is already being sourced", path));
+        }
+        match std::fs::read_to_string(path) {
+            Ok(script) => {
+                let mut sources = self.sources.clone();
+                sources.push(file);
+                let lines = script.lines().map(|line| line.to_string()).collect();
+                self.queue_lines(lines, sources, self.macro_depth);
+            }
+            Err(err) => output::error(&format!("{}: {}", path, err)),
+        }
+    }
+
+    /// Queues `lines` to run before any other pending command.
+    fn queue_lines(&mut self, lines: Vec<String>, sources: Vec<String>, macro_depth: usize) {
+        for text in lines.into_iter().rev() {
+            self.pending.push_front(PendingLine { text, sources: sources.clone(), macro_depth });
+        }
+    }
+
+    /// Runs commands until the user quits (or, in batch mode, until the queued commands run out),
+    /// returning the exit status for deet: 128 + the signal number if the last inferior crashed,
+    /// 0 otherwise.
+    pub fn run(&mut self) -> i32 {
         loop {
-            match self.get_next_command() {
-                DebuggerCommand::Run(args) => {
+            let command = self.get_next_command();
+            match command {
+                DebuggerCommand::Run(args, background) => {
+                    if let Some(recording) = self.recording.take() {
+                        recording.discard();
+                    }
                     // If inferior is not None, can only be stopped, kill it
                     // Because normally exited process has been set to None
                     if let Some(inferior) = &mut self.inferior {
-                        println!("Killing running process (pid={})", inferior.pid());
+                        output::console(&format!(
+                            "Killing running process (pid={})",
+                            inferior.pid()
+                        ));
+                        self.stop_epoch.fetch_add(1, Ordering::SeqCst);
                         inferior.kill().unwrap();
                     }
-                    if let Some(inferior) = Inferior::new(&self.target, &args, &self.breaks) {
+                    let breaks = self.breaks.iter().map(|bp| bp.addr).collect();
+                    if let Some(inferior) = Inferior::new(&self.target, &args, &breaks) {
                         // Create the inferior
                         self.inferior = Some(inferior);
-                        // You may use self.inferior.as_mut().unwrap() to get a mutable reference
-                        // to the Inferior object
-                        let status = self.inferior.as_mut().unwrap().cont().unwrap();
-                        match status {
-                            Status::Exited(exit_code) => {
-                                println!("Process exited with code {}", exit_code);
-                                self.inferior = None
-                            }
-                            Status::Signaled(signal) => {
-                                println!("Process exited by signal {}", signal);
-                                self.inferior = None
-                            }
-                            Status::Stopped(signal, rip) => {
-                                println!("Process stopped with signal {} at address 0x{:x}", signal, rip);
-                                self.inferior.as_ref().unwrap().print_stop(&self.debug_data).unwrap();
-                            }
+                        self.exit_code = 0;
+                        self.resume_inferior(background);
+                    } else {
+                        output::error("Error starting subprocess");
+                    }
+                }
+                DebuggerCommand::Continue(background) => match &self.inferior {
+                    Some(inferior) if inferior.is_running() => {
+                        output::error("The process is already running")
+                    }
+                    Some(_) if self.recording.is_some() => self.continue_recording(background),
+                    Some(_) => self.resume_inferior(background),
+                    None => output::error("No inferior process to continue"),
+                },
+                DebuggerCommand::Interrupt => match &mut self.inferior {
+                    Some(inferior) if inferior.is_running() => {
+                        self.stop_epoch.fetch_add(1, Ordering::SeqCst);
+                        let status =
+                            inferior.interrupt().expect("Fail to interrupt inferior process");
+                        self.report_status(status);
+                    }
+                    Some(_) => output::error("The process is not running"),
+                    None => output::error("No inferior process to interrupt"),
+                },
+                DebuggerCommand::BackTrace => {
+                    if let Some(target) = self.target() {
+                        if let Err(err) = target.print_backtrace(&self.debug_data) {
+                            output::error(&format!("Error reading stack: {}", err));
                         }
+                    }
+                }
+                DebuggerCommand::Print(format, expr) => {
+                    if let Some(target) = self.expression_target() {
+                        let session = &self.session;
+                        target.print_expression(&self.debug_data, session, &expr, format, false);
+                    }
+                }
+                DebuggerCommand::Return(expr) => self.return_now(expr.as_deref()),
+                DebuggerCommand::Jump(location) => self.jump(&location),
+                DebuggerCommand::Call(expr) => {
+                    if let Some(target) = self.target() {
+                        target.print_expression(&self.debug_data, &self.session, &expr, None, true);
+                    }
+                }
+                DebuggerCommand::Set(expr) => {
+                    if let Some(target) = self.expression_target() {
+                        let result = target.evaluate(&self.debug_data, &self.session, &expr, None);
+                        if let Err(msg) = result {
+                            output::error(&msg);
+                        }
+                    }
+                }
+                DebuggerCommand::Display(format, Some(expression)) => {
+                    let number = self.next_display;
+                    self.next_display += 1;
+                    self.displays.push(Display { number, format, expression });
+                    let values = self.evaluate_displays(self.displays.last());
+                    if output::is_json() {
+                        let display = display_json(self.displays.last().unwrap());
+                        output::set_result(displays_json(&values).pop().unwrap_or(display));
                     } else {
-                        println!("Error starting subprocess");
+                        print_displays(&values);
                     }
                 }
-                DebuggerCommand::Continue => match &mut self.inferior {
-                    Some(inferior) => {
-                        let status = inferior.cont().expect("Fail to continue inferior process");
-                        match status {
-                            Status::Exited(exit_code) => {
-                                println!("Process exited with code {}", exit_code);
-                                self.inferior = None
+                DebuggerCommand::Display(_, None) => {
+                    let values = self.evaluate_displays(&self.displays);
+                    if output::is_json() {
+                        output::set_result(json!({ "displays": displays_json(&values) }));
+                    } else {
+                        print_displays(&values);
+                    }
+                }
+                DebuggerCommand::Undisplay(numbers) if numbers.is_empty() => self.displays.clear(),
+                DebuggerCommand::Undisplay(numbers) => {
+                    for number in numbers {
+                        match self.displays.iter().position(|display| display.number == number) {
+                            Some(index) => {
+                                self.displays.remove(index);
                             }
-                            Status::Signaled(signal) => {
-                                println!("Process exited by signal {}", signal);
-                                self.inferior = None
+                            None => output::error(&format!("No display number {}.", number)),
+                        }
+                    }
+                }
+                DebuggerCommand::Examine(count, format, size, addr) => {
+                    if let Some(target) = self.target() {
+                        let addr = if addr.starts_with('$') {
+                            target
+                                .regs()
+                                .ok()
+                                .and_then(|regs| target::register_value(&regs, &addr[1..]))
+                        } else {
+                            parse_address(&addr)
+                        };
+                        match addr {
+                            Some(addr) => {
+                                if let Err(err) = target.examine(addr, count, format, size) {
+                                    output::error(&format!(
+                                        "Cannot access memory at {:#x}: {}",
+                                        addr, err
+                                    ));
+                                }
                             }
-                            Status::Stopped(signal, rip) => {
-                                println!("Process stopped with signal {} at address 0x{:x}", signal, rip);
-                                self.inferior.as_ref().unwrap().print_stop(&self.debug_data).unwrap();
+                            None => output::error("Invalid address"),
+                        }
+                    }
+                }
+                DebuggerCommand::GenerateCore(path) => match &self.inferior {
+                    Some(inferior) if inferior.is_running() => {
+                        output::error("The process is running; use \"interrupt\" to stop it first")
+                    }
+                    Some(inferior) => {
+                        let path = path.unwrap_or_else(|| format!("core.{}", inferior.pid()));
+                        let regs = inferior.regs().unwrap();
+                        match core_file::generate(inferior.pid(), &regs, &inferior.bp_map, &path) {
+                            Ok(segments) => {
+                                output::console(&format!(
+                                    "Saved corefile {} ({} segments)",
+                                    path, segments
+                                ));
+                                output::set_result(json!({ "path": path, "segments": segments }));
                             }
+                            Err(err) => output::error(&format!(
+                                "Failed to write core file {}: {}",
+                                path, err
+                            )),
                         }
                     }
-                    None => println!("No inferior process to continue"),
+                    None => output::error("No inferior process to dump"),
                 },
-                DebuggerCommand::BackTrace => match &self.inferior {
-                    Some(inferior) => {
-                        inferior.print_backtrace(&self.debug_data).unwrap();
+                DebuggerCommand::Checkpoint => self.checkpoint(),
+                DebuggerCommand::Restart(number) => self.restart(number),
+                DebuggerCommand::Record(true) => self.record(),
+                DebuggerCommand::Record(false) => match self.recording.take() {
+                    Some(recording) => {
+                        recording.discard();
+                        output::console(
+                            "Process record is stopped and all execution logs are deleted.",
+                        );
                     }
-                    None => println!("No inferior process to backtrace"),
+                    None => output::error("No recording is currently active."),
                 },
+                DebuggerCommand::RecordLimit(limit) => {
+                    self.record_limit = limit;
+                    if let Some(recording) = &mut self.recording {
+                        recording.set_max_instructions(limit);
+                    }
+                }
+                DebuggerCommand::ReverseStepi
+                | DebuggerCommand::ReverseStep
+                | DebuggerCommand::ReverseContinue => self.reverse(command),
+                DebuggerCommand::Info(what) => self.print_info(&what),
+                DebuggerCommand::Help(topic) => self.print_help(topic.as_deref()),
                 DebuggerCommand::Quit => {
                     if let Some(inferior) = &mut self.inferior {
                         // inferior is not None, must be stopped
-                        println!("Killing running process (pid={})", inferior.pid());
+                        output::console(&format!(
+                            "Killing running process (pid={})",
+                            inferior.pid()
+                        ));
+                        self.stop_epoch.fetch_add(1, Ordering::SeqCst);
                         inferior.kill().unwrap();
                     }
-                    return;
+                    if let Some(recording) = self.recording.take() {
+                        recording.discard();
+                    }
+                    for checkpoint in &mut self.checkpoints {
+                        checkpoint.inferior.kill().unwrap();
+                    }
+                    output::finish();
+                    return self.exit_code;
                 }
-                DebuggerCommand::BreakPoint(arg) => {
-                    let mut break_addr: Option<u64> = None;
-                    match arg.chars().next() {
-                        Some('*') => {  // raw address
-                            match parse_address(&arg[1..]) {
-                                Some(addr) => {
-                                    break_addr = Some(addr);
-                                }
-                                None => println!("Fail to parse address {}", &arg[1..]),
-                            }
+                DebuggerCommand::Source(path) => self.source(&path),
+                DebuggerCommand::Define(name) => {
+                    if debugger_command::lookup(&name).is_ok() {
+                        output::error(&format!("\"{}\" is a built-in command.", name));
+                        // Skip the definition that follows in a script
+                        if !self.pending.is_empty() {
+                            self.read_command_list();
                         }
-                        _ => {
-                            match usize::from_str_radix(&arg, 10) {
-                                Ok(line_number) => {
-                                    match self.debug_data.get_addr_for_line(None, line_number) {
-                                        Some(addr) => {  // debug_data may give wrong addr
-                                            break_addr = Some(addr as u64);
-                                        },
-                                        None => println!("No address found for line {}", line_number),
-                                    }
-                                },
-                                Err(_) => {  // function name
-                                    match self.debug_data.get_addr_for_function(None, &arg){
-                                        Some(addr) => {
-                                            break_addr = Some(addr as u64);
-                                        },
-                                        None => println!("No address found for function {}", arg),
-                                    }
-                                }
+                    } else {
+                        if self.pending.is_empty() {
+                            output::console(&format!(
+                                "Type commands for definition of \"{}\".",
+                                name
+                            ));
+                            output::console("End with a line saying just \"end\".");
+                        }
+                        let body = self.read_command_list();
+                        self.macros.insert(name, body);
+                    }
+                }
+                DebuggerCommand::Alias(name, expansion) => {
+                    // Aliases are expanded before commands are looked up
+                    if debugger_command::lookup(&name).is_ok() {
+                        output::error(&format!("\"{}\" is a built-in command.", name));
+                    } else {
+                        self.aliases.insert(name, expansion);
+                    }
+                }
+                DebuggerCommand::Commands(num) => {
+                    let num = num.or_else(|| self.breaks.len().checked_sub(1));
+                    match num {
+                        Some(num) if num < self.breaks.len() => {
+                            if self.pending.is_empty() {
+                                output::console(&format!(
+                                    "Type commands for breakpoint {}, one per line.",
+                                    num
+                                ));
+                                output::console("End with a line saying just \"end\".");
                             }
+                            self.breaks[num].commands = self.read_command_list();
                         }
+                        _ => output::error(&format!("No breakpoint number {}", num.unwrap_or(0))),
                     }
+                }
+                DebuggerCommand::CatchSyscall(names) => self.catch_syscall(&names),
+                DebuggerCommand::BreakPoint(arg) => {
+                    let break_addr = self.resolve_location(&arg);
                     if let Some(addr) = break_addr {
-                        println!("Set breakpoint {} at address {:#x}", self.breaks.len(), addr);
-                        self.breaks.push(addr);
+                        output::console(&format!(
+                            "Set breakpoint {} at address {:#x}",
+                            self.breaks.len(),
+                            addr
+                        ));
+                        self.breaks.push(Breakpoint { addr, commands: Vec::new() });
                         if let Some(inferior) = &mut self.inferior {
                             let orig_byte = inferior.write_byte(addr, 0xcc).unwrap();
                             inferior.bp_map.insert(addr, orig_byte);
                         }
+                        output::set_result(json!({
+                            "number": self.breaks.len() - 1,
+                            "address": addr,
+                        }));
                     }
                 }
             }
+            output::finish();
         }
     }
 
-    /// This function prompts the user to enter a command, and continues re-prompting until the user
-    /// enters a valid command. It uses DebuggerCommand::from_tokens to do the command parsing.
-    ///
-    /// You don't need to read, understand, or modify this function.
-    fn get_next_command(&mut self) -> DebuggerCommand {
+    /// Adds a catchpoint for the system calls given by name or number, or for any system call.
+    fn catch_syscall(&mut self, names: &[String]) {
+        let mut numbers = Vec::new();
+        for name in names {
+            match name.parse::<u64>().ok().or_else(|| syscall::number(name)) {
+                Some(number) => numbers.push(number),
+                None => {
+                    output::error(&format!("Unknown syscall name '{}'.", name));
+                    return;
+                }
+            }
+        }
+        output::console(&format!(
+            "Catchpoint {} ({})",
+            self.catchpoints.len(),
+            describe_catchpoint(&numbers)
+        ));
+        output::set_result(json!({
+            "number": self.catchpoints.len(),
+            "syscalls": numbers.iter().map(|number| syscall::describe(*number)).collect::<Vec<_>>(),
+        }));
+        self.catchpoints.push(numbers);
+    }
+
+    /// Resolves a location given to `break` or `jump`: a function name, a line number or
+    /// `*ADDRESS`. Prints why it failed when it returns None.
+    fn resolve_location(&self, arg: &str) -> Option<u64> {
+        let mut resolved: Option<u64> = None;
+        match arg.chars().next() {
+            Some('*') => {  // raw address
+                match parse_address(&arg[1..]) {
+                    Some(addr) => {
+                        resolved = Some(addr);
+                    }
+                    None => {
+                        output::error(&format!("Fail to parse address {}", &arg[1..]))
+                    }
+                }
+            }
+            _ => {
+                match usize::from_str_radix(&arg, 10) {
+                    Ok(line_number) => {
+                        match self.debug_data.get_addr_for_line(None, line_number) {
+                            Some(addr) => {  // debug_data may give wrong addr
+                                resolved = Some(addr as u64);
+                            },
+                            None => output::error(&format!(
+                                "No address found for line {}",
+                                line_number
+                            )),
+                        }
+                    },
+                    Err(_) => {  // function name
+                        let addr = self
+                            .debug_data
+                            .get_addr_for_function(None, &arg)
+                            // Functions that are only declared have no address
+                            .filter(|addr| *addr != 0)
+                            .map(|addr| addr as u64)
+                            .or_else(|| self.find_symbol(arg));
+                        match addr {
+                            Some(addr) => {
+                                resolved = Some(addr);
+                            },
+                            None => output::error(&format!(
+                                "No address found for function {}",
+                                arg
+                            )),
+                        }
+                    }
+                }
+            }
+        }
+        resolved
+    }
+
+    /// Looks up a function without debugging information by its ELF symbol, in the executable
+    /// and then in the shared libraries the running program has loaded.
+    fn find_symbol(&self, name: &str) -> Option<u64> {
+        self.debug_data.get_symbols().address_of(name).or_else(|| {
+            let libraries = self.inferior.as_ref()?.library_symbols();
+            libraries.iter().find_map(|table| table.address_of(name))
+        })
+    }
+
+    /// Returns the live inferior if it is stopped. Prints why not when it returns None.
+    fn stopped_inferior(&mut self) -> Option<&mut Inferior> {
+        match &mut self.inferior {
+            Some(inferior) if inferior.is_running() => {
+                output::error("The process is running; use \"interrupt\" to stop it first");
+                None
+            }
+            Some(inferior) => Some(inferior),
+            None => {
+                output::error("The program is not being run.");
+                None
+            }
+        }
+    }
+
+    /// Saves the state of the stopped program as a paused copy of it (`checkpoint`).
+    fn checkpoint(&mut self) {
+        let copy = match self.stopped_inferior() {
+            Some(inferior) => inferior.fork_checkpoint(),
+            None => return,
+        };
+        match copy {
+            Ok(inferior) => {
+                let number = self.next_checkpoint;
+                self.next_checkpoint += 1;
+                let pid = inferior.pid();
+                output::console(&format!("checkpoint {}: fork returned pid {}", number, pid));
+                output::set_result(json!({ "number": number, "pid": inferior.pid().as_raw() }));
+                self.checkpoints.push(Checkpoint { number, inferior });
+            }
+            Err(msg) => output::error(&format!("Cannot make a checkpoint: {}", msg)),
+        }
+    }
+
+    /// Replaces the program with a fresh copy of checkpoint `number`, so that the checkpoint can
+    /// be gone back to again later. Breakpoints are updated to the current ones.
+    fn restart(&mut self, number: usize) {
+        if self.refuse_while_recording() {
+            return;
+        }
+        let checkpoint = match self.checkpoints.iter_mut().find(|cp| cp.number == number) {
+            Some(checkpoint) => checkpoint,
+            None => {
+                output::error(&format!("No checkpoint number {}.", number));
+                return;
+            }
+        };
+        let mut copy = match checkpoint.inferior.fork_checkpoint() {
+            Ok(copy) => copy,
+            Err(msg) => {
+                output::error(&format!("Cannot restart checkpoint {}: {}", number, msg));
+                return;
+            }
+        };
+        let breaks: Vec<u64> = self.breaks.iter().map(|bp| bp.addr).collect();
+        copy.set_breakpoints(&breaks).unwrap();
+        if let Some(inferior) = &mut self.inferior {
+            self.stop_epoch.fetch_add(1, Ordering::SeqCst);
+            inferior.kill().unwrap();
+        }
+        output::console(&format!("Switching to process {}", copy.pid()));
+        output::set_result(json!({ "checkpoint": number, "pid": copy.pid().as_raw() }));
+        copy.print_stop(&self.debug_data).unwrap();
+        self.inferior = Some(copy);
+    }
+
+    /// Starts recording the stopped program (`record`).
+    fn record(&mut self) {
+        if self.recording.is_some() {
+            output::error(
+                "The process is already being recorded. Use \"record stop\" to stop recording \
+                 first.",
+            );
+            return;
+        }
+        let limit = self.record_limit;
+        let recording = match self.stopped_inferior() {
+            Some(inferior) => Recording::start(inferior, limit),
+            None => return,
+        };
+        match recording {
+            Ok(recording) => self.recording = Some(recording),
+            Err(msg) => output::error(&format!("Cannot record the program: {}", msg)),
+        }
+    }
+
+    /// Continues the recorded program: forward through the log while replaying it, then
+    /// recording again from its end.
+    fn continue_recording(&mut self, background: bool) {
+        if background {
+            output::error("The program cannot run in the background while it is recorded.");
+            return;
+        }
+        let breaks: Vec<u64> = self.breaks.iter().map(|bp| bp.addr).collect();
+        let recording = self.recording.as_mut().unwrap();
+        match recording.resume(&mut self.inferior, &breaks) {
+            Ok(status) => self.report_status(status),
+            Err(msg) => output::error(&msg),
+        }
+    }
+
+    /// Runs one of the reverse commands on the recorded program.
+    fn reverse(&mut self, command: DebuggerCommand) {
+        let recording = match &mut self.recording {
+            Some(recording) => recording,
+            None => {
+                output::error("The program is not being recorded; start with \"record\".");
+                return;
+            }
+        };
+        if self.inferior.as_ref().map_or(false, |inferior| inferior.is_running()) {
+            output::error("The process is running; use \"interrupt\" to stop it first");
+            return;
+        }
+        let breaks: Vec<u64> = self.breaks.iter().map(|bp| bp.addr).collect();
+        let current = &mut self.inferior;
+        let status = match command {
+            DebuggerCommand::ReverseStepi => recording.reverse_stepi(current, &breaks),
+            DebuggerCommand::ReverseStep => {
+                recording.reverse_step(current, &breaks, &self.debug_data)
+            }
+            _ => recording.reverse_continue(current, &breaks),
+        };
+        match status {
+            Ok(status) => self.report_status(status),
+            Err(msg) => output::error(&msg),
+        }
+    }
+
+    /// Tells the user that a command can't be used while recording, if it is the case. Such
+    /// commands change the program in ways the log can't replay.
+    fn refuse_while_recording(&self) -> bool {
+        if self.recording.is_some() {
+            output::error("Not allowed while recording; use \"record stop\" first.");
+        }
+        self.recording.is_some()
+    }
+
+    /// Pops the current stack frame (`return`), returning the value of `expr` converted to the
+    /// function's return type.
+    fn return_now(&mut self, expr: Option<&str>) {
+        if self.refuse_while_recording() {
+            return;
+        }
+        let regs = match self.stopped_inferior().map(|inferior| inferior.regs()) {
+            Some(Ok(regs)) => regs,
+            Some(Err(err)) => return output::error(&format!("Cannot read registers: {}", err)),
+            None => return,
+        };
+        let function = self.debug_data.get_function_at(regs.rip as usize);
+        let name = function.map_or("selected stack frame".to_string(), |func| func.name.clone());
+        let return_type = match function {
+            Some(func) => CType::from_dwarf(&self.debug_data, func.return_type),
+            None => CType::Void,
+        };
+        let value = match expr {
+            Some(expr) => {
+                let inferior = self.inferior.as_ref().unwrap();
+                let evaluator = Evaluator::new(
+                    inferior,
+                    &self.debug_data,
+                    regs.rip as usize,
+                    regs.rbp as usize,
+                )
+                .with_session(&self.session);
+                let value = evaluator.evaluate(expr).and_then(|value| match return_type {
+                    CType::Void => Ok(value),
+                    _ => evaluator.cast(&return_type, value),
+                });
+                match value {
+                    Ok(value) => Some((value.bits(), matches!(value.ctype, CType::Float { .. }))),
+                    Err(msg) => return output::error(&msg),
+                }
+            }
+            None => None,
+        };
+        if !self.confirm(&format!("Make {} return now?", name)) {
+            return;
+        }
+        let inferior = self.inferior.as_mut().unwrap();
+        match inferior.return_from_function(&self.debug_data, value) {
+            Ok(()) => inferior.print_stop(&self.debug_data).unwrap(),
+            Err(err) => output::error(&format!("Cannot pop the stack frame: {}", err)),
+        }
+    }
+
+    /// Resumes the program at another location (`jump`), asking first if that means leaving
+    /// the current function.
+    fn jump(&mut self, location: &str) {
+        if self.refuse_while_recording() {
+            return;
+        }
+        let rip = match self.stopped_inferior().map(|inferior| inferior.regs()) {
+            Some(Ok(regs)) => regs.rip as usize,
+            Some(Err(err)) => return output::error(&format!("Cannot read registers: {}", err)),
+            None => return,
+        };
+        let addr = match self.resolve_location(location) {
+            Some(addr) => addr,
+            None => return,
+        };
+        let function_at = |addr| self.debug_data.get_function_at(addr).map(|func| func.address);
+        let target_function = function_at(addr as usize);
+        let current = self.debug_data.get_function_at(rip).map(|f| (f.address, f.name.clone()));
+        if let Some((current_address, current_name)) = current {
+            let question = format!("{} is not in `{}'. Jump anyway?", location, current_name);
+            if target_function != Some(current_address) && !self.confirm(&question) {
+                return;
+            }
+        }
+        output::console(&format!("Continuing at {:#x}.", addr));
+        match self.inferior.as_mut().unwrap().set_pc(addr) {
+            Ok(()) => self.resume_inferior(false),
+            Err(err) => output::error(&format!("Cannot set rip: {}", err)),
+        }
+    }
+
+    /// Returns the program to inspect: the stopped inferior if there is one, otherwise the loaded
+    /// core file. Prints why nothing can be inspected when it returns None.
+    fn target(&self) -> Option<&dyn Target> {
+        let target = self.stopped_target();
+        if target.is_none() {
+            match &self.inferior {
+                Some(_) => {
+                    output::error("The process is running; use \"interrupt\" to stop it first")
+                }
+                None => output::error("The program is not being run."),
+            }
+        }
+        target
+    }
+
+    /// Like `target`, but without a program, returns a stand-in that can only evaluate
+    /// expressions that don't need one, such as `$foo = 1`.
+    fn expression_target(&self) -> Option<&dyn Target> {
+        match (&self.inferior, &self.core) {
+            (None, None) => Some(&NoProcess),
+            _ => self.target(),
+        }
+    }
+
+    /// Like `target`, but quietly returns None if nothing can be inspected.
+    fn stopped_target(&self) -> Option<&dyn Target> {
+        match (&self.inferior, &self.core) {
+            (Some(inferior), _) if inferior.is_running() => None,
+            (Some(inferior), _) => Some(inferior),
+            (None, Some(core)) => Some(core),
+            (None, None) => None,
+        }
+    }
+
+    /// Evaluates displays in the program being inspected, returning their values or the errors
+    /// evaluating them. Returns nothing if there is no program to inspect.
+    fn evaluate_displays<'a>(
+        &self,
+        displays: impl IntoIterator<Item = &'a Display>,
+    ) -> Vec<(&'a Display, Result<String, String>)> {
+        let target = match self.stopped_target() {
+            Some(target) => target,
+            None => return Vec::new(),
+        };
+        displays
+            .into_iter()
+            .map(|display| {
+                let value = target
+                    .evaluate(&self.debug_data, &self.session, &display.expression, display.format)
+                    .map(|(_, text)| text);
+                (display, value)
+            })
+            .collect()
+    }
+
+    /// Resumes the current inferior. In the foreground this waits for the next stop and reports
+    /// it; in the background it returns immediately and the stop is announced asynchronously.
+    fn resume_inferior(&mut self, background: bool) {
+        let inferior = self.inferior.as_mut().unwrap();
+        let catchpoints = &self.catchpoints;
+        // Background runs are waited on by a notifier thread that can't resume the inferior past
+        // uncaught system calls, so catchpoints only apply in the foreground
+        inferior.trace_syscalls = !background && !self.catchpoints.is_empty();
+        let status = if background {
+            match inferior.resume(None).expect("Fail to continue inferior process") {
+                Some(status) => status,
+                None => {
+                    inferior.spawn_stop_notifier(self.stop_epoch.clone());
+                    return;
+                }
+            }
+        } else {
+            loop {
+                let status = inferior.cont().expect("Fail to continue inferior process");
+                match inferior.syscall_stop() {
+                    Some(stop) if !catchpoints.iter().any(|nums| syscall::matches(stop, nums)) => {
+                        continue
+                    }
+                    _ => break status,
+                }
+            }
+        };
+        self.report_status(status);
+    }
+
+    /// Returns the number of the first catchpoint matching a system call stop.
+    fn catching(&self, stop: &SyscallStop) -> Option<usize> {
+        self.catchpoints.iter().position(|numbers| syscall::matches(stop, numbers))
+    }
+
+    /// Prints a status returned by the inferior, dropping the inferior once it has terminated.
+    fn report_status(&mut self, status: Status) {
+        self.stop_epoch.fetch_add(1, Ordering::SeqCst);
+        match status {
+            Status::Exited(exit_code) => {
+                if output::is_json() {
+                    output::emit(json!({
+                        "type": "stopped",
+                        "reason": "exited",
+                        "exit-code": exit_code,
+                    }));
+                } else {
+                    println!("Process exited with code {}", exit_code);
+                }
+                self.inferior = None
+            }
+            Status::Signaled(signal) => {
+                if output::is_json() {
+                    output::emit(json!({
+                        "type": "stopped",
+                        "reason": "exited-signalled",
+                        "signal": format!("{}", signal),
+                    }));
+                } else {
+                    println!("Process exited by signal {}", signal);
+                }
+                self.exit_code = 128 + signal as i32;
+                self.inferior = None
+            }
+            Status::Stopped(signal, rip) => {
+                let syscall_stop = self.inferior.as_ref().and_then(|i| i.syscall_stop().cloned());
+                let caught = syscall_stop.as_ref().and_then(|stop| {
+                    let num = self.catching(stop)?;
+                    Some((num, stop, self.describe_syscall_stop(stop)))
+                });
+                // rip is just past the int3 of a breakpoint that was hit
+                let hit = match signal {
+                    Signal::SIGTRAP if syscall_stop.is_none() => {
+                        self.breaks.iter().position(|bp| bp.addr + 1 == rip as u64)
+                    }
+                    _ => None,
+                };
+                let commands = hit.map(|num| self.breaks[num].commands.clone()).unwrap_or_default();
+                let silent = commands.first().map(|cmd| cmd.trim() == "silent").unwrap_or(false);
+                let mut crash = None;
+                if is_crash(signal) {
+                    self.exit_code = 128 + signal as i32;
+                    let inferior = self.inferior.as_ref().unwrap();
+                    crash = Crash::analyze(inferior, &self.debug_data, signal);
+                }
+                let displays = if silent && !output::is_json() {
+                    Vec::new()
+                } else {
+                    self.evaluate_displays(&self.displays)
+                };
+                if output::is_json() {
+                    // Stops are always reported to tools, even for silent breakpoints
+                    let reason = match &caught {
+                        Some((_, stop, _)) if stop.result.is_some() => "syscall-return",
+                        Some(_) => "syscall-entry",
+                        None if hit.is_some() => "breakpoint-hit",
+                        None => "signal-received",
+                    };
+                    let mut record = json!({
+                        "type": "stopped",
+                        "reason": reason,
+                        "signal": format!("{}", signal),
+                        "rip": rip,
+                        "function": self.debug_data.get_function_from_addr(rip),
+                        "line": self.debug_data.get_line_from_addr(rip).as_ref().map(output::line),
+                    });
+                    if let Some(num) = hit {
+                        record["breakpoint"] = json!(num);
+                    }
+                    if let Some(crash) = &crash {
+                        record["crash"] = crash.to_json();
+                    }
+                    if let Some((num, stop, _)) = &caught {
+                        record["catchpoint"] = json!(num);
+                        record["syscall"] = json!(syscall::describe(stop.number));
+                        record["syscall-number"] = json!(stop.number);
+                        record["result"] = json!(stop.result);
+                    }
+                    if !displays.is_empty() {
+                        record["displays"] = json!(displays_json(&displays));
+                    }
+                    output::emit(record);
+                } else if let Some((num, _, description)) = &caught {
+                    println!("Catchpoint {} ({})", num, description);
+                    self.inferior.as_ref().unwrap().print_stop(&self.debug_data).unwrap();
+                    print_displays(&displays);
+                } else if !silent {
+                    println!("Process stopped with signal {} at address 0x{:x}", signal, rip);
+                    if let Some(crash) = &crash {
+                        crash.print();
+                    }
+                    self.inferior.as_ref().unwrap().print_stop(&self.debug_data).unwrap();
+                    print_displays(&displays);
+                }
+                self.queue_breakpoint_commands(&commands);
+            }
+        }
+    }
+
+    /// Describes a system call stop, e.g. "call to syscall write(1, "hi\n", 3)" or "returned from
+    /// syscall write = 3".
+    fn describe_syscall_stop(&self, stop: &SyscallStop) -> String {
+        match stop.result {
+            None => {
+                let inferior = self.inferior.as_ref().unwrap();
+                format!("call to syscall {}", syscall::format_call(inferior, stop))
+            }
+            Some(result) => format!(
+                "returned from syscall {} = {}",
+                syscall::describe(stop.number),
+                syscall::format_result(stop.number, result)
+            ),
+        }
+    }
+
+    /// Reads the body of `commands` or `define` up to the closing `end`, from the queued script
+    /// lines if there are any, otherwise from the user.
+    fn read_command_list(&mut self) -> Vec<String> {
+        let mut commands = Vec::new();
+        while let Some(line) = self.next_line(">") {
+            let line = line.trim();
+            if line == "end" {
+                break;
+            }
+            if !line.is_empty() {
+                commands.push(line.to_string());
+            }
+        }
+        commands
+    }
+
+    /// Runs the command list of a breakpoint that was hit before any other pending command. Like
+    /// gdb, commands after one that resumes the inferior are not run.
+    fn queue_breakpoint_commands(&mut self, commands: &[String]) {
+        let mut commands: Vec<&String> = commands.iter().filter(|cmd| *cmd != "silent").collect();
+        let resume = commands.iter().position(|cmd| {
+            let cmd = self.expand_alias(cmd);
+            let tokens: Vec<&str> = cmd.split_whitespace().collect();
+            match DebuggerCommand::from_tokens(&tokens) {
+                Ok(DebuggerCommand::Continue(_)) | Ok(DebuggerCommand::Run(..)) => true,
+                _ => false,
+            }
+        });
+        if let Some(resume) = resume {
+            commands.truncate(resume + 1);
+        }
+        let commands = commands.into_iter().cloned().collect();
+        self.queue_lines(commands, self.sources.clone(), self.macro_depth);
+    }
+
+    /// Replaces a user alias at the start of a command line with the text it stands for.
+    fn expand_alias(&self, line: &str) -> String {
+        let line = line.trim();
+        let (name, rest) = line.split_at(line.find(char::is_whitespace).unwrap_or(line.len()));
+        match self.aliases.get(name) {
+            Some(expansion) => format!("{}{}", expansion, rest),
+            None => line.to_string(),
+        }
+    }
+
+    /// Runs a user macro before any other pending command, substituting its arguments for
+    /// `$arg0`, `$arg1`... and their number for `$argc`.
+    fn queue_macro(&mut self, name: &str, args: &[&str]) {
+        if self.macro_depth >= MAX_USER_CALL_DEPTH {
+            output::error("Max user call depth exceeded -- command aborted.");
+            // Abandon the rest of the macros being run, as if they had all failed
+            while self.pending.front().map_or(false, |line| line.macro_depth > 0) {
+                self.pending.pop_front();
+            }
+            return;
+        }
+        let body = self.macros[name]
+            .iter()
+            .map(|line| {
+                let mut line = line.replace("$argc", &args.len().to_string());
+                // Substitute from the last argument so that $arg1 doesn't clobber $arg10
+                for (i, arg) in args.iter().enumerate().rev() {
+                    line = line.replace(&format!("$arg{}", i), arg);
+                }
+                line
+            })
+            .collect();
+        self.queue_lines(body, self.sources.clone(), self.macro_depth + 1);
+    }
+
+    /// Reaps and reports a state change of an inferior running in the background, if one has
+    /// happened since the last prompt.
+    fn poll_inferior(&mut self) {
+        let status = match &mut self.inferior {
+            Some(inferior) if inferior.is_running() => inferior.try_wait().unwrap(),
+            _ => None,
+        };
+        if let Some(status) = status {
+            self.report_status(status);
+        }
+    }
+
+    fn print_help(&self, topic: Option<&str>) {
+        let topic = match topic {
+            Some(topic) => topic,
+            None => {
+                output::console("List of commands:");
+                for info in COMMANDS {
+                    output::console(&format!("  {:<36} {}", info.usage, info.summary));
+                }
+                for name in self.macros.keys() {
+                    output::console(&format!("  {:<36} User-defined.", name));
+                }
+                output::console("Type \"help\" followed by a command name for more information.");
+                return;
+            }
+        };
+        if let Some(expansion) = self.aliases.get(topic) {
+            output::console(&format!("\"{}\" is an alias for \"{}\".", topic, expansion));
+        } else if let Some(body) = self.macros.get(topic) {
+            output::console(&format!("\"{}\" is a user-defined command running:", topic));
+            for line in body {
+                output::console(&format!("  {}", line));
+            }
+        } else {
+            match debugger_command::lookup(topic) {
+                Ok(info) => {
+                    output::console(&format!("Usage: {}", info.usage));
+                    output::console(info.help);
+                    if !info.aliases.is_empty() {
+                        output::console(&format!("Aliases: {}", info.aliases.join(", ")));
+                    }
+                }
+                Err(msg) => output::error(&msg),
+            }
+        }
+    }
+
+    fn print_info(&self, what: &str) {
+        match what {
+            "threads" => match &self.inferior {
+                Some(inferior) if output::is_json() => {
+                    let threads: Vec<_> = inferior
+                        .threads()
+                        .into_iter()
+                        .map(|(tid, name, state)| {
+                            json!({ "tid": tid, "name": name, "state": state })
+                        })
+                        .collect();
+                    output::set_result(json!({
+                        "pid": inferior.pid().as_raw(),
+                        "running": inferior.is_running(),
+                        "threads": threads,
+                    }));
+                }
+                Some(inferior) => {
+                    let state = if inferior.is_running() { "running" } else { "stopped" };
+                    println!("Process {} is {}", inferior.pid(), state);
+                    for (tid, name, sched_state) in inferior.threads() {
+                        println!("  Thread {} ({}) state {}", tid, name, sched_state);
+                    }
+                }
+                None => output::error("No inferior process"),
+            },
+            "breakpoints" | "break" | "b" if output::is_json() => {
+                let breaks: Vec<_> = self
+                    .breaks
+                    .iter()
+                    .enumerate()
+                    .map(|(num, bp)| {
+                        json!({ "number": num, "address": bp.addr, "commands": bp.commands })
+                    })
+                    .collect();
+                let catchpoints: Vec<_> = self
+                    .catchpoints
+                    .iter()
+                    .enumerate()
+                    .map(|(num, numbers)| {
+                        let names: Vec<_> =
+                            numbers.iter().map(|number| syscall::describe(*number)).collect();
+                        json!({ "number": num, "syscalls": names })
+                    })
+                    .collect();
+                output::set_result(json!({ "breakpoints": breaks, "catchpoints": catchpoints }));
+            }
+            "breakpoints" | "break" | "b" => {
+                for (num, bp) in self.breaks.iter().enumerate() {
+                    println!("Breakpoint {} at {:#x}", num, bp.addr);
+                    for command in &bp.commands {
+                        println!("        {}", command);
+                    }
+                }
+                for (num, numbers) in self.catchpoints.iter().enumerate() {
+                    println!("Catchpoint {} ({})", num, describe_catchpoint(numbers));
+                }
+            }
+            "display" if output::is_json() => {
+                let displays: Vec<_> = self.displays.iter().map(display_json).collect();
+                output::set_result(json!({ "displays": displays }));
+            }
+            "display" if self.displays.is_empty() => {
+                println!("There are no auto-display expressions now.");
+            }
+            "display" => {
+                println!("Auto-display expressions now in effect:");
+                for display in &self.displays {
+                    println!("{}: {}", display.number, display.label());
+                }
+            }
+            "checkpoints" if output::is_json() => {
+                let checkpoints: Vec<_> = self
+                    .checkpoints
+                    .iter()
+                    .map(|checkpoint| {
+                        let rip = checkpoint.inferior.regs().unwrap().rip as usize;
+                        let line = self.debug_data.get_line_from_addr(rip);
+                        json!({
+                            "number": checkpoint.number,
+                            "pid": checkpoint.inferior.pid().as_raw(),
+                            "rip": rip,
+                            "function": self.debug_data.get_function_from_addr(rip),
+                            "line": line.as_ref().map(output::line),
+                        })
+                    })
+                    .collect();
+                output::set_result(json!({ "checkpoints": checkpoints }));
+            }
+            "checkpoints" if self.checkpoints.is_empty() => println!("No checkpoints."),
+            "checkpoints" => {
+                for checkpoint in &self.checkpoints {
+                    let rip = checkpoint.inferior.regs().unwrap().rip as usize;
+                    let location = match self.debug_data.get_line_from_addr(rip) {
+                        Some(line) => format!("{:#x} ({})", rip, line),
+                        None => format!("{:#x}", rip),
+                    };
+                    println!(
+                        "  {} process {} at {}",
+                        checkpoint.number,
+                        checkpoint.inferior.pid(),
+                        location
+                    );
+                }
+            }
+            "record" => match &self.recording {
+                Some(recording) if output::is_json() => output::set_result(recording.info()),
+                Some(recording) => recording.print_info(),
+                None => output::console("No recording is currently active."),
+            },
+            _ => output::error("Usage: info threads|breakpoints|display|checkpoints|record"),
+        }
+    }
+
+    /// Returns the next line of input: a queued script line if there is one, otherwise a line
+    /// typed by the user at `prompt`. Returns None at the end of input (ctrl+d, or the end of the
+    /// scripts in batch mode).
+    fn next_line(&mut self, prompt: &str) -> Option<String> {
         loop {
+            if let Some(line) = self.pending.pop_front() {
+                self.interactive = false;
+                self.sources = line.sources;
+                self.macro_depth = line.macro_depth;
+                return Some(line.text);
+            }
+            if self.batch {
+                return None;
+            }
+            self.interactive = true;
+            self.sources.clear();
+            self.macro_depth = 0;
             // Print prompt and get next line of user input
-            match self.readline.readline("(deet) ") {
+            // Tools driving the JSON interpreter don't want prompts mixed into the records
+            let prompt = if output::is_json() { "" } else { prompt };
+            match self.readline.readline(prompt) {
                 Err(ReadlineError::Interrupted) => {
                     // User pressed ctrl+c. We're going to ignore it
-                    println!("Type \"quit\" to exit");
+                    output::console("Type \"quit\" to exit");
                 }
                 Err(ReadlineError::Eof) => {
                     // User pressed ctrl+d, which is the equivalent of "quit" for our purposes
-                    return DebuggerCommand::Quit;
+                    return None;
                 }
                 Err(err) => {
                     panic!("Unexpected I/O error: {:?}", err);
                 }
                 Ok(line) => {
-                    if line.trim().len() == 0 {
-                        continue;
-                    }
-                    self.readline.add_history_entry(line.as_str());
-                    if let Err(err) = self.readline.save_history(&self.history_path) {
-                        println!(
-                            "Warning: failed to save history file at {}: {}",
-                            self.history_path, err
-                        );
-                    }
-                    let tokens: Vec<&str> = line.split_whitespace().collect();
-                    if let Some(cmd) = DebuggerCommand::from_tokens(&tokens) {
-                        return cmd;
-                    } else {
-                        println!("Unrecognized command.");
+                    if line.trim().len() > 0 {
+                        self.readline.add_history_entry(line.as_str());
+                        if let Err(err) = self.readline.save_history(&self.history_path) {
+                            output::console(&format!(
+                                "Warning: failed to save history file at {}: {}",
+                                self.history_path, err
+                            ));
+                        }
                     }
+                    return Some(line);
+                }
+            }
+        }
+    }
+
+    /// Asks the user to confirm a command that changes the program's state. Commands from
+    /// scripts and from tools driving the JSON interpreter are not confirmed.
+    fn confirm(&mut self, question: &str) -> bool {
+        if !self.interactive || output::is_json() {
+            return true;
+        }
+        loop {
+            match self.readline.readline(&format!("{} (y or n) ", question)) {
+                Ok(answer) => match answer.trim() {
+                    "y" | "yes" => return true,
+                    "n" | "no" => break,
+                    _ => println!("Please answer y or n."),
+                },
+                Err(_) => break,
+            }
+        }
+        println!("Not confirmed.");
+        false
+    }
+
+    /// This function prompts the user to enter a command, and continues re-prompting until the user
+    /// enters a valid command. It uses DebuggerCommand::from_tokens to do the command parsing.
+    /// Commands queued from scripts take precedence over the prompt.
+    fn get_next_command(&mut self) -> DebuggerCommand {
+        loop {
+            let line = match self.next_line("(deet) ") {
+                Some(line) => line,
+                None => return DebuggerCommand::Quit,
+            };
+            self.poll_inferior();
+            let line = self.expand_alias(&line);
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if tokens.is_empty() || tokens[0].starts_with('#') {
+                continue;
+            }
+            output::begin(&line);
+            if self.macros.contains_key(tokens[0]) {
+                self.queue_macro(tokens[0], &tokens[1..]);
+                output::finish();
+                continue;
+            }
+            match DebuggerCommand::from_tokens(&tokens) {
+                Ok(cmd) => return cmd,
+                Err(msg) => {
+                    output::error(&msg);
+                    output::finish();
                 }
             }
         }
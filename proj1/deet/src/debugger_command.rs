@@ -1,33 +1,494 @@
 pub enum DebuggerCommand {
     Quit,
-    Run(Vec<String>),
-    Continue,
+    /// Arguments for the inferior, and whether to run it in the background (`run ... &`)
+    Run(Vec<String>, bool),
+    /// Whether to continue in the background (`continue &`)
+    Continue(bool),
     BackTrace,
     BreakPoint(String),
+    /// Names or numbers of the system calls to catch, or any system call if empty
+    CatchSyscall(Vec<String>),
+    Interrupt,
+    Info(String),
+    /// Format letter (`print/x`) and expression
+    Print(Option<char>, String),
+    /// Value to return from the current function, if any
+    Return(Option<String>),
+    /// Location to resume execution at: FUNCTION, LINE or *ADDRESS
+    Jump(String),
+    /// Expression to evaluate, typically a function call; void results are not printed
+    Call(String),
+    /// Expression to evaluate for its side effects, e.g. `set $count = 0`
+    Set(String),
+    /// Format letter and expression to show at every stop; no expression shows them all now
+    Display(Option<char>, Option<String>),
+    /// Numbers of the displays to delete, or all of them if empty
+    Undisplay(Vec<usize>),
+    /// `x/NFU addr`: count, format letter, unit size in bytes and address
+    Examine(usize, char, usize, String),
+    /// Path to write the core file to, defaulting to core.<pid>
+    GenerateCore(Option<String>),
+    Checkpoint,
+    /// Number of the checkpoint to go back to
+    Restart(usize),
+    /// Whether to start recording (`record`) or to stop and discard the log (`record stop`)
+    Record(bool),
+    /// Most instructions a recording keeps (`set record full insn-number-max`), or None for no
+    /// limit
+    RecordLimit(Option<usize>),
+    ReverseStepi,
+    ReverseStep,
+    ReverseContinue,
+    /// Path of a command file to execute
+    Source(String),
+    /// Breakpoint whose command list to set, defaulting to the last one
+    Commands(Option<usize>),
+    /// Name of a user macro whose body follows, up to `end`
+    Define(String),
+    /// Alias name and the command text it stands for
+    Alias(String, String),
+    /// Command to describe, or None to list all commands
+    Help(Option<String>),
+}
+
+/// Describes a command for parsing, `help` and tab completion.
+pub struct CommandInfo {
+    pub name: &'static str,
+    /// Other names that always select this command, even where they are a prefix of others
+    pub aliases: &'static [&'static str],
+    pub usage: &'static str,
+    /// One line for the list of commands
+    pub summary: &'static str,
+    pub help: &'static str,
+}
+
+/// All commands, in the order `help` lists them.
+pub const COMMANDS: &[CommandInfo] = &[
+    CommandInfo {
+        name: "run",
+        aliases: &["r"],
+        usage: "run [ARGS...] [&]",
+        summary: "Start the program.",
+        help: "Start the program with ARGS, killing it first if it is already running. With a \
+               trailing &, run it in the background.",
+    },
+    CommandInfo {
+        name: "continue",
+        aliases: &["c", "cont"],
+        usage: "continue [&]",
+        summary: "Continue the stopped program.",
+        help: "Continue the stopped program. With a trailing &, continue in the background.",
+    },
+    CommandInfo {
+        name: "interrupt",
+        aliases: &[],
+        usage: "interrupt",
+        summary: "Stop a program running in the background.",
+        help: "Stop a program running in the background.",
+    },
+    CommandInfo {
+        name: "break",
+        aliases: &["b", "breakpoint"],
+        usage: "break FUNCTION | LINE | *ADDRESS",
+        summary: "Set a breakpoint.",
+        help: "Set a breakpoint at the start of a function, at a line of the main source file, \
+               or at an address.",
+    },
+    CommandInfo {
+        name: "commands",
+        aliases: &[],
+        usage: "commands [BREAKPOINT]",
+        summary: "Set commands to run when a breakpoint is hit.",
+        help: "Set the commands to run when a breakpoint (by default the last one) is hit, one \
+               per line up to \"end\". A first line of \"silent\" suppresses the stop message.",
+    },
+    CommandInfo {
+        name: "catch",
+        aliases: &[],
+        usage: "catch syscall [NAME | NUMBER...]",
+        summary: "Stop when the program makes a system call.",
+        help: "Set a catchpoint that stops the program on entry to and return from the given \
+               system calls, or any system call if none are given. Catchpoints only apply when \
+               the program runs in the foreground.",
+    },
+    CommandInfo {
+        name: "backtrace",
+        aliases: &["bt", "back"],
+        usage: "backtrace",
+        summary: "Print the call stack.",
+        help: "Print the call stack.",
+    },
+    CommandInfo {
+        name: "print",
+        aliases: &["p"],
+        usage: "print[/F] EXPRESSION",
+        summary: "Print the value of an expression.",
+        help: "Print the value of a C expression, e.g. \"*p\", \"arr[i + 1]\", \
+               \"s->next->value\", \"x * 2 + y\", \"(long)ptr\" or \"$rip\". Integers and \
+               pointers are shown in format F if given (x, d, u, o, t or c). Each value printed \
+               is recorded as $1, $2, ..., which later expressions can use; $ is the last value \
+               and $$ the one before.",
+    },
+    CommandInfo {
+        name: "return",
+        aliases: &[],
+        usage: "return [EXPRESSION]",
+        summary: "Make the current function return now.",
+        help: "Pop the current stack frame without running the rest of the function, returning \
+               the value of EXPRESSION if given. The program stays stopped in the caller.",
+    },
+    CommandInfo {
+        name: "jump",
+        aliases: &[],
+        usage: "jump LINE | FUNCTION | *ADDRESS",
+        summary: "Continue the program at another location.",
+        help: "Resume execution at the given location instead of where the program stopped. \
+               Jumping out of the current function leaves its stack frame in place.",
+    },
+    CommandInfo {
+        name: "call",
+        aliases: &[],
+        usage: "call FUNCTION(ARGUMENTS...)",
+        summary: "Call a function in the program.",
+        help: "Call a function in the program and print its return value like print, unless it \
+               returns void. Function calls can also be part of any expression, e.g. \
+               \"print count_list(head) * 2\". Breakpoints are ignored during the call.",
+    },
+    CommandInfo {
+        name: "set",
+        aliases: &[],
+        usage: "set $VARIABLE = EXPRESSION | set record full insn-number-max N|unlimited",
+        summary: "Set a convenience variable or the recording limit.",
+        help: "Evaluate an expression without printing it, typically an assignment to a \
+               convenience variable such as \"set $count = $count + 1\". Convenience variables \
+               keep their values across stops and can be used in any expression. \
+               \"set record full insn-number-max\" limits how many instructions \"record\" \
+               keeps (200000 by default); the oldest are deleted past it.",
+    },
+    CommandInfo {
+        name: "display",
+        aliases: &[],
+        usage: "display[/F] [EXPRESSION]",
+        summary: "Print an expression every time the program stops.",
+        help: "Print the value of an expression, as with print, now and every time the program \
+               stops. Without an expression, show all the displays now.",
+    },
+    CommandInfo {
+        name: "undisplay",
+        aliases: &[],
+        usage: "undisplay [NUMBER...]",
+        summary: "Stop displaying expressions.",
+        help: "Delete the given displays (see \"info display\"), or all of them.",
+    },
+    CommandInfo {
+        name: "x",
+        aliases: &[],
+        usage: "x[/NFU] ADDRESS | $REGISTER",
+        summary: "Examine memory.",
+        help: "Examine memory: N units (default 1) of size U (b, h, w or g; default w) in \
+               format F (x, d, u or c; default x).",
+    },
+    CommandInfo {
+        name: "info",
+        aliases: &["i"],
+        usage: "info threads | breakpoints | display | checkpoints | record",
+        summary: "Describe threads, breakpoints, displays, checkpoints or the recording.",
+        help: "Describe the threads of the program, the breakpoints, the expressions to \
+               display at every stop, the checkpoints or the recording.",
+    },
+    CommandInfo {
+        name: "gcore",
+        aliases: &["generate-core-file"],
+        usage: "gcore [FILE]",
+        summary: "Write a core file of the stopped program.",
+        help: "Write a core file of the stopped program, by default to core.<pid>.",
+    },
+    CommandInfo {
+        name: "checkpoint",
+        aliases: &[],
+        usage: "checkpoint",
+        summary: "Save the state of the stopped program.",
+        help: "Fork a paused copy of the stopped program, which \"restart\" can go back to.",
+    },
+    CommandInfo {
+        name: "restart",
+        aliases: &[],
+        usage: "restart CHECKPOINT",
+        summary: "Go back to a checkpoint.",
+        help: "Replace the program with a copy of the given checkpoint, killing the current \
+               process. The checkpoint itself is kept, so it can be restarted again.",
+    },
+    CommandInfo {
+        name: "record",
+        aliases: &["rec"],
+        usage: "record [stop]",
+        summary: "Record the program so that it can run backwards.",
+        help: "Record every instruction the stopped program runs from now on, so that the \
+               reverse commands can go back to earlier states. Recording single-steps the \
+               program, which makes it much slower. \"record stop\" discards the log, leaving \
+               the program where it is.",
+    },
+    CommandInfo {
+        name: "reverse-stepi",
+        aliases: &["rsi"],
+        usage: "reverse-stepi",
+        summary: "Go back one instruction.",
+        help: "Go back to the state before the last instruction of the recording.",
+    },
+    CommandInfo {
+        name: "reverse-step",
+        aliases: &["rs"],
+        usage: "reverse-step",
+        summary: "Go back to the previous source line.",
+        help: "Go back to the start of the source line that ran before the current one, \
+               stepping back into functions it called.",
+    },
+    CommandInfo {
+        name: "reverse-continue",
+        aliases: &["rc"],
+        usage: "reverse-continue",
+        summary: "Run backwards to the previous breakpoint.",
+        help: "Go back to the last time a breakpoint was reached, or to the start of the \
+               recording. \"continue\" then replays the recording forward up to the next \
+               breakpoint, and records again once it reaches the end.",
+    },
+    CommandInfo {
+        name: "source",
+        aliases: &[],
+        usage: "source FILE",
+        summary: "Run the commands in a file.",
+        help: "Run the commands in FILE.",
+    },
+    CommandInfo {
+        name: "define",
+        aliases: &[],
+        usage: "define NAME",
+        summary: "Define a command.",
+        help: "Define a command running the following lines up to \"end\", with its arguments \
+               substituted for $arg0, $arg1... and their number for $argc. Built-in commands, \
+               and abbreviations of them, can't be redefined.",
+    },
+    CommandInfo {
+        name: "alias",
+        aliases: &[],
+        usage: "alias NAME = COMMAND...",
+        summary: "Define an alias for a command.",
+        help: "Make NAME stand for the start of a command, e.g. \"alias xs = x/16xb\". \
+               Built-in commands, and abbreviations of them, can't be aliased.",
+    },
+    CommandInfo {
+        name: "help",
+        aliases: &["h"],
+        usage: "help [COMMAND]",
+        summary: "List the commands, or describe one.",
+        help: "List the commands, or describe one.",
+    },
+    CommandInfo {
+        name: "quit",
+        aliases: &["q"],
+        usage: "quit",
+        summary: "Exit the debugger.",
+        help: "Kill the program if it is running and exit.",
+    },
+];
+
+/// Finds the command a word refers to: an exact name or alias, or else a prefix of exactly one
+/// command name.
+pub fn lookup(word: &str) -> Result<&'static CommandInfo, String> {
+    if let Some(info) =
+        COMMANDS.iter().find(|info| info.name == word || info.aliases.contains(&word))
+    {
+        return Ok(info);
+    }
+    let matches: Vec<&CommandInfo> =
+        COMMANDS.iter().filter(|info| info.name.starts_with(word)).collect();
+    match matches.len() {
+        1 => Ok(matches[0]),
+        0 => Err(format!("Undefined command: \"{}\". Try \"help\".", word)),
+        _ => {
+            let names: Vec<&str> = matches.iter().map(|info| info.name).collect();
+            Err(format!("Ambiguous command \"{}\": {}.", word, names.join(", ")))
+        }
+    }
 }
 
 impl DebuggerCommand {
-    pub fn from_tokens(tokens: &Vec<&str>) -> Option<DebuggerCommand> {
-        match tokens[0] {
-            "q" | "quit" => Some(DebuggerCommand::Quit),
-            "r" | "run" => {
-                let args = tokens[1..].to_vec();
-                Some(DebuggerCommand::Run(
-                    args.iter().map(|s| s.to_string()).collect(),
-                ))
-            }
-            "c" | "cont" | "continue" => {
-                Some(DebuggerCommand::Continue)
+    /// Parses a command line split into words, returning a message for the user if it is not a
+    /// valid command.
+    pub fn from_tokens(tokens: &Vec<&str>) -> Result<DebuggerCommand, String> {
+        // Formats are attached to the command word, as in "x/16xb" or "print/x"
+        let (word, suffix) = match tokens[0].find('/') {
+            Some(slash) => tokens[0].split_at(slash),
+            None => (tokens[0], ""),
+        };
+        let info = lookup(word)?;
+        let args = &tokens[1..];
+        let usage = || format!("Usage: {}", info.usage);
+        if !suffix.is_empty() && !["x", "print", "display"].contains(&info.name) {
+            return Err(usage());
+        }
+        let print_format = || match parse_print_format(suffix) {
+            Some(format) => Ok(format),
+            None => Err(format!("Invalid format \"{}\". {}", suffix, usage())),
+        };
+        match info.name {
+            "quit" => Ok(DebuggerCommand::Quit),
+            "run" => {
+                let (args, background) = split_background(args);
+                Ok(DebuggerCommand::Run(args.iter().map(|s| s.to_string()).collect(), background))
             }
-            "bt" | "back" | "backtrace" => {
-                Some(DebuggerCommand::BackTrace)
+            "continue" => match split_background(args) {
+                (&[], background) => Ok(DebuggerCommand::Continue(background)),
+                _ => Err(usage()),
+            },
+            "interrupt" => Ok(DebuggerCommand::Interrupt),
+            "info" => match args {
+                [what] => Ok(DebuggerCommand::Info(what.to_string())),
+                _ => Err(usage()),
+            },
+            "backtrace" => Ok(DebuggerCommand::BackTrace),
+            "break" => match args {
+                [location] => Ok(DebuggerCommand::BreakPoint(location.to_string())),
+                _ => Err(usage()),
+            },
+            "catch" => match args {
+                ["syscall", syscalls @ ..] => Ok(DebuggerCommand::CatchSyscall(
+                    syscalls.iter().map(|syscall| syscall.to_string()).collect(),
+                )),
+                _ => Err(usage()),
+            },
+            "print" => match args {
+                [] => Err(usage()),
+                _ => Ok(DebuggerCommand::Print(print_format()?, args.join(" "))),
+            },
+            "return" => match args {
+                [] => Ok(DebuggerCommand::Return(None)),
+                _ => Ok(DebuggerCommand::Return(Some(args.join(" ")))),
+            },
+            "jump" => match args {
+                [location] => Ok(DebuggerCommand::Jump(location.to_string())),
+                _ => Err(usage()),
+            },
+            "call" => match args {
+                [] => Err(usage()),
+                _ => Ok(DebuggerCommand::Call(args.join(" "))),
+            },
+            "set" => match args {
+                [] => Err(usage()),
+                ["record", "full", "insn-number-max", max] => match *max {
+                    "unlimited" | "0" => Ok(DebuggerCommand::RecordLimit(None)),
+                    max => max.parse().map(|max| DebuggerCommand::RecordLimit(Some(max))),
+                }
+                .map_err(|_| usage()),
+                _ => Ok(DebuggerCommand::Set(args.join(" "))),
+            },
+            "display" => match args {
+                [] => Ok(DebuggerCommand::Display(print_format()?, None)),
+                _ => Ok(DebuggerCommand::Display(print_format()?, Some(args.join(" ")))),
+            },
+            "undisplay" => args
+                .iter()
+                .map(|num| num.parse())
+                .collect::<Result<Vec<usize>, _>>()
+                .map(DebuggerCommand::Undisplay)
+                .map_err(|_| usage()),
+            "x" => match (parse_examine_format(suffix), args) {
+                (Some((count, format, size)), [addr]) => {
+                    Ok(DebuggerCommand::Examine(count, format, size, addr.to_string()))
+                }
+                (None, _) => Err(format!("Invalid format \"{}\". {}", suffix, usage())),
+                _ => Err(usage()),
             },
-            "b" | "break" | "breakpoint" => {
-                let addr = tokens[1].to_string();
-                Some(DebuggerCommand::BreakPoint(addr))
+            "checkpoint" => match args {
+                [] => Ok(DebuggerCommand::Checkpoint),
+                _ => Err(usage()),
             },
-            // Default case:
-            _ => None,
+            "restart" => match args {
+                [number] => match number.parse::<usize>() {
+                    Ok(number) => Ok(DebuggerCommand::Restart(number)),
+                    Err(_) => Err(format!("Invalid checkpoint number \"{}\". {}", number, usage())),
+                },
+                _ => Err(usage()),
+            },
+            "record" => match args {
+                [] => Ok(DebuggerCommand::Record(true)),
+                ["stop"] => Ok(DebuggerCommand::Record(false)),
+                _ => Err(usage()),
+            },
+            "reverse-stepi" => Ok(DebuggerCommand::ReverseStepi),
+            "reverse-step" => Ok(DebuggerCommand::ReverseStep),
+            "reverse-continue" => Ok(DebuggerCommand::ReverseContinue),
+            "gcore" => match args {
+                [] => Ok(DebuggerCommand::GenerateCore(None)),
+                [path] => Ok(DebuggerCommand::GenerateCore(Some(path.to_string()))),
+                _ => Err(usage()),
+            },
+            "source" => match args {
+                [path] => Ok(DebuggerCommand::Source(path.to_string())),
+                _ => Err(usage()),
+            },
+            "commands" => match args {
+                [] => Ok(DebuggerCommand::Commands(None)),
+                [num] => num.parse().map(|num| DebuggerCommand::Commands(Some(num))).map_err(
+                    |_| format!("Invalid breakpoint number \"{}\". {}", num, usage()),
+                ),
+                _ => Err(usage()),
+            },
+            "define" => match args {
+                [name] => Ok(DebuggerCommand::Define(name.to_string())),
+                _ => Err(usage()),
+            },
+            "alias" => match args {
+                [name, "=", command @ ..] if !command.is_empty() => {
+                    Ok(DebuggerCommand::Alias(name.to_string(), command.join(" ")))
+                }
+                _ => Err(usage()),
+            },
+            "help" => match args {
+                [] => Ok(DebuggerCommand::Help(None)),
+                [command] => Ok(DebuggerCommand::Help(Some(command.to_string()))),
+                _ => Err(usage()),
+            },
+            _ => unreachable!("command {} is not parsed", info.name),
+        }
+    }
+}
+
+/// Strips a trailing `&` from the arguments of `run`/`continue`, returning whether it was there.
+fn split_background<'a>(args: &'a [&'a str]) -> (&'a [&'a str], bool) {
+    match args.last() {
+        Some(&"&") => (&args[..args.len() - 1], true),
+        _ => (args, false),
+    }
+}
+
+/// Parses the `/F` suffix of `print` and `display`, returning None if it is invalid.
+fn parse_print_format(spec: &str) -> Option<Option<char>> {
+    if !spec.starts_with('/') {
+        return Some(None);
+    }
+    match &spec[1..] {
+        format if format.len() == 1 && "xduotc".contains(format) => Some(format.chars().next()),
+        _ => None,
+    }
+}
+
+/// Parses the `/NFU` suffix of `x`, e.g. "/16xb". Defaults to one hex word, like gdb.
+fn parse_examine_format(spec: &str) -> Option<(usize, char, usize)> {
+    let spec = if spec.starts_with('/') { &spec[1..] } else { spec };
+    let digits: String = spec.chars().take_while(|c| c.is_ascii_digit()).collect();
+    let count = if digits.is_empty() { 1 } else { digits.parse().ok()? };
+    let mut format = 'x';
+    let mut size = 4;
+    for c in spec[digits.len()..].chars() {
+        match c {
+            'x' | 'd' | 'u' | 'c' => format = c,
+            'b' => size = 1,
+            'h' => size = 2,
+            'w' => size = 4,
+            'g' => size = 8,
+            _ => return None,
         }
     }
+    Some((count, format, size))
 }
@@ -1,6 +1,8 @@
 use crate::gimli_wrapper;
+use crate::symbols::SymbolTable;
 use addr2line::Context;
 use object::Object;
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::{fmt, fs};
 
@@ -12,7 +14,12 @@ pub enum Error {
 
 pub struct DwarfData {
     files: Vec<File>,
-    addr2line: Context<addr2line::gimli::EndianRcSlice<addr2line::gimli::RunTimeEndian>>,
+    /// Types by the offset of their DIE in .debug_info
+    types: HashMap<usize, Type>,
+    /// None if the DWARF couldn't be loaded, leaving only the symbol table
+    addr2line: Option<Context<addr2line::gimli::EndianRcSlice<addr2line::gimli::RunTimeEndian>>>,
+    /// Function symbols of the executable, for code without debugging information
+    symbols: SymbolTable,
 }
 
 impl fmt::Debug for DwarfData {
@@ -27,27 +34,145 @@ impl From<gimli_wrapper::Error> for Error {
     }
 }
 
+/// Where distributions install separate debug files.
+const DEBUG_DIR: &str = "/usr/lib/debug";
+
+fn map_file(path: &str) -> Result<memmap::Mmap, Error> {
+    let file = fs::File::open(path).or(Err(Error::ErrorOpeningFile))?;
+    unsafe { memmap::Mmap::map(&file).or(Err(Error::ErrorOpeningFile)) }
+}
+
+/// Finds the separate debug file of the stripped executable at `path`: by the build ID in its
+/// .note.gnu.build-id, as DEBUG_DIR/.build-id/ab/cdef...debug, or else by the name and CRC in
+/// its .gnu_debuglink, in the executable's directory, its .debug subdirectory, or the same
+/// directory under DEBUG_DIR.
+fn find_debug_file(path: &str, object: &object::File) -> Option<String> {
+    if let Some(note) = object.section_data_by_name(".note.gnu.build-id") {
+        // Name size, ID size and note type, then the name ("GNU") and the ID, 4-byte aligned
+        let word = |offset: usize| -> Option<usize> {
+            Some(u32::from_le_bytes(note.get(offset..offset + 4)?.try_into().ok()?) as usize)
+        };
+        if let (Some(name_size), Some(id_size)) = (word(0), word(4)) {
+            let start = 12 + ((name_size + 3) & !3);
+            if let Some(id) = note.get(start..start + id_size).filter(|id| id.len() > 1) {
+                let hex: String = id.iter().map(|byte| format!("{:02x}", byte)).collect();
+                let debug_path =
+                    format!("{}/.build-id/{}/{}.debug", DEBUG_DIR, &hex[..2], &hex[2..]);
+                if fs::metadata(&debug_path).map_or(false, |metadata| metadata.is_file()) {
+                    return Some(debug_path);
+                }
+            }
+        }
+    }
+    // The file name, NUL-terminated and padded to 4 bytes, then the CRC-32 of the debug file
+    let link = object.section_data_by_name(".gnu_debuglink")?;
+    let name_len = link.iter().position(|&byte| byte == 0)?;
+    let name = std::str::from_utf8(&link[..name_len]).ok()?;
+    let crc_offset = (name_len + 4) & !3;
+    let crc = u32::from_le_bytes(link.get(crc_offset..crc_offset + 4)?.try_into().ok()?);
+    let dir = fs::canonicalize(path).ok()?.parent()?.to_str()?.to_string();
+    let candidates = vec![
+        format!("{}/{}", dir, name),
+        format!("{}/.debug/{}", dir, name),
+        format!("{}{}/{}", DEBUG_DIR, dir, name),
+    ];
+    // A CRC mismatch means a debug file for another build
+    candidates
+        .into_iter()
+        .find(|candidate| fs::read(candidate).map_or(false, |data| crc32(&data) == crc))
+}
+
+/// The CRC-32 (IEEE) that `objcopy --add-gnu-debuglink` records for the debug file.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
 impl DwarfData {
+    /// Loads the debugging information of the executable at `path`. A stripped executable's is
+    /// read from its separate debug file (`objcopy --only-keep-debug`), if it can be found.
     pub fn from_file(path: &str) -> Result<DwarfData, Error> {
-        let file = fs::File::open(path).or(Err(Error::ErrorOpeningFile))?;
-        let mmap = unsafe { memmap::Mmap::map(&file).or(Err(Error::ErrorOpeningFile))? };
+        let mmap = map_file(path)?;
         let object = object::File::parse(&*mmap)
             .or_else(|e| Err(gimli_wrapper::Error::ObjectError(e.to_string())))?;
+        if object.section_data_by_name(".debug_info").is_some() {
+            return DwarfData::load(&object, path);
+        }
+        match find_debug_file(path, &object) {
+            Some(debug_path) => {
+                let debug_mmap = map_file(&debug_path)?;
+                let debug_object = object::File::parse(&*debug_mmap)
+                    .or_else(|e| Err(gimli_wrapper::Error::ObjectError(e.to_string())))?;
+                DwarfData::load(&debug_object, path)
+            }
+            None => DwarfData::load(&object, path),
+        }
+    }
+
+    /// Loads the DWARF and symbols of `object`, which is the executable at `path` or its debug
+    /// file.
+    fn load(object: &object::File, path: &str) -> Result<DwarfData, Error> {
         let endian = if object.is_little_endian() {
             gimli::RunTimeEndian::Little
         } else {
             gimli::RunTimeEndian::Big
         };
+        let (files, types) = gimli_wrapper::load_file(object, endian, path)?;
+        // addr2line fails on the DWARF 5 split units gimli_wrapper skips; lines are then looked
+        // up in our own line tables
+        let addr2line = Context::new(object).ok();
         Ok(DwarfData {
-            files: gimli_wrapper::load_file(&object, endian)?,
-            addr2line: Context::new(&object).or_else(|e| Err(gimli_wrapper::Error::from(e)))?,
+            files,
+            types,
+            addr2line,
+            symbols: SymbolTable::from_object(object, 0),
         })
     }
 
+    /// Loads only the ELF symbol table of the executable at `path`, for when its DWARF is
+    /// missing or can't be parsed: functions can still be found by name, but there are no
+    /// lines, variables or types.
+    pub fn from_symbols(path: &str) -> Result<DwarfData, Error> {
+        let mmap = map_file(path)?;
+        let object = object::File::parse(&*mmap)
+            .or_else(|e| Err(gimli_wrapper::Error::ObjectError(e.to_string())))?;
+        Ok(DwarfData {
+            files: Vec::new(),
+            types: HashMap::new(),
+            addr2line: None,
+            symbols: SymbolTable::from_object(&object, 0),
+        })
+    }
+
+    /// Loads the debugging information of `target` for the command line tools such as
+    /// `--coverage`, printing why to stderr if it can't be loaded.
+    pub fn load_for_tool(target: &str) -> Option<DwarfData> {
+        match DwarfData::from_file(target) {
+            Ok(debug_data) => Some(debug_data),
+            Err(err) => {
+                eprintln!("Could not load debugging information from {}: {:?}", target, err);
+                None
+            }
+        }
+    }
+
+    /// Returns the function symbols of the executable.
+    pub fn get_symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
     #[allow(dead_code)]
     fn get_target_file(&self, file: &str) -> Option<&File> {
         self.files.iter().find(|f| {
-            f.name == file || (!file.contains("/") && f.name.ends_with(&format!("/{}", file)))
+            f.name == file
+                || (!file.contains("/") && f.name.ends_with(&format!("/{}", file)))
+                || (file.starts_with("/") && file.ends_with(&format!("/{}", f.name)))
         })
     }
 
@@ -89,26 +214,115 @@ impl DwarfData {
 
     #[allow(dead_code)]
     pub fn get_line_from_addr(&self, curr_addr: usize) -> Option<Line> {
-        let location = self
-            .addr2line
-            .find_location(curr_addr.try_into().unwrap())
-            .ok()??;
-        Some(Line {
-            file: location.file?.to_string(),
-            number: location.line?.try_into().unwrap(),
-            address: curr_addr,
-        })
+        let location = self.addr2line.as_ref().and_then(|addr2line| {
+            addr2line.find_location(curr_addr.try_into().unwrap()).ok()?
+        });
+        if let Some(location) = location {
+            return Some(Line {
+                file: location.file?.to_string(),
+                number: location.line?.try_into().unwrap(),
+                address: curr_addr,
+            });
+        }
+        // addr2line only finds lines in functions it has read, so not in split DWARF units;
+        // their lines are loaded into `files` though
+        let func = self.get_function_at(curr_addr)?;
+        let line = self
+            .files
+            .iter()
+            .flat_map(|file| file.lines.iter())
+            .filter(|line| func.address <= line.address && line.address <= curr_addr)
+            .max_by_key(|line| line.address)?;
+        Some(Line { address: curr_addr, ..line.clone() })
     }
 
     #[allow(dead_code)]
     pub fn get_function_from_addr(&self, curr_addr: usize) -> Option<String> {
-        let frame = self
-            .addr2line
-            .find_frames(curr_addr.try_into().unwrap())
-            .ok()?
-            .next()
-            .ok()??;
-        Some(frame.function?.raw_name().ok()?.to_string())
+        let name = self.addr2line.as_ref().and_then(|addr2line| {
+            let frame =
+                addr2line.find_frames(curr_addr.try_into().unwrap()).ok()?.next().ok()??;
+            Some(frame.function?.raw_name().ok()?.to_string())
+        });
+        // addr2line doesn't read the .dwo files of split DWARF units
+        name.or_else(|| Some(self.get_function_at(curr_addr)?.name.clone()))
+    }
+
+    /// Returns true if the address is the first instruction generated for some source line.
+    pub fn is_line_start(&self, curr_addr: usize) -> bool {
+        self.files
+            .iter()
+            .any(|file| file.lines.iter().any(|line| line.address == curr_addr))
+    }
+
+    /// Returns the function whose code contains the given address.
+    pub fn get_function_at(&self, curr_addr: usize) -> Option<&Function> {
+        self.files.iter().flat_map(|file| file.functions.iter()).find(|func| {
+            func.address <= curr_addr && curr_addr < func.address + func.text_length
+        })
+    }
+
+    /// Looks up a type by the offset of its DIE, as referenced by `Variable::type_offset`,
+    /// `TypeKind` and `Member`.
+    pub fn get_type(&self, offset: usize) -> Option<&Type> {
+        self.types.get(&offset)
+    }
+
+    /// Looks up a type by its C name, e.g. "unsigned int", "struct node" or a typedef name.
+    pub fn find_type(&self, name: &str) -> Option<usize> {
+        // Prefer a definition over a forward declaration without members
+        let mut found = None;
+        for (offset, ty) in &self.types {
+            if ty.name == name {
+                match ty.kind {
+                    TypeKind::Struct(ref members) if members.is_empty() => found = Some(*offset),
+                    _ => return Some(*offset),
+                }
+            }
+        }
+        found
+    }
+
+    /// Looks up an enumerator by name, returning the offset of its enum type and its value.
+    pub fn find_enumerator(&self, name: &str) -> Option<(usize, i64)> {
+        self.types.iter().find_map(|(offset, dtype)| match &dtype.kind {
+            TypeKind::Enum(_, values) => values
+                .iter()
+                .find(|(enumerator, _)| enumerator == name)
+                .map(|(_, value)| (*offset, *value)),
+            _ => None,
+        })
+    }
+
+    /// Returns the names of the functions of all compilation units.
+    pub fn get_function_names(&self) -> Vec<String> {
+        let functions = self.files.iter().flat_map(|file| file.functions.iter());
+        functions.map(|func| func.name.clone()).collect()
+    }
+
+    /// Returns the compilation units, with their functions, global variables and lines.
+    pub fn get_files(&self) -> &[File] {
+        &self.files
+    }
+
+    /// Returns the functions of all compilation units.
+    pub fn get_functions(&self) -> Vec<&Function> {
+        self.files.iter().flat_map(|file| file.functions.iter()).collect()
+    }
+
+    /// Returns the global variables of all compilation units.
+    pub fn get_global_variables(&self) -> Vec<&Variable> {
+        self.files.iter().flat_map(|file| file.global_variables.iter()).collect()
+    }
+
+    /// Looks up a variable visible at the given address: a local variable or parameter of the
+    /// enclosing function first, then a global variable.
+    pub fn get_variable(&self, curr_addr: usize, name: &str) -> Option<&Variable> {
+        if let Some(func) = self.get_function_at(curr_addr) {
+            if let Some(var) = func.variables.iter().find(|var| var.name == name) {
+                return Some(var);
+            }
+        }
+        self.get_global_variables().into_iter().find(|var| var.name == name)
     }
 
     #[allow(dead_code)]
@@ -150,8 +364,10 @@ impl DwarfData {
 
 #[derive(Debug, Clone, Default)]
 pub struct Type {
+    /// C name of the type, e.g. "int", "struct node *" or "char [16]"
     pub name: String,
     pub size: usize,
+    pub kind: TypeKind,
 }
 
 impl Type {
@@ -159,10 +375,47 @@ impl Type {
         Type {
             name: name,
             size: size,
+            kind: TypeKind::Base,
         }
     }
 }
 
+/// What a type is made of. Other types are referred to by the offset of their DIE, so that
+/// recursive types like linked list nodes can be represented.
+#[derive(Debug, Clone)]
+pub enum TypeKind {
+    /// int, char, double...
+    Base,
+    /// Type pointed to, or None for void *
+    Pointer(Option<usize>),
+    /// struct or union
+    Struct(Vec<Member>),
+    /// Element type and the number of elements in each dimension (0 if unknown)
+    Array(usize, Vec<usize>),
+    /// Whether the underlying integer type is signed, and the names and values of the
+    /// enumerators
+    Enum(bool, Vec<(String, i64)>),
+    /// typedef, const or volatile version of another type, or of void if None
+    Alias(Option<usize>),
+    /// Function type, only used through function pointers
+    Function,
+}
+
+impl Default for TypeKind {
+    fn default() -> Self {
+        TypeKind::Base
+    }
+}
+
+/// A member of a struct or union.
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub name: String,
+    pub type_offset: usize,
+    /// Offset in bytes from the start of the struct
+    pub offset: usize,
+}
+
 #[derive(Clone)]
 pub enum Location {
     Address(usize),
@@ -189,6 +442,8 @@ impl fmt::Debug for Location {
 pub struct Variable {
     pub name: String,
     pub entity_type: Type,
+    /// Offset of the DIE of entity_type, to look up the types it is made of
+    pub type_offset: usize,
     pub location: Location,
     pub line_number: usize, // Line number in source file
 }
@@ -200,6 +455,10 @@ pub struct Function {
     pub text_length: usize,
     pub line_number: usize, // Line number in source file
     pub variables: Vec<Variable>,
+    /// Offset of the DIE of the return type, None for void
+    pub return_type: Option<usize>,
+    /// Offsets of the DIEs of the parameter types, in order
+    pub parameters: Vec<usize>,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -0,0 +1,1382 @@
+//! Parser and evaluator for the C expressions accepted by `print`, such as `*p`, `arr[i + 1]`,
+//! `s->next->value`, `x * 2 + y`, `(long)ptr` and `$rip`. Values are read from the program's
+//! memory and interpreted using the types loaded by `dwarf_data`.
+//!
+//! Expressions can also refer to the values printed earlier (`$`, `$$`, `$$2`, `$3`) and to
+//! convenience variables (`$count = $count + 1`), which are kept in a `Session`.
+
+use crate::dwarf_data::{DwarfData, Location, TypeKind, Variable};
+use crate::target::{self, Target};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// Values bigger than this (e.g. huge arrays) are only partially read.
+const MAX_VALUE_SIZE: usize = 1 << 16;
+/// Number of array elements or string characters printed before eliding the rest.
+const PRINT_LIMIT: usize = 200;
+
+/// The type of a value, as far as evaluating C expressions is concerned.
+#[derive(Debug, Clone)]
+pub enum CType {
+    Void,
+    /// Integer of the given size in bytes; char, _Bool and enums are integers too
+    Int { name: String, size: usize, signed: bool },
+    Float { name: String, size: usize },
+    Pointer(Box<CType>),
+    /// Offset of a struct or union type. Its members are looked up when needed, since a struct
+    /// may contain pointers to itself.
+    Struct(usize),
+    /// Element type and number of elements (0 if unknown)
+    Array(Box<CType>, usize),
+    /// Offset of an enum type, and whether its values are signed
+    Enum(usize, bool),
+    Function,
+}
+
+impl CType {
+    /// Converts a type loaded from DWARF, given by the offset of its DIE (None for void).
+    pub fn from_dwarf(debug_data: &DwarfData, offset: Option<usize>) -> CType {
+        let dtype = match offset.and_then(|offset| debug_data.get_type(offset)) {
+            Some(dtype) => dtype,
+            None => return CType::Void,
+        };
+        match &dtype.kind {
+            TypeKind::Base => base_type(&dtype.name, dtype.size),
+            TypeKind::Pointer(target) => {
+                CType::Pointer(Box::new(CType::from_dwarf(debug_data, *target)))
+            }
+            TypeKind::Struct(_) => CType::Struct(offset.unwrap()),
+            TypeKind::Array(elem, dims) => {
+                // int grid[2][3] is an array of 2 arrays of 3 ints
+                let elem = CType::from_dwarf(debug_data, Some(*elem));
+                dims.iter().rev().fold(elem, |ctype, dim| CType::Array(Box::new(ctype), *dim))
+            }
+            TypeKind::Enum(signed, _) => CType::Enum(offset.unwrap(), *signed),
+            TypeKind::Alias(target) => CType::from_dwarf(debug_data, *target),
+            TypeKind::Function => CType::Function,
+        }
+    }
+
+    pub fn size(&self, debug_data: &DwarfData) -> usize {
+        match self {
+            // Like gdb, arithmetic on void * and function pointers goes byte by byte
+            CType::Void | CType::Function => 1,
+            CType::Int { size, .. } | CType::Float { size, .. } => *size,
+            CType::Pointer(_) => 8,
+            CType::Struct(offset) | CType::Enum(offset, _) => {
+                debug_data.get_type(*offset).map_or(0, |dtype| dtype.size)
+            }
+            CType::Array(elem, count) => elem.size(debug_data) * count,
+        }
+    }
+
+    /// Returns the C name of the type, e.g. "struct node *" or "int [2][3]".
+    pub fn name(&self, debug_data: &DwarfData) -> String {
+        match self {
+            CType::Void => "void".to_string(),
+            CType::Function => "<function>".to_string(),
+            CType::Int { name, .. } | CType::Float { name, .. } => name.clone(),
+            CType::Pointer(target) if matches!(**target, CType::Function) => {
+                "void (*)()".to_string()
+            }
+            CType::Pointer(target) => {
+                let target_name = target.name(debug_data);
+                if target_name.ends_with('*') {
+                    format!("{}*", target_name)
+                } else {
+                    format!("{} *", target_name)
+                }
+            }
+            CType::Struct(offset) | CType::Enum(offset, _) => debug_data
+                .get_type(*offset)
+                .map_or_else(|| "<unknown>".to_string(), |dtype| dtype.name.clone()),
+            CType::Array(..) => {
+                let mut dims = String::new();
+                let mut elem = self;
+                while let CType::Array(inner, count) = elem {
+                    dims += &format!("[{}]", count);
+                    elem = inner;
+                }
+                format!("{} {}", elem.name(debug_data), dims)
+            }
+        }
+    }
+
+    fn is_char(&self) -> bool {
+        match self {
+            CType::Int { name, size: 1, .. } => name.contains("char"),
+            _ => false,
+        }
+    }
+}
+
+/// Classifies a DWARF base type by its name, e.g. "long unsigned int".
+fn base_type(name: &str, size: usize) -> CType {
+    if name.contains("float") || name.contains("double") {
+        CType::Float { name: name.to_string(), size }
+    } else {
+        let signed = !name.contains("unsigned") && name != "_Bool";
+        CType::Int { name: name.to_string(), size, signed }
+    }
+}
+
+fn int_type(size: usize, signed: bool) -> CType {
+    let name = match (size, signed) {
+        (1, true) => "char",
+        (1, false) => "unsigned char",
+        (2, true) => "short",
+        (2, false) => "unsigned short",
+        (4, true) => "int",
+        (4, false) => "unsigned int",
+        (_, true) => "long",
+        (_, false) => "unsigned long",
+    };
+    CType::Int { name: name.to_string(), size, signed }
+}
+
+fn double_type() -> CType {
+    CType::Float { name: "double".to_string(), size: 8 }
+}
+
+#[derive(Debug, Clone)]
+pub struct Value {
+    pub ctype: CType,
+    /// Where the value is in the program's memory, if it is there at all
+    pub address: Option<u64>,
+    /// Contents of the value in the program's byte order
+    pub bytes: Vec<u8>,
+}
+
+impl Value {
+    /// Creates a value that is not in memory from the low bytes of `bits`.
+    fn scalar(ctype: CType, bits: u64) -> Value {
+        let size = match ctype {
+            CType::Int { size, .. } | CType::Float { size, .. } => size.min(8),
+            _ => 8,
+        };
+        Value { ctype, address: None, bytes: bits.to_le_bytes()[..size].to_vec() }
+    }
+
+    /// Returns the contents zero-extended to 64 bits.
+    pub fn bits(&self) -> u64 {
+        let mut buf = [0u8; 8];
+        let size = self.bytes.len().min(8);
+        buf[..size].copy_from_slice(&self.bytes[..size]);
+        u64::from_le_bytes(buf)
+    }
+
+    fn is_signed(&self) -> bool {
+        match self.ctype {
+            CType::Int { signed, .. } | CType::Enum(_, signed) => signed,
+            _ => false,
+        }
+    }
+
+    /// Returns an integer value, extended according to its signedness.
+    fn as_i128(&self) -> i128 {
+        if self.is_signed() && !self.bytes.is_empty() {
+            target::sign_extend(self.bits(), self.bytes.len().min(8)) as i128
+        } else {
+            self.bits() as i128
+        }
+    }
+
+    fn as_f64(&self) -> f64 {
+        match self.ctype {
+            CType::Float { size: 4, .. } => f32::from_bits(self.bits() as u32) as f64,
+            CType::Float { .. } => f64::from_bits(self.bits()),
+            _ => self.as_i128() as f64,
+        }
+    }
+
+    fn is_true(&self) -> bool {
+        match self.ctype {
+            CType::Float { .. } => self.as_f64() != 0.0,
+            _ => self.bits() != 0,
+        }
+    }
+}
+
+/// Values kept between commands: the history of values printed by `print`, numbered from 1, and
+/// convenience variables set by the user.
+#[derive(Default)]
+pub struct Session {
+    history: Vec<Value>,
+    convenience: HashMap<String, Value>,
+}
+
+impl Session {
+    /// Appends a value to the history, returning its number.
+    pub fn record(&mut self, value: Value) -> usize {
+        self.history.push(value);
+        self.history.len()
+    }
+
+    /// Looks up `$`, `$$`, `$$N` and `$N`, given without the first `$`. Returns None if `name`
+    /// does not refer to the history.
+    fn history_value(&self, name: &str) -> Option<Result<Value, String>> {
+        let is_number = |text: &str| text.chars().all(|c| c.is_ascii_digit());
+        let index = if name.is_empty() {
+            self.history.len().checked_sub(1)
+        } else if name.starts_with('$') && is_number(&name[1..]) {
+            let back = if name.len() == 1 { 1 } else { name[1..].parse().ok()? };
+            self.history.len().checked_sub(back + 1)
+        } else if is_number(name) {
+            let number: usize = name.parse().ok()?;
+            if number == 0 || number > self.history.len() {
+                return Some(Err(format!("History has not yet reached ${}.", number)));
+            }
+            Some(number - 1)
+        } else {
+            return None;
+        };
+        Some(match index {
+            Some(index) => Ok(self.history[index].clone()),
+            None if self.history.is_empty() => Ok(Value::scalar(CType::Void, 0)),
+            None => Err("History has not yet reached that far back.".to_string()),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Int(u64),
+    Float(f64),
+    Char(u8),
+    Ident(String),
+    /// `$` followed by a register, history or convenience variable name, e.g. "rip", "", "$2"
+    Dollar(String),
+    Punct(&'static str),
+}
+
+/// Operators, longest first so that e.g. "->" isn't read as "-".
+const PUNCTUATION: &[&str] = &[
+    "->", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+", "-", "*", "/", "%", "(", ")", "[",
+    "]", ".", "&", "|", "^", "!", "~", "<", ">", "=", ",",
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next_is_digit = chars.get(i + 1).map_or(false, |c| c.is_ascii_digit());
+        let starts_number = c.is_ascii_digit() || (c == '.' && next_is_digit);
+        if c.is_whitespace() {
+            i += 1;
+        } else if starts_number {
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_ascii_alphanumeric()
+                    || (chars[i] == '.' && chars.get(i + 1).map_or(true, |c| c.is_ascii_digit())))
+            {
+                i += 1;
+            }
+            tokens.push(parse_number(&chars[start..i].iter().collect::<String>())?);
+        } else if c.is_alphabetic() || c == '_' || c == '$' {
+            let start = i;
+            i += 1;
+            // $$ and $$N refer to the value history
+            if c == '$' && chars.get(i) == Some(&'$') {
+                i += 1;
+            }
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            if word.starts_with('$') {
+                tokens.push(Token::Dollar(word[1..].to_string()));
+            } else {
+                tokens.push(Token::Ident(word));
+            }
+        } else if c == '\'' {
+            let (value, len) = match (chars.get(i + 1), chars.get(i + 2)) {
+                (Some('\\'), Some(escaped)) => {
+                    let value = match escaped {
+                        'n' => b'\n',
+                        't' => b'\t',
+                        'r' => b'\r',
+                        '0' => 0,
+                        other => *other as u8,
+                    };
+                    (value, 4)
+                }
+                (Some(c), _) => (*c as u8, 3),
+                _ => return Err("Unmatched single quote.".to_string()),
+            };
+            if chars.get(i + len - 1) != Some(&'\'') {
+                return Err("Unmatched single quote.".to_string());
+            }
+            tokens.push(Token::Char(value));
+            i += len;
+        } else {
+            let rest: String = chars[i..].iter().collect();
+            match PUNCTUATION.iter().find(|punct| rest.starts_with(*punct)) {
+                Some(punct) => {
+                    tokens.push(Token::Punct(punct));
+                    i += punct.len();
+                }
+                None => return Err(format!("Invalid character '{}' in expression.", c)),
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_number(text: &str) -> Result<Token, String> {
+    let invalid = || format!("Invalid number \"{}\".", text);
+    let lower = text.to_lowercase();
+    if lower.starts_with("0x") {
+        let digits = lower[2..].trim_end_matches(|c| c == 'u' || c == 'l');
+        return u64::from_str_radix(digits, 16).map(Token::Int).map_err(|_| invalid());
+    }
+    if lower.contains('.') || lower.contains('e') {
+        return lower.trim_end_matches('f').parse().map(Token::Float).map_err(|_| invalid());
+    }
+    let digits = lower.trim_end_matches(|c| c == 'u' || c == 'l');
+    // A leading 0 makes it octal, as in C
+    if digits.len() > 1 && digits.starts_with('0') {
+        return u64::from_str_radix(&digits[1..], 8).map(Token::Int).map_err(|_| invalid());
+    }
+    digits.parse().map(Token::Int).map_err(|_| invalid())
+}
+
+#[derive(Debug)]
+enum Expr {
+    Int(u64),
+    Float(f64),
+    Char(u8),
+    Name(String),
+    /// A `$` name: register, history value or convenience variable
+    Dollar(String),
+    Assign(Box<Expr>, Box<Expr>),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Cast(CType, Box<Expr>),
+    Index(Box<Expr>, Box<Expr>),
+    Member(Box<Expr>, String),
+    Call(Box<Expr>, Vec<Expr>),
+    SizeofType(CType),
+    SizeofExpr(Box<Expr>),
+}
+
+fn binary_precedence(op: &str) -> Option<u8> {
+    Some(match op {
+        "||" => 1,
+        "&&" => 2,
+        "|" => 3,
+        "^" => 4,
+        "&" => 5,
+        "==" | "!=" => 6,
+        "<" | ">" | "<=" | ">=" => 7,
+        "<<" | ">>" => 8,
+        "+" | "-" => 9,
+        "*" | "/" | "%" => 10,
+        _ => return None,
+    })
+}
+
+/// Words that can start a type name in a cast or sizeof.
+const TYPE_WORDS: &[&str] = &[
+    "void", "char", "short", "int", "long", "float", "double", "signed", "unsigned", "_Bool",
+    "struct", "union", "enum", "const", "volatile",
+];
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    debug_data: &'a DwarfData,
+    rip: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_punct(&self) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Punct(punct)) => Some(punct),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), String> {
+        if self.peek_punct() == Some(punct) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.syntax_error())
+        }
+    }
+
+    fn syntax_error(&self) -> String {
+        match self.peek() {
+            Some(token) => format!("A syntax error in expression, near `{}'.", describe(token)),
+            None => "A syntax error in expression, near `'.".to_string(),
+        }
+    }
+
+    /// Parses an assignment, or any other expression.
+    fn parse_expr(&mut self) -> Result<Expr, String> {
+        let lhs = self.parse_binary(1)?;
+        if self.peek_punct() == Some("=") {
+            self.pos += 1;
+            return Ok(Expr::Assign(Box::new(lhs), Box::new(self.parse_expr()?)));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_binary(&mut self, min_precedence: u8) -> Result<Expr, String> {
+        let mut lhs = self.parse_unary()?;
+        while let Some(op) = self.peek_punct() {
+            let precedence = match binary_precedence(op) {
+                Some(precedence) if precedence >= min_precedence => precedence,
+                _ => break,
+            };
+            self.pos += 1;
+            let rhs = self.parse_binary(precedence + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        if let Some(op @ "-") | Some(op @ "+") | Some(op @ "!") | Some(op @ "~") | Some(op @ "*")
+        | Some(op @ "&") = self.peek_punct()
+        {
+            self.pos += 1;
+            return Ok(Expr::Unary(op, Box::new(self.parse_unary()?)));
+        }
+        if self.peek() == Some(&Token::Ident("sizeof".to_string())) {
+            self.pos += 1;
+            if self.peek_punct() == Some("(") && self.starts_type(self.pos + 1) {
+                self.pos += 1;
+                let ctype = self.parse_type()?;
+                self.expect(")")?;
+                return Ok(Expr::SizeofType(ctype));
+            }
+            return Ok(Expr::SizeofExpr(Box::new(self.parse_unary()?)));
+        }
+        if self.peek_punct() == Some("(") && self.starts_type(self.pos + 1) {
+            self.pos += 1;
+            let ctype = self.parse_type()?;
+            self.expect(")")?;
+            return Ok(Expr::Cast(ctype, Box::new(self.parse_unary()?)));
+        }
+        self.parse_postfix()
+    }
+
+    fn parse_postfix(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_primary()?;
+        loop {
+            match self.peek_punct() {
+                Some("[") => {
+                    self.pos += 1;
+                    let index = self.parse_expr()?;
+                    self.expect("]")?;
+                    expr = Expr::Index(Box::new(expr), Box::new(index));
+                }
+                Some(op @ ".") | Some(op @ "->") => {
+                    self.pos += 1;
+                    let member = match self.peek() {
+                        Some(Token::Ident(member)) => member.clone(),
+                        _ => return Err(self.syntax_error()),
+                    };
+                    self.pos += 1;
+                    if op == "->" {
+                        expr = Expr::Unary("*", Box::new(expr));
+                    }
+                    expr = Expr::Member(Box::new(expr), member);
+                }
+                Some("(") => {
+                    self.pos += 1;
+                    let mut args = Vec::new();
+                    if self.peek_punct() != Some(")") {
+                        args.push(self.parse_expr()?);
+                        while self.peek_punct() == Some(",") {
+                            self.pos += 1;
+                            args.push(self.parse_expr()?);
+                        }
+                    }
+                    self.expect(")")?;
+                    expr = Expr::Call(Box::new(expr), args);
+                }
+                _ => return Ok(expr),
+            }
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, String> {
+        let expr = match self.peek() {
+            Some(Token::Int(value)) => Expr::Int(*value),
+            Some(Token::Float(value)) => Expr::Float(*value),
+            Some(Token::Char(value)) => Expr::Char(*value),
+            Some(Token::Ident(name)) => Expr::Name(name.clone()),
+            Some(Token::Dollar(name)) => Expr::Dollar(name.clone()),
+            Some(Token::Punct("(")) => {
+                self.pos += 1;
+                let expr = self.parse_expr()?;
+                self.expect(")")?;
+                return Ok(expr);
+            }
+            _ => return Err(self.syntax_error()),
+        };
+        self.pos += 1;
+        Ok(expr)
+    }
+
+    /// Whether the token at `pos` starts a type name rather than an expression.
+    fn starts_type(&self, pos: usize) -> bool {
+        match self.tokens.get(pos) {
+            Some(Token::Ident(word)) => {
+                TYPE_WORDS.contains(&word.as_str())
+                    || (self.debug_data.find_type(word).is_some()
+                        && self.debug_data.get_variable(self.rip, word).is_none())
+            }
+            _ => false,
+        }
+    }
+
+    fn parse_type(&mut self) -> Result<CType, String> {
+        let mut words: Vec<String> = Vec::new();
+        while let Some(Token::Ident(word)) = self.peek() {
+            // A typedef name can only be the whole type
+            if !TYPE_WORDS.contains(&word.as_str()) && !words.is_empty() {
+                let tagged = ["struct", "union", "enum"].contains(&words.last().unwrap().as_str());
+                if !tagged {
+                    break;
+                }
+            }
+            words.push(word.clone());
+            self.pos += 1;
+        }
+        let mut ctype = self.resolve_type(&words)?;
+        while self.peek_punct() == Some("*") {
+            self.pos += 1;
+            ctype = CType::Pointer(Box::new(ctype));
+        }
+        Ok(ctype)
+    }
+
+    fn resolve_type(&self, words: &[String]) -> Result<CType, String> {
+        let words: Vec<&str> = words
+            .iter()
+            .map(|word| word.as_str())
+            .filter(|word| *word != "const" && *word != "volatile")
+            .collect();
+        let count = |name: &str| words.iter().filter(|word| **word == name).count();
+        match words.as_slice() {
+            [] => return Err(self.syntax_error()),
+            [tag @ "struct", name] | [tag @ "union", name] | [tag @ "enum", name] => {
+                let name = format!("{} {}", tag, name);
+                return match self.debug_data.find_type(&name) {
+                    Some(offset) => Ok(CType::from_dwarf(self.debug_data, Some(offset))),
+                    None => Err(format!("No {} type named {}.", tag, &name[tag.len() + 1..])),
+                };
+            }
+            ["void"] => return Ok(CType::Void),
+            ["float"] => return Ok(CType::Float { name: "float".to_string(), size: 4 }),
+            ["double"] => return Ok(double_type()),
+            ["_Bool"] => {
+                return Ok(CType::Int { name: "_Bool".to_string(), size: 1, signed: false })
+            }
+            [name] if !TYPE_WORDS.contains(name) => {
+                return match self.debug_data.find_type(name) {
+                    Some(offset) => Ok(CType::from_dwarf(self.debug_data, Some(offset))),
+                    None => Err(format!("No symbol \"{}\" in current context.", name)),
+                };
+            }
+            _ => {}
+        }
+        let known = ["char", "short", "int", "long", "signed", "unsigned"];
+        if words.iter().any(|word| !known.contains(word)) || count("long") > 2 {
+            return Err(format!("Unsupported type \"{}\".", words.join(" ")));
+        }
+        let signed = count("unsigned") == 0;
+        if count("char") > 0 {
+            let name = match (count("signed"), signed) {
+                (0, true) => "char",
+                (_, true) => "signed char",
+                _ => "unsigned char",
+            };
+            return Ok(CType::Int { name: name.to_string(), size: 1, signed });
+        }
+        let size = if count("short") > 0 {
+            2
+        } else if count("long") > 0 {
+            8
+        } else {
+            4
+        };
+        Ok(int_type(size, signed))
+    }
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Int(value) => value.to_string(),
+        Token::Float(value) => value.to_string(),
+        Token::Char(value) => format!("'{}'", escape(*value, '\'')),
+        Token::Ident(name) => name.clone(),
+        Token::Dollar(name) => format!("${}", name),
+        Token::Punct(punct) => punct.to_string(),
+    }
+}
+
+/// Escapes a byte for a C character or string literal quoted with `quote`.
+fn escape(byte: u8, quote: char) -> String {
+    match byte {
+        b'\n' => "\\n".to_string(),
+        b'\t' => "\\t".to_string(),
+        b'\\' => "\\\\".to_string(),
+        _ if byte as char == quote => format!("\\{}", quote),
+        0x20..=0x7e => (byte as char).to_string(),
+        _ => format!("\\{:03o}", byte),
+    }
+}
+
+/// Evaluates expressions in the context of a stack frame.
+pub struct Evaluator<'a, T: Target + ?Sized> {
+    target: &'a T,
+    debug_data: &'a DwarfData,
+    /// Instruction and frame pointer of the frame whose local variables are visible
+    rip: usize,
+    rbp: usize,
+    /// gdb format letter (`print/x`) used to format integers and pointers
+    format: Option<char>,
+    /// Value history and convenience variables, if the caller keeps them
+    session: Option<&'a RefCell<Session>>,
+}
+
+impl<'a, T: Target + ?Sized> Evaluator<'a, T> {
+    pub fn new(target: &'a T, debug_data: &'a DwarfData, rip: usize, rbp: usize) -> Self {
+        Evaluator { target, debug_data, rip, rbp, format: None, session: None }
+    }
+
+    pub fn with_format(self, format: Option<char>) -> Self {
+        Evaluator { format, ..self }
+    }
+
+    pub fn with_session(self, session: &'a RefCell<Session>) -> Self {
+        Evaluator { session: Some(session), ..self }
+    }
+
+    pub fn evaluate(&self, text: &str) -> Result<Value, String> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser { tokens, pos: 0, debug_data: self.debug_data, rip: self.rip };
+        let expr = parser.parse_expr()?;
+        if parser.pos < parser.tokens.len() {
+            return Err(parser.syntax_error());
+        }
+        self.eval(&expr)
+    }
+
+    /// Reads the value of a variable of the frame.
+    pub fn variable(&self, var: &Variable) -> Result<Value, String> {
+        let address = match var.location {
+            Location::Address(addr) => addr as u64,
+            // DW_AT_frame_base is DW_OP_call_frame_cfa, which is rbp + 16 once the prologue has
+            // pushed rbp
+            Location::FramePointerOffset(offset) => (self.rbp as i64 + 16 + offset as i64) as u64,
+        };
+        self.read(address, CType::from_dwarf(self.debug_data, Some(var.type_offset)))
+    }
+
+    fn read(&self, address: u64, ctype: CType) -> Result<Value, String> {
+        let size = match ctype {
+            CType::Function => 0,
+            _ => ctype.size(self.debug_data).min(MAX_VALUE_SIZE),
+        };
+        let bytes = if size > 0 {
+            self.target
+                .read_memory(address, size)
+                .map_err(|_| format!("Cannot access memory at address {:#x}", address))?
+        } else {
+            Vec::new()
+        };
+        Ok(Value { ctype, address: Some(address), bytes })
+    }
+
+    fn eval(&self, expr: &Expr) -> Result<Value, String> {
+        match expr {
+            Expr::Int(value) => {
+                let ctype = if *value <= i32::MAX as u64 {
+                    int_type(4, true)
+                } else {
+                    int_type(8, *value <= i64::MAX as u64)
+                };
+                Ok(Value::scalar(ctype, *value))
+            }
+            Expr::Float(value) => Ok(Value::scalar(double_type(), value.to_bits())),
+            Expr::Char(value) => Ok(Value::scalar(int_type(1, true), *value as u64)),
+            Expr::Name(name) => self.name(name),
+            Expr::Dollar(name) => self.dollar(name),
+            Expr::Assign(lhs, rhs) => {
+                let name = match &**lhs {
+                    Expr::Dollar(name) if self.is_convenience(name) => name,
+                    _ => {
+                        return Err(
+                            "Left operand of assignment is not a modifiable lvalue.".to_string()
+                        )
+                    }
+                };
+                let value = self.eval(rhs)?;
+                let session = self.session.ok_or("Convenience variables are not available.")?;
+                session.borrow_mut().convenience.insert(name.clone(), value.clone());
+                Ok(value)
+            }
+            Expr::Unary(op, operand) => self.unary(op, self.eval(operand)?),
+            Expr::Binary(op @ "&&", lhs, rhs) | Expr::Binary(op @ "||", lhs, rhs) => {
+                // The right-hand side is only evaluated when needed, so that e.g.
+                // "p != 0 && p->value" works for null pointers
+                let lhs = self.eval(lhs)?.is_true();
+                let result = if *op == "&&" {
+                    lhs && self.eval(rhs)?.is_true()
+                } else {
+                    lhs || self.eval(rhs)?.is_true()
+                };
+                Ok(Value::scalar(int_type(4, true), result as u64))
+            }
+            Expr::Binary(op, lhs, rhs) => self.binary(op, self.eval(lhs)?, self.eval(rhs)?),
+            Expr::Cast(ctype, operand) => self.cast(ctype, self.eval(operand)?),
+            Expr::Index(base, index) => {
+                let element = self.binary("+", self.eval(base)?, self.eval(index)?)?;
+                self.deref(element)
+            }
+            Expr::Member(base, member) => self.member(self.eval(base)?, member),
+            Expr::Call(function, args) => self.call(self.eval(function)?, args),
+            Expr::SizeofType(ctype) => {
+                Ok(Value::scalar(int_type(8, false), ctype.size(self.debug_data) as u64))
+            }
+            Expr::SizeofExpr(operand) => {
+                let size = self.eval(operand)?.ctype.size(self.debug_data);
+                Ok(Value::scalar(int_type(8, false), size as u64))
+            }
+        }
+    }
+
+    fn name(&self, name: &str) -> Result<Value, String> {
+        if let Some(var) = self.debug_data.get_variable(self.rip, name) {
+            return self.variable(var);
+        }
+        if let Some((offset, value)) = self.debug_data.find_enumerator(name) {
+            let ctype = CType::from_dwarf(self.debug_data, Some(offset));
+            return Ok(Value::scalar(ctype, value as u64));
+        }
+        // Functions that are only declared have no address
+        let function = self.debug_data.get_addr_for_function(None, name).filter(|addr| *addr != 0);
+        if let Some(addr) = function {
+            return Ok(Value { ctype: CType::Function, address: Some(addr as u64), bytes: vec![] });
+        }
+        Err(format!("No symbol \"{}\" in current context.", name))
+    }
+
+    /// Whether `$name` is a convenience variable rather than a register or history value.
+    fn is_convenience(&self, name: &str) -> bool {
+        let first = name.chars().next();
+        first.map_or(false, |c| c.is_alphabetic() || c == '_') && !target::is_register(name)
+    }
+
+    fn dollar(&self, name: &str) -> Result<Value, String> {
+        if !self.is_convenience(name) {
+            let history = self.session.and_then(|session| session.borrow().history_value(name));
+            return match history {
+                Some(value) => value,
+                None if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) => {
+                    Err("History is not available.".to_string())
+                }
+                None => self.register(name),
+            };
+        }
+        let session = match self.session {
+            Some(session) => session.borrow(),
+            None => return Err("Convenience variables are not available.".to_string()),
+        };
+        // Like gdb, variables that were never set are void
+        Ok(session.convenience.get(name).cloned().unwrap_or_else(|| Value::scalar(CType::Void, 0)))
+    }
+
+    fn register(&self, name: &str) -> Result<Value, String> {
+        let regs = self.target.regs().map_err(|err| match err {
+            err if err == target::no_process() => "No registers.".to_string(),
+            err => err.to_string(),
+        })?;
+        let value = target::register_value(&regs, name)
+            .ok_or_else(|| format!("Invalid register \"${}\".", name))?;
+        let ctype = match name {
+            "rip" | "pc" => CType::Pointer(Box::new(CType::Function)),
+            "rsp" | "sp" | "rbp" | "fp" => CType::Pointer(Box::new(CType::Void)),
+            _ => int_type(8, true),
+        };
+        Ok(Value::scalar(ctype, value))
+    }
+
+    /// Converts arrays and functions to pointers to their start, as C does when using them in
+    /// expressions.
+    fn decay(&self, value: Value) -> Value {
+        match (&value.ctype, value.address) {
+            (CType::Array(elem, _), Some(address)) => {
+                Value::scalar(CType::Pointer(elem.clone()), address)
+            }
+            (CType::Function, Some(address)) => {
+                Value::scalar(CType::Pointer(Box::new(CType::Function)), address)
+            }
+            _ => value,
+        }
+    }
+
+    fn deref(&self, value: Value) -> Result<Value, String> {
+        let value = self.decay(value);
+        let address = value.bits();
+        match value.ctype {
+            CType::Pointer(target) => match *target {
+                CType::Void => Err("Attempt to take contents of a non-pointer value.".to_string()),
+                target => self.read(address, target),
+            },
+            _ => Err("Attempt to take contents of a non-pointer value.".to_string()),
+        }
+    }
+
+    fn unary(&self, op: &str, value: Value) -> Result<Value, String> {
+        match op {
+            "*" => self.deref(value),
+            "&" => match value.address {
+                Some(address) => Ok(Value::scalar(CType::Pointer(Box::new(value.ctype)), address)),
+                None => Err("Attempt to take address of value not located in memory.".to_string()),
+            },
+            "!" => Ok(Value::scalar(int_type(4, true), !self.decay(value).is_true() as u64)),
+            _ => {
+                // -x, +x and ~x are 0 - x, 0 + x and -1 ^ x
+                let zero = Value::scalar(int_type(4, true), 0);
+                match op {
+                    "~" => self.binary("^", Value::scalar(int_type(4, true), u64::MAX), value),
+                    _ => self.binary(op, zero, value),
+                }
+            }
+        }
+    }
+
+    fn binary(&self, op: &str, lhs: Value, rhs: Value) -> Result<Value, String> {
+        let (lhs, rhs) = (self.decay(lhs), self.decay(rhs));
+        let comparison = ["==", "!=", "<", ">", "<=", ">="].contains(&op);
+        match (op, &lhs.ctype, &rhs.ctype) {
+            ("+", CType::Pointer(target), CType::Int { .. })
+            | ("+", CType::Pointer(target), CType::Enum(..)) => {
+                return Ok(self.offset_pointer(&lhs, target, rhs.as_i128()));
+            }
+            ("+", CType::Int { .. }, CType::Pointer(target)) => {
+                return Ok(self.offset_pointer(&rhs, target, lhs.as_i128()));
+            }
+            ("-", CType::Pointer(target), CType::Int { .. }) => {
+                return Ok(self.offset_pointer(&lhs, target, -rhs.as_i128()));
+            }
+            ("-", CType::Pointer(target), CType::Pointer(_)) => {
+                let distance = lhs.bits().wrapping_sub(rhs.bits()) as i64;
+                let size = target.size(self.debug_data).max(1) as i64;
+                return Ok(Value::scalar(int_type(8, true), (distance / size) as u64));
+            }
+            (_, CType::Pointer(_), _) | (_, _, CType::Pointer(_)) if comparison => {
+                let result = compare(op, lhs.bits() as i128, rhs.bits() as i128);
+                return Ok(Value::scalar(int_type(4, true), result as u64));
+            }
+            _ => {}
+        }
+        for operand in [&lhs, &rhs].iter() {
+            match operand.ctype {
+                CType::Int { .. } | CType::Enum(..) | CType::Float { .. } => {}
+                _ => {
+                    return Err(format!(
+                        "Argument to arithmetic operation not a number or boolean: {}",
+                        operand.ctype.name(self.debug_data)
+                    ))
+                }
+            }
+        }
+        if let (CType::Float { .. }, _) | (_, CType::Float { .. }) = (&lhs.ctype, &rhs.ctype) {
+            let (a, b) = (lhs.as_f64(), rhs.as_f64());
+            let result = match op {
+                "+" => a + b,
+                "-" => a - b,
+                "*" => a * b,
+                "/" => a / b,
+                _ if comparison => {
+                    let result = match op {
+                        "==" => a == b,
+                        "!=" => a != b,
+                        "<" => a < b,
+                        ">" => a > b,
+                        "<=" => a <= b,
+                        _ => a >= b,
+                    };
+                    return Ok(Value::scalar(int_type(4, true), result as u64));
+                }
+                _ => return Err("Integer only operation.".to_string()),
+            };
+            return Ok(Value::scalar(double_type(), result.to_bits()));
+        }
+        // The usual arithmetic conversions: at least int, unsigned if an unsigned operand is
+        // at least as wide as the result. Wider types, such as __int128, are only computed with
+        // to 64 bits.
+        let (lhs_size, rhs_size) = (lhs.bytes.len(), rhs.bytes.len());
+        let size = lhs_size.max(rhs_size).max(4).min(8);
+        let unsigned_wins = |value: &Value, value_size| !value.is_signed() && value_size >= size;
+        let signed = !(unsigned_wins(&lhs, lhs_size) || unsigned_wins(&rhs, rhs_size));
+        let result_type = int_type(size, signed);
+        let convert = |value: &Value| {
+            let bits = value.as_i128() as u64;
+            if signed {
+                target::sign_extend(bits, size) as i128
+            } else {
+                (bits & (u64::MAX >> (64 - 8 * size as u32))) as i128
+            }
+        };
+        let (a, b) = (convert(&lhs), convert(&rhs));
+        if comparison {
+            return Ok(Value::scalar(int_type(4, true), compare(op, a, b) as u64));
+        }
+        let result = match op {
+            "+" => a.wrapping_add(b),
+            "-" => a.wrapping_sub(b),
+            "*" => a.wrapping_mul(b),
+            "/" | "%" if b == 0 => return Err("Division by zero".to_string()),
+            "/" => a / b,
+            "%" => a % b,
+            "&" => a & b,
+            "|" => a | b,
+            "^" => a ^ b,
+            "<<" => a << (b & 63),
+            ">>" => a >> (b & 63),
+            _ => return Err(format!("Unsupported operator {}.", op)),
+        };
+        Ok(Value::scalar(result_type, result as u64))
+    }
+
+    /// Calls a function in the program. Arguments are converted to the parameter types from the
+    /// debugging information, like C does for calls to prototyped functions.
+    fn call(&self, function: Value, args: &[Expr]) -> Result<Value, String> {
+        let function = self.decay(function);
+        let addr = match &function.ctype {
+            CType::Pointer(target) if matches!(**target, CType::Function) => function.bits(),
+            _ => return Err("Cannot perform a function call on a non-function value.".to_string()),
+        };
+        let signature = self
+            .debug_data
+            .get_function_at(addr as usize)
+            .filter(|function| function.address as u64 == addr);
+        let (parameters, return_type) = match signature {
+            Some(function) => (
+                function
+                    .parameters
+                    .iter()
+                    .map(|offset| CType::from_dwarf(self.debug_data, Some(*offset)))
+                    .collect(),
+                CType::from_dwarf(self.debug_data, function.return_type),
+            ),
+            // Like C without a prototype, assume an int return value
+            None => (Vec::new(), int_type(4, true)),
+        };
+        if args.len() < parameters.len() {
+            return Err("Too few arguments in function call.".to_string());
+        }
+        let mut int_args = Vec::new();
+        let mut float_args = Vec::new();
+        for (i, arg) in args.iter().enumerate() {
+            let value = self.decay(self.eval(arg)?);
+            let value = match (parameters.get(i), &value.ctype) {
+                (Some(CType::Struct(_)), _) | (_, CType::Struct(_)) => {
+                    return Err("Passing structs by value is not supported.".to_string())
+                }
+                (Some(ctype), _) => self.cast(ctype, value)?,
+                // Extra arguments of variadic functions are promoted
+                (None, CType::Float { .. }) => self.cast(&double_type(), value)?,
+                (None, _) => value,
+            };
+            match value.ctype {
+                CType::Float { .. } => float_args.push(value.bits()),
+                _ => int_args.push(value.as_i128() as u64),
+            }
+        }
+        if let CType::Struct(_) = return_type {
+            return Err("Returning structs by value is not supported.".to_string());
+        }
+        let (rax, xmm0) = self.target.call_function(addr, &int_args, &float_args)?;
+        let bits = if let CType::Float { .. } = return_type { xmm0 } else { rax };
+        self.register_value(return_type, bits)
+    }
+
+    /// Makes a value of type `ctype` from the register it is passed or returned in, holding
+    /// `bits`. Void values are empty; structs are not passed in a single register.
+    pub fn register_value(&self, ctype: CType, bits: u64) -> Result<Value, String> {
+        let size = match ctype {
+            CType::Struct(_) => return Err("Structs are not passed in registers.".to_string()),
+            CType::Void => 0,
+            _ => ctype.size(self.debug_data).min(8),
+        };
+        Ok(Value { ctype, address: None, bytes: bits.to_le_bytes()[..size].to_vec() })
+    }
+
+    fn offset_pointer(&self, pointer: &Value, target: &CType, count: i128) -> Value {
+        let size = target.size(self.debug_data) as i128;
+        let address = (pointer.bits() as i128).wrapping_add(count * size);
+        Value::scalar(pointer.ctype.clone(), address as u64)
+    }
+
+    /// Converts a value to another type, as a C cast does.
+    pub fn cast(&self, ctype: &CType, value: Value) -> Result<Value, String> {
+        let value = self.decay(value);
+        match (ctype, &value.ctype) {
+            (CType::Void, _) => Ok(Value::scalar(CType::Void, 0)),
+            (CType::Struct(_), _) | (CType::Array(..), _) | (CType::Function, _) => {
+                Err("Invalid cast.".to_string())
+            }
+            (_, CType::Struct(_)) | (_, CType::Void) => Err("Invalid cast.".to_string()),
+            (CType::Float { size, .. }, _) => {
+                let bits = if *size == 4 {
+                    (value.as_f64() as f32).to_bits() as u64
+                } else {
+                    value.as_f64().to_bits()
+                };
+                Ok(Value::scalar(ctype.clone(), bits))
+            }
+            (_, CType::Float { .. }) => {
+                Ok(Value::scalar(ctype.clone(), value.as_f64() as i64 as u64))
+            }
+            _ => Ok(Value::scalar(ctype.clone(), value.as_i128() as u64)),
+        }
+    }
+
+    fn member(&self, value: Value, name: &str) -> Result<Value, String> {
+        let offset = match value.ctype {
+            CType::Struct(offset) => offset,
+            _ => {
+                return Err(format!(
+                    "Attempt to extract a component of a value that is not a structure{}.",
+                    if let CType::Pointer(_) = value.ctype { " pointer" } else { "" }
+                ))
+            }
+        };
+        let members = match self.debug_data.get_type(offset).map(|dtype| &dtype.kind) {
+            Some(TypeKind::Struct(members)) => members,
+            _ => return Err("Incomplete struct type.".to_string()),
+        };
+        let member = members
+            .iter()
+            .find(|member| member.name == name)
+            .ok_or_else(|| format!("There is no member named {}.", name))?;
+        let ctype = CType::from_dwarf(self.debug_data, Some(member.type_offset));
+        let start = member.offset.min(value.bytes.len());
+        let end = (member.offset + ctype.size(self.debug_data)).min(value.bytes.len());
+        Ok(Value {
+            ctype,
+            address: value.address.map(|address| address + member.offset as u64),
+            bytes: value.bytes[start..end].to_vec(),
+        })
+    }
+
+    /// Formats a value the way gdb's print does.
+    pub fn format(&self, value: &Value) -> String {
+        self.format_value(value, true)
+    }
+
+    /// `top_level` is false for the members and elements of structs and arrays, whose pointer
+    /// values are printed without their type.
+    fn format_value(&self, value: &Value, top_level: bool) -> String {
+        if let (Some(format), CType::Int { .. }) | (Some(format), CType::Enum(..))
+        | (Some(format), CType::Pointer(_)) = (self.format, &value.ctype)
+        {
+            return format_integer(value, format);
+        }
+        match &value.ctype {
+            CType::Void => "void".to_string(),
+            CType::Int { name, .. } if name == "_Bool" => (value.bits() != 0).to_string(),
+            ctype @ CType::Int { .. } if ctype.is_char() => {
+                format!("{} '{}'", value.as_i128(), escape(value.bits() as u8, '\''))
+            }
+            CType::Int { .. } => value.as_i128().to_string(),
+            CType::Float { size: 4, .. } | CType::Float { size: 8, .. } => {
+                value.as_f64().to_string()
+            }
+            CType::Float { .. } => "<unsupported floating point type>".to_string(),
+            CType::Enum(offset, _) => {
+                let number = value.as_i128();
+                let enumerator = match self.debug_data.get_type(*offset).map(|dtype| &dtype.kind) {
+                    Some(TypeKind::Enum(_, values)) => {
+                        values.iter().find(|(_, value)| *value as i128 == number)
+                    }
+                    _ => None,
+                };
+                match enumerator {
+                    Some((name, _)) => name.clone(),
+                    None => number.to_string(),
+                }
+            }
+            CType::Pointer(target) => {
+                let address = value.bits();
+                if target.is_char() && address != 0 {
+                    return format!("{:#x} {}", address, self.read_string(address));
+                }
+                let mut text = format!("{:#x}", address);
+                if let CType::Function = **target {
+                    if let Some(func) = self.debug_data.get_function_from_addr(address as usize) {
+                        text += &format!(" <{}>", func);
+                    }
+                }
+                if top_level {
+                    format!("({}) {}", value.ctype.name(self.debug_data), text)
+                } else {
+                    text
+                }
+            }
+            CType::Struct(_) => {
+                let members = match value.ctype {
+                    CType::Struct(offset) => match self.debug_data.get_type(offset) {
+                        Some(dtype) => match &dtype.kind {
+                            TypeKind::Struct(members) => members.clone(),
+                            _ => Vec::new(),
+                        },
+                        None => Vec::new(),
+                    },
+                    _ => unreachable!(),
+                };
+                let fields: Vec<String> = members
+                    .iter()
+                    .filter_map(|member| {
+                        let field = self.member(value.clone(), &member.name).ok()?;
+                        Some(format!("{} = {}", member.name, self.format_value(&field, false)))
+                    })
+                    .collect();
+                format!("{{{}}}", fields.join(", "))
+            }
+            CType::Array(elem, count) => {
+                if elem.is_char() {
+                    let end = value.bytes.iter().position(|byte| *byte == 0);
+                    let text = &value.bytes[..end.unwrap_or(value.bytes.len())];
+                    return quote(text, text.len() > PRINT_LIMIT);
+                }
+                let size = elem.size(self.debug_data).max(1);
+                let shown = (*count).min(PRINT_LIMIT).min(value.bytes.len() / size);
+                let mut elements: Vec<String> = (0..shown)
+                    .map(|i| {
+                        let element = Value {
+                            ctype: (**elem).clone(),
+                            address: value.address.map(|address| address + (i * size) as u64),
+                            bytes: value.bytes[i * size..(i + 1) * size].to_vec(),
+                        };
+                        self.format_value(&element, false)
+                    })
+                    .collect();
+                if shown < *count {
+                    elements.push("...".to_string());
+                }
+                format!("{{{}}}", elements.join(", "))
+            }
+            CType::Function => {
+                let address = value.address.unwrap_or(0);
+                match self.debug_data.get_function_from_addr(address as usize) {
+                    Some(func) => format!("{{<function>}} {:#x} <{}>", address, func),
+                    None => format!("{{<function>}} {:#x}", address),
+                }
+            }
+        }
+    }
+
+    /// Reads a NUL-terminated string and formats it as a C string literal.
+    fn read_string(&self, address: u64) -> String {
+        let mut text = Vec::new();
+        while text.len() < PRINT_LIMIT {
+            match self.target.read_memory(address + text.len() as u64, 1) {
+                Ok(bytes) if bytes[0] == 0 => return quote(&text, false),
+                Ok(bytes) => text.push(bytes[0]),
+                Err(_) if text.is_empty() => {
+                    return format!("<error: Cannot access memory at address {:#x}>", address)
+                }
+                Err(_) => break,
+            }
+        }
+        quote(&text, true)
+    }
+}
+
+/// Quotes bytes as a C string literal, with a trailing ... if `truncated`.
+pub fn quote(text: &[u8], truncated: bool) -> String {
+    let quoted: String = text.iter().take(PRINT_LIMIT).map(|byte| escape(*byte, '"')).collect();
+    format!("\"{}\"{}", quoted, if truncated { "..." } else { "" })
+}
+
+/// Formats an integer or pointer with a gdb format letter: x, d, u, o, t or c.
+fn format_integer(value: &Value, format: char) -> String {
+    let bits = value.bits();
+    match format {
+        'x' => format!("{:#x}", bits),
+        'o' => format!("0{:o}", bits),
+        't' => format!("{:b}", bits),
+        'd' => target::sign_extend(bits, value.bytes.len().min(8).max(1)).to_string(),
+        'c' => format!("{} '{}'", bits as u8 as i8, escape(bits as u8, '\'')),
+        _ => bits.to_string(),
+    }
+}
+
+fn compare(op: &str, a: i128, b: i128) -> bool {
+    match op {
+        "==" => a == b,
+        "!=" => a != b,
+        "<" => a < b,
+        ">" => a > b,
+        "<=" => a <= b,
+        _ => a >= b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::target::NoProcess;
+
+    /// The symbols of the test program itself, without debugging information.
+    fn debug_data() -> DwarfData {
+        DwarfData::from_symbols("/proc/self/exe").unwrap()
+    }
+
+    fn parse(text: &str) -> Result<String, String> {
+        let debug_data = debug_data();
+        let tokens = tokenize(text)?;
+        let mut parser = Parser { tokens, pos: 0, debug_data: &debug_data, rip: 0 };
+        let expr = parser.parse_expr()?;
+        if parser.pos < parser.tokens.len() {
+            return Err(parser.syntax_error());
+        }
+        Ok(format!("{:?}", expr))
+    }
+
+    fn evaluate(text: &str) -> Result<String, String> {
+        let debug_data = debug_data();
+        let evaluator = Evaluator::new(&NoProcess, &debug_data, 0, 0);
+        evaluator.evaluate(text).map(|value| evaluator.format(&value))
+    }
+
+    #[test]
+    fn tokenize_operators_and_names() {
+        let tokens = tokenize("s->next[0x1f] >= 'a'").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Ident("s".to_string()),
+                Token::Punct("->"),
+                Token::Ident("next".to_string()),
+                Token::Punct("["),
+                Token::Int(31),
+                Token::Punct("]"),
+                Token::Punct(">="),
+                Token::Char(b'a'),
+            ]
+        );
+        let tokens = tokenize("$$2+$rip*'\\n'").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Dollar("$2".to_string()),
+                Token::Punct("+"),
+                Token::Dollar("rip".to_string()),
+                Token::Punct("*"),
+                Token::Char(b'\n'),
+            ]
+        );
+        assert_eq!(tokenize(".5").unwrap(), vec![Token::Float(0.5)]);
+        assert!(tokenize("'a").is_err());
+        assert!(tokenize("a @ b").is_err());
+    }
+
+    #[test]
+    fn parse_numbers() {
+        assert_eq!(parse_number("42"), Ok(Token::Int(42)));
+        assert_eq!(parse_number("0"), Ok(Token::Int(0)));
+        assert_eq!(parse_number("0x1fUL"), Ok(Token::Int(31)));
+        assert_eq!(parse_number("010"), Ok(Token::Int(8)));
+        assert_eq!(parse_number("0777u"), Ok(Token::Int(511)));
+        assert_eq!(parse_number("1.5f"), Ok(Token::Float(1.5)));
+        assert_eq!(parse_number("1e3"), Ok(Token::Float(1000.0)));
+        assert!(parse_number("09").is_err());
+        assert!(parse_number("0x").is_err());
+        assert!(parse_number("12abc").is_err());
+    }
+
+    #[test]
+    fn parse_precedence_and_associativity() {
+        assert_eq!(
+            parse("1 + 2 * 3").unwrap(),
+            "Binary(\"+\", Int(1), Binary(\"*\", Int(2), Int(3)))"
+        );
+        assert_eq!(
+            parse("1 - 2 - 3").unwrap(),
+            "Binary(\"-\", Binary(\"-\", Int(1), Int(2)), Int(3))"
+        );
+        assert_eq!(
+            parse("a = b = 1").unwrap(),
+            "Assign(Name(\"a\"), Assign(Name(\"b\"), Int(1)))"
+        );
+        assert_eq!(
+            parse("-*p[1]").unwrap(),
+            "Unary(\"-\", Unary(\"*\", Index(Name(\"p\"), Int(1))))"
+        );
+        assert_eq!(
+            parse("s->a.b").unwrap(),
+            "Member(Member(Unary(\"*\", Name(\"s\")), \"a\"), \"b\")"
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(parse("1 +"), Err("A syntax error in expression, near `'.".to_string()));
+        assert!(parse("(1").is_err());
+        assert!(parse("1 2").is_err());
+        assert!(parse("sizeof").is_err());
+    }
+
+    #[test]
+    fn evaluate_constants() {
+        assert_eq!(evaluate("1 + 2 * 3"), Ok("7".to_string()));
+        assert_eq!(evaluate("010 + 0x10"), Ok("24".to_string()));
+        assert_eq!(evaluate("-7 / 2"), Ok("-3".to_string()));
+        assert_eq!(evaluate("-1 < (unsigned int) 0"), Ok("0".to_string()));
+        assert_eq!(evaluate("(unsigned char) -1"), Ok("255 '\\377'".to_string()));
+        assert_eq!(evaluate("sizeof(long)"), Ok("8".to_string()));
+        assert_eq!(evaluate("1.5 * 2"), Ok("3".to_string()));
+        assert!(evaluate("1 / 0").is_err());
+    }
+
+    #[test]
+    fn pointer_difference() {
+        assert_eq!(evaluate("(int *) 16 - (int *) 8"), Ok("2".to_string()));
+        assert_eq!(evaluate("(int *) 8 - (int *) 16"), Ok("-2".to_string()));
+        assert_eq!(evaluate("(char *) 0 - (char *) -1"), Ok("1".to_string()));
+    }
+
+    #[test]
+    fn arithmetic_on_wide_integers() {
+        let debug_data = debug_data();
+        let evaluator = Evaluator::new(&NoProcess, &debug_data, 0, 0);
+        let wide = Value {
+            ctype: CType::Int { name: "__int128".to_string(), size: 16, signed: true },
+            address: None,
+            bytes: vec![0; 16],
+        };
+        let one = Value::scalar(int_type(4, true), 1);
+        let sum = evaluator.binary("+", wide, one).unwrap();
+        assert_eq!(evaluator.format(&sum), "1");
+    }
+
+    #[test]
+    fn convenience_variables_without_a_process() {
+        let debug_data = debug_data();
+        let session = RefCell::new(Session::default());
+        let evaluator = Evaluator::new(&NoProcess, &debug_data, 0, 0).with_session(&session);
+        let format = |text| evaluator.evaluate(text).map(|value| evaluator.format(&value));
+        assert_eq!(format("$count = 2"), Ok("2".to_string()));
+        assert_eq!(format("$count * 3"), Ok("6".to_string()));
+        assert_eq!(format("$unset"), Ok("void".to_string()));
+        assert_eq!(format("$rip"), Err("No registers.".to_string()));
+        assert!(format("$rip = 1").is_err());
+    }
+}
@@ -0,0 +1,466 @@
+//! A stub speaking the GDB remote serial protocol, so that gdb (`target remote :PORT`) can drive
+//! an `Inferior` over TCP. Only what gdb needs to debug a single-threaded x86_64 process is
+//! implemented; every other packet gets the empty "unsupported" reply.
+
+use nix::libc::user_regs_struct;
+use nix::sys::ptrace;
+use nix::sys::signal::Signal;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use crate::inferior::{Inferior, Status};
+use crate::target::Target;
+
+/// Size in bytes of each register in gdb's amd64 numbering, up to gs. gdb accepts a `g` reply
+/// that stops there and treats the floating point registers as unavailable.
+const GDB_REGISTER_SIZES: [usize; 24] =
+    [8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 4, 4, 4, 4, 4, 4, 4];
+
+fn gdb_register(regs: &mut user_regs_struct, num: usize) -> Option<&mut u64> {
+    Some(match num {
+        0 => &mut regs.rax,
+        1 => &mut regs.rbx,
+        2 => &mut regs.rcx,
+        3 => &mut regs.rdx,
+        4 => &mut regs.rsi,
+        5 => &mut regs.rdi,
+        6 => &mut regs.rbp,
+        7 => &mut regs.rsp,
+        8 => &mut regs.r8,
+        9 => &mut regs.r9,
+        10 => &mut regs.r10,
+        11 => &mut regs.r11,
+        12 => &mut regs.r12,
+        13 => &mut regs.r13,
+        14 => &mut regs.r14,
+        15 => &mut regs.r15,
+        16 => &mut regs.rip,
+        17 => &mut regs.eflags,
+        18 => &mut regs.cs,
+        19 => &mut regs.ss,
+        20 => &mut regs.ds,
+        21 => &mut regs.es,
+        22 => &mut regs.fs,
+        23 => &mut regs.gs,
+        _ => return None,
+    })
+}
+
+/// gdb numbers signals its own way; most of the low ones agree with Linux, but not all.
+fn gdb_signal(signal: Signal) -> u8 {
+    match signal {
+        Signal::SIGBUS => 10,
+        Signal::SIGUSR1 => 30,
+        Signal::SIGUSR2 => 31,
+        Signal::SIGCHLD => 20,
+        Signal::SIGCONT => 19,
+        Signal::SIGSTOP => 17,
+        Signal::SIGTSTP => 18,
+        Signal::SIGURG => 16,
+        Signal::SIGIO => 23,
+        Signal::SIGPWR => 32,
+        Signal::SIGSYS => 12,
+        Signal::SIGSTKFLT => 143,
+        other => other as u8,
+    }
+}
+
+fn from_gdb_signal(num: u8) -> Option<Signal> {
+    Signal::iterator().find(|signal| gdb_signal(*signal) == num)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// The checksum ending a packet: the sum of its bytes, modulo 256.
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+/// Parses an "addr,length" pair as used by the m, M and Z packets.
+fn parse_addr_len(args: &str) -> Option<(u64, usize)> {
+    let mut parts = args.splitn(2, ',');
+    let addr = u64::from_str_radix(parts.next()?, 16).ok()?;
+    let len = usize::from_str_radix(parts.next()?, 16).ok()?;
+    Some((addr, len))
+}
+
+fn nix_to_io(err: nix::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+struct GdbServer {
+    stream: TcpStream,
+    inferior: Option<Inferior>,
+    /// Stop reply for the most recent stop, sent again in response to `?`
+    last_stop: String,
+    no_ack: bool,
+}
+
+/// Starts `target` stopped at its first instruction and serves it to one gdb connection on
+/// `addr` (e.g. ":1234").
+pub fn serve(addr: &str, target: &str, args: &Vec<String>) -> io::Result<()> {
+    let addr = match addr.starts_with(':') {
+        true => format!("127.0.0.1{}", addr),
+        false => addr.to_string(),
+    };
+    let inferior = match Inferior::new(target, args, &Vec::new()) {
+        Some(inferior) => inferior,
+        None => return Err(io::Error::new(io::ErrorKind::Other, "error starting subprocess")),
+    };
+    println!("Process {} created; pid = {}", target, inferior.pid());
+    let listener = TcpListener::bind(&addr)?;
+    println!("Listening on {}", addr);
+    let (stream, peer) = listener.accept()?;
+    println!("Remote debugging from host {}", peer);
+    stream.set_nodelay(true)?;
+
+    let mut server = GdbServer {
+        stream,
+        inferior: Some(inferior),
+        last_stop: "S05".to_string(),
+        no_ack: false,
+    };
+    while let Some(packet) = server.read_packet()? {
+        match server.handle(&packet)? {
+            Some(reply) => server.send_packet(&reply)?,
+            None => break,
+        }
+        if packet == "QStartNoAckMode" {
+            server.no_ack = true;
+        }
+    }
+    if let Some(inferior) = &mut server.inferior {
+        println!("Killing inferior (pid={})", inferior.pid());
+        inferior.kill().map_err(nix_to_io)?;
+    }
+    Ok(())
+}
+
+impl GdbServer {
+    /// Reads the next `$packet#checksum`, acknowledging it unless in no-ack mode. Returns None
+    /// once gdb disconnects.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        let mut byte = [0u8];
+        // Skip acks and stray interrupt requests until the start of a packet
+        loop {
+            if self.stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+        let mut data = Vec::new();
+        loop {
+            if self.stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+        let mut sum = [0u8; 2];
+        self.stream.read_exact(&mut sum)?;
+        let expected =
+            std::str::from_utf8(&sum).ok().and_then(|c| u8::from_str_radix(c, 16).ok());
+        let actual = checksum(&data);
+        if !self.no_ack {
+            if expected != Some(actual) {
+                self.stream.write_all(b"-")?;
+                return self.read_packet();
+            }
+            self.stream.write_all(b"+")?;
+        }
+        Ok(Some(String::from_utf8_lossy(&data).to_string()))
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())?;
+        if !self.no_ack {
+            // Wait for the ack; a "-" asks for retransmission
+            let mut ack = [0u8];
+            self.stream.read_exact(&mut ack)?;
+            if ack[0] == b'-' {
+                return self.send_packet(data);
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles one packet, returning the reply, or None when the session is over.
+    fn handle(&mut self, packet: &str) -> io::Result<Option<String>> {
+        // Commands are one ASCII letter; anything else falls through to the unsupported reply
+        let (cmd, args) = match packet.get(..1) {
+            Some(cmd) => (cmd, &packet[1..]),
+            None => ("", packet),
+        };
+        if packet.starts_with("qSupported") {
+            return Ok(Some("PacketSize=4000;QStartNoAckMode+".to_string()));
+        }
+        if packet == "QStartNoAckMode" {
+            // The OK itself is still acknowledged; the serve loop switches modes after sending it
+            return Ok(Some("OK".to_string()));
+        }
+        if packet.starts_with("vKill") {
+            if let Some(inferior) = &mut self.inferior {
+                inferior.kill().map_err(nix_to_io)?;
+            }
+            self.inferior = None;
+            return Ok(Some("OK".to_string()));
+        }
+        let inferior = match (&mut self.inferior, cmd) {
+            (_, "k") => return Ok(None),
+            (_, "?") => return Ok(Some(self.last_stop.clone())),
+            (_, "H") | (_, "T") => return Ok(Some("OK".to_string())),
+            (Some(inferior), _) => inferior,
+            // The process is gone: only the queries above make sense any more
+            (None, "D") => {
+                self.send_packet("OK")?;
+                return Ok(None);
+            }
+            (None, _) => return Ok(Some("E01".to_string())),
+        };
+        let pid = inferior.pid();
+        let reply = match cmd {
+            "g" => {
+                let mut regs = inferior.regs().map_err(nix_to_io)?;
+                let mut bytes = Vec::new();
+                for (num, size) in GDB_REGISTER_SIZES.iter().enumerate() {
+                    let value = *gdb_register(&mut regs, num).unwrap();
+                    bytes.extend_from_slice(&value.to_le_bytes()[..*size]);
+                }
+                to_hex(&bytes)
+            }
+            "G" => match from_hex(args) {
+                Some(bytes) => {
+                    let mut regs = inferior.regs().map_err(nix_to_io)?;
+                    let mut pos = 0;
+                    for (num, size) in GDB_REGISTER_SIZES.iter().enumerate() {
+                        if let Some(value) = bytes.get(pos..pos + size) {
+                            let mut buf = [0u8; 8];
+                            buf[..*size].copy_from_slice(value);
+                            *gdb_register(&mut regs, num).unwrap() = u64::from_le_bytes(buf);
+                        }
+                        pos += size;
+                    }
+                    ptrace::setregs(pid, regs).map_err(nix_to_io)?;
+                    "OK".to_string()
+                }
+                None => "E01".to_string(),
+            },
+            "p" => {
+                let mut regs = inferior.regs().map_err(nix_to_io)?;
+                let num = usize::from_str_radix(args, 16).unwrap_or(usize::MAX);
+                match (gdb_register(&mut regs, num), GDB_REGISTER_SIZES.get(num)) {
+                    (Some(value), Some(size)) => to_hex(&value.to_le_bytes()[..*size]),
+                    _ => "E01".to_string(),
+                }
+            }
+            "P" => {
+                let mut regs = inferior.regs().map_err(nix_to_io)?;
+                let mut parts = args.splitn(2, '=');
+                let num = parts.next().and_then(|n| usize::from_str_radix(n, 16).ok());
+                let value = parts.next().and_then(from_hex);
+                match (num.and_then(|n| gdb_register(&mut regs, n)), value) {
+                    (Some(reg), Some(value)) if value.len() <= 8 => {
+                        let mut buf = [0u8; 8];
+                        buf[..value.len()].copy_from_slice(&value);
+                        *reg = u64::from_le_bytes(buf);
+                        ptrace::setregs(pid, regs).map_err(nix_to_io)?;
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "m" => match parse_addr_len(args).map(|(addr, len)| inferior.read_memory(addr, len)) {
+                Some(Ok(bytes)) => to_hex(&bytes),
+                _ => "E01".to_string(),
+            },
+            "M" => {
+                let mut parts = args.splitn(2, ':');
+                let range = parts.next().and_then(parse_addr_len);
+                match (range, parts.next().and_then(from_hex)) {
+                    (Some((addr, len)), Some(bytes)) if bytes.len() == len => {
+                        match inferior.write_memory(addr, &bytes) {
+                            Ok(()) => "OK".to_string(),
+                            Err(_) => "E01".to_string(),
+                        }
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "Z" | "z" if args.starts_with("0,") => match parse_addr_len(&args[2..]) {
+                Some((addr, _kind)) => {
+                    if cmd == "Z" && !inferior.bp_map.contains_key(&addr) {
+                        match inferior.write_byte(addr, 0xcc) {
+                            Ok(orig_byte) => {
+                                inferior.bp_map.insert(addr, orig_byte);
+                                "OK".to_string()
+                            }
+                            Err(_) => "E01".to_string(),
+                        }
+                    } else if cmd == "z" {
+                        if let Some(orig_byte) = inferior.bp_map.remove(&addr) {
+                            inferior.write_byte(addr, orig_byte).map_err(nix_to_io)?;
+                        }
+                        "OK".to_string()
+                    } else {
+                        "OK".to_string()
+                    }
+                }
+                None => "E01".to_string(),
+            },
+            "c" | "s" | "C" => {
+                // c [addr], s [addr] or C sig[;addr]
+                let (signal, addr) = match cmd {
+                    "C" => {
+                        let mut parts = args.splitn(2, ';');
+                        let signal = parts.next().and_then(|sig| u8::from_str_radix(sig, 16).ok());
+                        (signal.and_then(from_gdb_signal), parts.next())
+                    }
+                    _ => (None, Some(args)),
+                };
+                if let Some(Ok(addr)) = addr.map(|addr| u64::from_str_radix(addr, 16)) {
+                    let mut regs = inferior.regs().map_err(nix_to_io)?;
+                    regs.rip = addr;
+                    ptrace::setregs(pid, regs).map_err(nix_to_io)?;
+                }
+                let status = if cmd == "s" {
+                    inferior.step_instruction().map_err(nix_to_io)?
+                } else {
+                    self.continue_interruptible(signal)?
+                };
+                self.last_stop = self.stop_reply(status)?;
+                self.last_stop.clone()
+            }
+            "D" => {
+                if let Some(inferior) = self.inferior.take() {
+                    // Take our breakpoints out before letting the process go
+                    for (&addr, &orig_byte) in &inferior.bp_map {
+                        inferior.write_byte(addr, orig_byte).map_err(nix_to_io)?;
+                    }
+                    ptrace::detach(pid, None).map_err(nix_to_io)?;
+                }
+                self.send_packet("OK")?;
+                return Ok(None);
+            }
+            _ => match packet {
+                "qC" => format!("QC{:x}", pid.as_raw()),
+                "qfThreadInfo" => format!("m{:x}", pid.as_raw()),
+                "qsThreadInfo" => "l".to_string(),
+                // We launched the process, so gdb should kill rather than detach on quit
+                "qAttached" => "0".to_string(),
+                _ if packet.starts_with("qSymbol") => "OK".to_string(),
+                _ => String::new(),
+            },
+        };
+        Ok(Some(reply))
+    }
+
+    /// Continues the inferior until it stops, while watching the connection for the 0x03 byte
+    /// gdb sends when the user presses ctrl+c.
+    fn continue_interruptible(&mut self, signal: Option<Signal>) -> io::Result<Status> {
+        let inferior = self.inferior.as_mut().unwrap();
+        if let Some(status) = inferior.resume(signal).map_err(nix_to_io)? {
+            return Ok(status);
+        }
+        loop {
+            if let Some(status) = inferior.try_wait().map_err(nix_to_io)? {
+                return Ok(status);
+            }
+            self.stream.set_nonblocking(true)?;
+            let mut byte = [0u8];
+            let read = self.stream.read(&mut byte);
+            self.stream.set_nonblocking(false)?;
+            match read {
+                Ok(1) if byte[0] == 0x03 => return inferior.interrupt().map_err(nix_to_io),
+                Ok(0) => return inferior.interrupt().map_err(nix_to_io),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                Err(err) => return Err(err),
+                Ok(_) => {}
+            }
+        }
+    }
+
+    /// Builds the stop reply packet for a status. After a breakpoint trap, rip is rewound onto
+    /// the breakpoint as gdb expects.
+    fn stop_reply(&mut self, status: Status) -> io::Result<String> {
+        Ok(match status {
+            Status::Stopped(signal, rip) => {
+                let inferior = self.inferior.as_mut().unwrap();
+                let rip = rip as u64;
+                if signal == Signal::SIGTRAP && inferior.bp_map.contains_key(&rip.wrapping_sub(1)) {
+                    let mut regs = inferior.regs().map_err(nix_to_io)?;
+                    regs.rip = rip.wrapping_sub(1);
+                    ptrace::setregs(inferior.pid(), regs).map_err(nix_to_io)?;
+                }
+                format!("S{:02x}", gdb_signal(signal))
+            }
+            Status::Exited(code) => {
+                self.inferior = None;
+                format!("W{:02x}", code as u8)
+            }
+            Status::Signaled(signal) => {
+                self.inferior = None;
+                format!("X{:02x}", gdb_signal(signal))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_round_trip() {
+        assert_eq!(from_hex("00ff7f"), Some(vec![0x00, 0xff, 0x7f]));
+        assert_eq!(from_hex("DeadBeef"), Some(vec![0xde, 0xad, 0xbe, 0xef]));
+        assert_eq!(from_hex(""), Some(Vec::new()));
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(from_hex("zz"), None);
+        assert_eq!(from_hex(&to_hex(b"deet")), Some(b"deet".to_vec()));
+    }
+
+    #[test]
+    fn packet_checksums() {
+        assert_eq!(checksum(b""), 0);
+        assert_eq!(checksum(b"OK"), 0x9a);
+        assert_eq!(checksum(b"qSupported"), 0x37);
+        // Wraps around rather than overflowing
+        assert_eq!(checksum(&[0xff; 3]), 0xfd);
+    }
+
+    #[test]
+    fn signal_round_trip() {
+        assert_eq!(gdb_signal(Signal::SIGTRAP), 5);
+        assert_eq!(gdb_signal(Signal::SIGURG), 16);
+        assert_eq!(gdb_signal(Signal::SIGIO), 23);
+        assert_eq!(gdb_signal(Signal::SIGSYS), 12);
+        assert_eq!(from_gdb_signal(32), Some(Signal::SIGPWR));
+        assert_eq!(from_gdb_signal(143), Some(Signal::SIGSTKFLT));
+        assert_eq!(from_gdb_signal(0), None);
+        // No two signals share a number
+        for signal in Signal::iterator() {
+            assert_eq!(from_gdb_signal(gdb_signal(signal)), Some(signal), "{:?}", signal);
+        }
+    }
+}
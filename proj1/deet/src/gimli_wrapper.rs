@@ -6,17 +6,26 @@
 //! This code is a huge mess. Please don't read it unless you're trying to do an extension :)
 
 use gimli;
-use gimli::{UnitOffset, UnitSectionOffset};
+use gimli::{Reader as _, UnitOffset, UnitSectionOffset};
 use object::Object;
 use std::borrow;
 //use std::io::{BufWriter, Write};
-use crate::dwarf_data::{File, Function, Line, Location, Type, Variable};
+use crate::dwarf_data::{File, Function, Line, Location, Member, Type, TypeKind, Variable};
+use crate::output;
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fmt::Write;
-use std::{io, path};
+use std::{fs, io, path};
 
-pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<Vec<File>, Error> {
+/// Loads the DWARF of the executable at `path`, whose parsed ELF file is `object`. The DIEs of
+/// units compiled with -gsplit-dwarf are loaded from their .dwo file, or from the DWARF package
+/// `path`.dwp if there is one. Only the GNU flavor of split DWARF (-gdwarf-4) is supported; gimli
+/// doesn't read the skeleton units of DWARF 5, which are skipped with a warning.
+pub fn load_file(
+    object: &object::File,
+    endian: gimli::RunTimeEndian,
+    path: &str,
+) -> Result<(Vec<File>, HashMap<usize, Type>), Error> {
     // Load a section and return as `Cow<[u8]>`.
     let load_section = |id: gimli::SectionId| -> Result<borrow::Cow<[u8]>, gimli::Error> {
         Ok(object
@@ -44,203 +53,761 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
 
     let mut compilation_units: Vec<File> = Vec::new();
 
+    // DIE offsets in .dwo files count from the end of .debug_info, as if the files followed
+    // each other, so that the types of different files don't get mixed up
+    let debug_info = load_section(gimli::SectionId::DebugInfo)?;
+    let mut dwo_base = debug_info.len();
+    let package = Package::open(&format!("{}.dwp", path));
+
+    let (offsets, skipped) = unit_offsets(&debug_info, endian);
+    if skipped > 0 {
+        output::console(&format!(
+            "{}: DWARF 5 split units are not supported, skipping {} of {} units. Compile with \
+             -gdwarf-4 -gsplit-dwarf to debug them.",
+            path,
+            skipped,
+            offsets.len() + skipped
+        ));
+    }
     // Iterate over the compilation units.
-    let mut iter = dwarf.units();
-    while let Some(header) = iter.next()? {
+    for offset in offsets {
+        let header = dwarf.debug_info.header_from_offset(gimli::DebugInfoOffset(offset))?;
         let unit = dwarf.unit(header)?;
+        // The DIEs of a split DWARF unit are in its .dwo file, leaving only a skeleton here
+        match get_split_unit(&unit, &dwarf)? {
+            Some(split) => {
+                match load_split_unit(&split, &dwarf, package.as_ref(), endian)? {
+                    Some((mut files, types, size)) => {
+                        let types = rebase(&mut files, types, dwo_base);
+                        compilation_units.append(&mut files);
+                        offset_to_type.extend(types);
+                        dwo_base += size;
+                    }
+                    None => {
+                        output::console(&format!(
+                            "Could not find split debugging information in {}",
+                            split.path.display()
+                        ));
+                        load_entries(&unit, &dwarf, &mut compilation_units, &mut offset_to_type)?;
+                    }
+                }
+            }
+            None => load_entries(&unit, &dwarf, &mut compilation_units, &mut offset_to_type)?,
+        }
+        load_lines(&unit, &dwarf, &mut compilation_units)?;
+    }
+    resolve_types(&mut offset_to_type);
+    for file in &mut compilation_units {
+        let variables = file
+            .global_variables
+            .iter_mut()
+            .chain(file.functions.iter_mut().flat_map(|func| func.variables.iter_mut()));
+        for var in variables {
+            if let Some(dtype) = offset_to_type.get(&var.type_offset) {
+                var.entity_type = dtype.clone();
+            }
+        }
+        // Drop variables of types we couldn't load
+        let known = |var: &Variable| offset_to_type.contains_key(&var.type_offset);
+        file.global_variables.retain(known);
+        for func in &mut file.functions {
+            func.variables.retain(known);
+        }
+    }
+    Ok((compilation_units, offset_to_type))
+}
 
-        // Iterate over the Debugging Information Entries (DIEs) in the unit.
-        let mut depth = 0;
-        let mut entries = unit.entries();
-        while let Some((delta_depth, entry)) = entries.next_dfs()? {
-            depth += delta_depth;
-            // Update the offset_to_type mapping for types
-            // Update the variable list for formal params/variables
-            match entry.tag() {
-                gimli::DW_TAG_compile_unit => {
-                    let name = if let Ok(Some(attr)) = entry.attr(gimli::DW_AT_name) {
-                        if let Ok(DebugValue::Str(name)) = get_attr_value(&attr, &unit, &dwarf) {
-                            name
-                        } else {
-                            "<unknown>".to_string()
-                        }
+/// Returns the offsets of the units in `debug_info` that gimli can read, and how many DWARF 5
+/// skeleton and split units were left out, whose headers it fails on. Should a header be
+/// malformed, the scan stops there, leaving it to gimli to report the error.
+fn unit_offsets(debug_info: &[u8], endian: gimli::RunTimeEndian) -> (Vec<usize>, usize) {
+    let mut offsets = Vec::new();
+    let mut skipped = 0;
+    let mut offset = 0;
+    while offset < debug_info.len() {
+        let mut input = gimli::EndianSlice::new(&debug_info[offset..], endian);
+        let header = input.read_initial_length().and_then(|(length, format)| {
+            let version = input.read_u16()?;
+            let unit_type = if version >= 5 { Some(input.read_u8()?) } else { None };
+            Ok((format.initial_length_size() as usize + length, unit_type))
+        });
+        let (size, unit_type) = match header {
+            Ok(header) => header,
+            Err(_) => {
+                offsets.push(offset);
+                break;
+            }
+        };
+        match unit_type {
+            Some(DW_UT_SKELETON) | Some(DW_UT_SPLIT_COMPILE) => skipped += 1,
+            _ => offsets.push(offset),
+        }
+        offset += size;
+    }
+    (offsets, skipped)
+}
+
+/// Unit types of the units that point to a .dwo file in DWARF 5, and of those in it
+const DW_UT_SKELETON: u8 = 0x04;
+const DW_UT_SPLIT_COMPILE: u8 = 0x05;
+
+/// Loads the DIEs of a compilation unit: its functions, variables and types.
+fn load_entries<R: Reader>(
+    unit: &gimli::Unit<R>,
+    dwarf: &gimli::Dwarf<R>,
+    compilation_units: &mut Vec<File>,
+    offset_to_type: &mut HashMap<usize, Type>,
+) -> Result<(), Error> {
+    // Iterate over the Debugging Information Entries (DIEs) in the unit.
+    let mut depth = 0;
+    // Types whose children describe them (struct members, array dimensions, enumerators),
+    // with their depth
+    let mut aggregates: Vec<(isize, usize)> = Vec::new();
+    // Depth of the function whose parameters are being read
+    let mut function_depth: Option<isize> = None;
+    let mut entries = unit.entries();
+    while let Some((delta_depth, entry)) = entries.next_dfs()? {
+        depth += delta_depth;
+        while aggregates.last().map_or(false, |&(parent_depth, _)| parent_depth >= depth) {
+            aggregates.pop();
+        }
+        if function_depth.map_or(false, |function_depth| function_depth >= depth) {
+            function_depth = None;
+        }
+        // Update the offset_to_type mapping for types
+        // Update the variable list for formal params/variables
+        match entry.tag() {
+            gimli::DW_TAG_compile_unit => {
+                let name = if let Ok(Some(attr)) = entry.attr(gimli::DW_AT_name) {
+                    if let Ok(DebugValue::Str(name)) = get_attr_value(&attr, unit, dwarf) {
+                        name
                     } else {
                         "<unknown>".to_string()
-                    };
-                    compilation_units.push(File {
-                        name,
-                        global_variables: Vec::new(),
-                        functions: Vec::new(),
-                        lines: Vec::new(),
-                    });
-                }
-                gimli::DW_TAG_base_type => {
-                    let name = if let Ok(Some(attr)) = entry.attr(gimli::DW_AT_name) {
-                        if let Ok(DebugValue::Str(name)) = get_attr_value(&attr, &unit, &dwarf) {
-                            name
-                        } else {
-                            "<unknown>".to_string()
-                        }
+                    }
+                } else {
+                    "<unknown>".to_string()
+                };
+                compilation_units.push(File {
+                    name,
+                    global_variables: Vec::new(),
+                    functions: Vec::new(),
+                    lines: Vec::new(),
+                });
+            }
+            gimli::DW_TAG_base_type => {
+                let name = if let Ok(Some(attr)) = entry.attr(gimli::DW_AT_name) {
+                    if let Ok(DebugValue::Str(name)) = get_attr_value(&attr, unit, dwarf) {
+                        name
                     } else {
                         "<unknown>".to_string()
-                    };
-                    let byte_size = if let Ok(Some(attr)) = entry.attr(gimli::DW_AT_byte_size) {
-                        if let Ok(DebugValue::Uint(byte_size)) =
-                            get_attr_value(&attr, &unit, &dwarf)
-                        {
-                            byte_size
-                        } else {
-                            // TODO: report error?
-                            0
-                        }
+                    }
+                } else {
+                    "<unknown>".to_string()
+                };
+                let byte_size = if let Ok(Some(attr)) = entry.attr(gimli::DW_AT_byte_size) {
+                    if let Ok(DebugValue::Uint(byte_size)) =
+                        get_attr_value(&attr, unit, dwarf)
+                    {
+                        byte_size
                     } else {
                         // TODO: report error?
                         0
-                    };
-                    let type_offset = entry.offset().0;
-                    offset_to_type
-                        .insert(type_offset, Type::new(name, byte_size.try_into().unwrap()));
+                    }
+                } else {
+                    // TODO: report error?
+                    0
+                };
+                let type_offset = section_offset(entry.offset(), unit);
+                offset_to_type
+                    .insert(type_offset, Type::new(name, byte_size.try_into().unwrap()));
+            }
+            gimli::DW_TAG_pointer_type
+            | gimli::DW_TAG_structure_type
+            | gimli::DW_TAG_union_type
+            | gimli::DW_TAG_array_type
+            | gimli::DW_TAG_enumeration_type
+            | gimli::DW_TAG_typedef
+            | gimli::DW_TAG_const_type
+            | gimli::DW_TAG_volatile_type
+            | gimli::DW_TAG_restrict_type
+            | gimli::DW_TAG_subroutine_type => {
+                let attrs = get_die_attrs(entry, unit, dwarf)?;
+                let tag_name = |keyword: &str| match &attrs.name {
+                    Some(name) => format!("{} {}", keyword, name),
+                    None => format!("{} {{...}}", keyword),
+                };
+                // Names of derived types are filled in by resolve_types
+                let (name, kind) = match entry.tag() {
+                    gimli::DW_TAG_pointer_type => {
+                        (String::new(), TypeKind::Pointer(attrs.type_offset))
+                    }
+                    gimli::DW_TAG_structure_type => {
+                        (tag_name("struct"), TypeKind::Struct(Vec::new()))
+                    }
+                    gimli::DW_TAG_union_type => {
+                        (tag_name("union"), TypeKind::Struct(Vec::new()))
+                    }
+                    gimli::DW_TAG_array_type => match attrs.type_offset {
+                        Some(elem) => (String::new(), TypeKind::Array(elem, Vec::new())),
+                        None => continue,
+                    },
+                    gimli::DW_TAG_enumeration_type => {
+                        (tag_name("enum"), TypeKind::Enum(enum_is_signed(entry, unit)?, Vec::new()))
+                    }
+                    gimli::DW_TAG_typedef => {
+                        let name = attrs.name.clone().unwrap_or_default();
+                        (name, TypeKind::Alias(attrs.type_offset))
+                    }
+                    gimli::DW_TAG_const_type => {
+                        ("const".to_string(), TypeKind::Alias(attrs.type_offset))
+                    }
+                    gimli::DW_TAG_volatile_type => {
+                        ("volatile".to_string(), TypeKind::Alias(attrs.type_offset))
+                    }
+                    gimli::DW_TAG_restrict_type => {
+                        ("restrict".to_string(), TypeKind::Alias(attrs.type_offset))
+                    }
+                    _ => ("<function>".to_string(), TypeKind::Function),
+                };
+                let type_offset = section_offset(entry.offset(), unit);
+                match kind {
+                    TypeKind::Struct(_) | TypeKind::Array(..) | TypeKind::Enum(..) => {
+                        aggregates.push((depth, type_offset))
+                    }
+                    _ => {}
                 }
-                gimli::DW_TAG_subprogram => {
-                    let mut func: Function = Default::default();
-                    let mut attrs = entry.attrs();
-                    while let Some(attr) = attrs.next()? {
-                        let val = get_attr_value(&attr, &unit, &dwarf);
-                        //println!("   {}: {:?}", attr.name(), val);
-                        match attr.name() {
-                            gimli::DW_AT_name => {
-                                if let Ok(DebugValue::Str(name)) = val {
-                                    func.name = name;
-                                }
+                let size = attrs.byte_size.unwrap_or(0);
+                offset_to_type.insert(type_offset, Type { name, size, kind });
+            }
+            gimli::DW_TAG_member | gimli::DW_TAG_subrange_type | gimli::DW_TAG_enumerator => {
+                let parent = match aggregates.last() {
+                    Some(&(parent_depth, parent)) if parent_depth == depth - 1 => parent,
+                    _ => continue,
+                };
+                let attrs = get_die_attrs(entry, unit, dwarf)?;
+                let parent_size = offset_to_type[&parent].size;
+                match (entry.tag(), &mut offset_to_type.get_mut(&parent).unwrap().kind) {
+                    (gimli::DW_TAG_member, TypeKind::Struct(members)) => {
+                        if let Some(type_offset) = attrs.type_offset {
+                            members.push(Member {
+                                name: attrs.name.unwrap_or_default(),
+                                type_offset,
+                                offset: attrs.member_offset.unwrap_or(0),
+                            });
+                        }
+                    }
+                    (gimli::DW_TAG_subrange_type, TypeKind::Array(_, dims)) => {
+                        let count = attrs.count.or(attrs.upper_bound.map(|bound| bound + 1));
+                        dims.push(count.unwrap_or(0).max(0) as usize);
+                    }
+                    (gimli::DW_TAG_enumerator, TypeKind::Enum(signed, values)) => {
+                        let name = attrs.name.unwrap_or_default();
+                        let mut value = attrs.const_value.unwrap_or(0);
+                        // Constants in data forms are read zero-extended, which is right for the
+                        // compact forms compilers use for positive values. One as wide as a
+                        // signed enum holds its bit pattern, which may be negative.
+                        let form_size = match entry.attr_value(gimli::DW_AT_const_value)? {
+                            Some(gimli::AttributeValue::Data1(_)) => 1,
+                            Some(gimli::AttributeValue::Data2(_)) => 2,
+                            Some(gimli::AttributeValue::Data4(_)) => 4,
+                            _ => 0,
+                        };
+                        if *signed && form_size == parent_size {
+                            let shift = 64 - 8 * form_size;
+                            value = (value << shift) >> shift;
+                        }
+                        values.push((name, value));
+                    }
+                    _ => {}
+                }
+            }
+            gimli::DW_TAG_subprogram => {
+                let mut func: Function = Default::default();
+                let mut attrs = entry.attrs();
+                while let Some(attr) = attrs.next()? {
+                    let val = get_attr_value(&attr, unit, dwarf);
+                    //println!("   {}: {:?}", attr.name(), val);
+                    match attr.name() {
+                        gimli::DW_AT_name => {
+                            if let Ok(DebugValue::Str(name)) = val {
+                                func.name = name;
                             }
-                            gimli::DW_AT_high_pc => {
-                                if let Ok(DebugValue::Uint(high_pc)) = val {
-                                    func.text_length = high_pc.try_into().unwrap();
-                                }
+                        }
+                        gimli::DW_AT_high_pc => {
+                            if let Ok(DebugValue::Uint(high_pc)) = val {
+                                func.text_length = high_pc.try_into().unwrap();
                             }
-                            gimli::DW_AT_low_pc => {
-                                //println!("low pc {:?}", attr.value());
-                                if let Ok(DebugValue::Uint(low_pc)) = val {
-                                    func.address = low_pc.try_into().unwrap();
-                                }
+                        }
+                        gimli::DW_AT_low_pc => {
+                            //println!("low pc {:?}", attr.value());
+                            if let Ok(DebugValue::Uint(low_pc)) = val {
+                                func.address = low_pc.try_into().unwrap();
                             }
-                            gimli::DW_AT_decl_line => {
-                                if let Ok(DebugValue::Uint(line_number)) = val {
-                                    func.line_number = line_number.try_into().unwrap();
-                                }
+                        }
+                        gimli::DW_AT_decl_line => {
+                            if let Ok(DebugValue::Uint(line_number)) = val {
+                                func.line_number = line_number.try_into().unwrap();
                             }
-                            _ => {}
                         }
+                        gimli::DW_AT_type => {
+                            if let Ok(DebugValue::Size(offset)) = val {
+                                func.return_type = Some(offset);
+                            }
+                        }
+                        _ => {}
                     }
-                    compilation_units.last_mut().unwrap().functions.push(func);
                 }
-                gimli::DW_TAG_formal_parameter | gimli::DW_TAG_variable => {
-                    let mut name = String::new();
-                    let mut entity_type: Option<Type> = None;
-                    let mut location: Option<Location> = None;
-                    let mut line_number = 0;
-                    let mut attrs = entry.attrs();
-                    while let Some(attr) = attrs.next()? {
-                        let val = get_attr_value(&attr, &unit, &dwarf);
-                        //println!("   {}: {:?}", attr.name(), val);
-                        match attr.name() {
-                            gimli::DW_AT_name => {
-                                if let Ok(DebugValue::Str(attr_name)) = val {
-                                    name = attr_name;
-                                }
+                compilation_units.last_mut().unwrap().functions.push(func);
+                function_depth = Some(depth);
+            }
+            gimli::DW_TAG_formal_parameter | gimli::DW_TAG_variable => {
+                let mut name = String::new();
+                let mut type_offset: Option<usize> = None;
+                let mut location: Option<Location> = None;
+                let mut line_number = 0;
+                let mut attrs = entry.attrs();
+                while let Some(attr) = attrs.next()? {
+                    let val = get_attr_value(&attr, unit, dwarf);
+                    //println!("   {}: {:?}", attr.name(), val);
+                    match attr.name() {
+                        gimli::DW_AT_name => {
+                            if let Ok(DebugValue::Str(attr_name)) = val {
+                                name = attr_name;
                             }
-                            gimli::DW_AT_type => {
-                                if let Ok(DebugValue::Size(offset)) = val {
-                                    if let Some(dtype) = offset_to_type.get(&offset).clone() {
-                                        entity_type = Some(dtype.clone());
-                                    }
-                                }
+                        }
+                        gimli::DW_AT_type => {
+                            // The type may be defined later on, so it is looked up once
+                            // all types are loaded
+                            if let Ok(DebugValue::Size(offset)) = val {
+                                type_offset = Some(offset);
                             }
-                            gimli::DW_AT_location => {
-                                if let Some(loc) = get_location(&attr, &unit) {
-                                    location = Some(loc);
-                                }
+                        }
+                        gimli::DW_AT_location => {
+                            if let Some(loc) = get_location(&attr, unit, dwarf) {
+                                location = Some(loc);
                             }
-                            gimli::DW_AT_decl_line => {
-                                if let Ok(DebugValue::Uint(num)) = val {
-                                    line_number = num;
-                                }
+                        }
+                        gimli::DW_AT_decl_line => {
+                            if let Ok(DebugValue::Uint(num)) = val {
+                                line_number = num;
                             }
-                            _ => {}
                         }
+                        _ => {}
                     }
-                    if entity_type.is_some() && location.is_some() {
-                        let var = Variable {
-                            name,
-                            entity_type: entity_type.unwrap(),
-                            location: location.unwrap(),
-                            line_number: line_number.try_into().unwrap(),
-                        };
-                        if depth == 1 {
-                            compilation_units
-                                .last_mut()
-                                .unwrap()
-                                .global_variables
-                                .push(var);
-                        } else if depth > 1 {
-                            compilation_units
-                                .last_mut()
-                                .unwrap()
-                                .functions
-                                .last_mut()
-                                .unwrap()
-                                .variables
-                                .push(var);
-                        }
+                }
+                let is_parameter = entry.tag() == gimli::DW_TAG_formal_parameter;
+                if let (true, Some(function_depth), Some(offset)) =
+                    (is_parameter, function_depth, type_offset)
+                {
+                    if depth == function_depth + 1 {
+                        let functions = &mut compilation_units.last_mut().unwrap().functions;
+                        functions.last_mut().unwrap().parameters.push(offset);
+                    }
+                }
+                if type_offset.is_some() && location.is_some() {
+                    let var = Variable {
+                        name,
+                        entity_type: Type::default(),
+                        type_offset: type_offset.unwrap(),
+                        location: location.unwrap(),
+                        line_number: line_number.try_into().unwrap(),
+                    };
+                    if depth == 1 {
+                        compilation_units
+                            .last_mut()
+                            .unwrap()
+                            .global_variables
+                            .push(var);
+                    } else if depth > 1 {
+                        compilation_units
+                            .last_mut()
+                            .unwrap()
+                            .functions
+                            .last_mut()
+                            .unwrap()
+                            .variables
+                            .push(var);
                     }
                 }
-                // NOTE: :You may consider supporting other types by extending this
-                // match statement
-                _ => {}
             }
+            // NOTE: :You may consider supporting other types by extending this
+            // match statement
+            _ => {}
         }
+    }
+    Ok(())
+}
 
-        // Get line numbers
-        if let Some(program) = unit.line_program.clone() {
-            // Iterate over the line program rows.
-            let mut rows = program.rows();
-            while let Some((header, row)) = rows.next_row()? {
-                if !row.end_sequence() {
-                    // Determine the path. Real applications should cache this for performance.
-                    let mut path = path::PathBuf::new();
-                    if let Some(file) = row.file(header) {
-                        if let Some(dir) = file.directory(header) {
-                            path.push(dwarf.attr_string(&unit, dir)?.to_string_lossy().as_ref());
-                        }
-                        path.push(
-                            dwarf
-                                .attr_string(&unit, file.path_name())?
-                                .to_string_lossy()
-                                .as_ref(),
-                        );
+/// Loads the line numbers of the code of a compilation unit into the `File` of its source file.
+fn load_lines<R: Reader>(
+    unit: &gimli::Unit<R>,
+    dwarf: &gimli::Dwarf<R>,
+    compilation_units: &mut Vec<File>,
+) -> Result<(), Error> {
+    let comp_dir = match &unit.comp_dir {
+        Some(dir) => path::PathBuf::from(dir.to_string_lossy()?.as_ref()),
+        None => path::PathBuf::new(),
+    };
+    // Get line numbers
+    if let Some(program) = unit.line_program.clone() {
+        // Iterate over the line program rows.
+        let mut rows = program.rows();
+        while let Some((header, row)) = rows.next_row()? {
+            if !row.end_sequence() {
+                // Determine the path. Real applications should cache this for performance.
+                let mut path = path::PathBuf::new();
+                if let Some(file) = row.file(header) {
+                    if let Some(dir) = file.directory(header) {
+                        path.push(dwarf.attr_string(unit, dir)?.to_string_lossy()?.as_ref());
                     }
+                    path.push(
+                        dwarf
+                            .attr_string(unit, file.path_name())?
+                            .to_string_lossy()?
+                            .as_ref(),
+                    );
+                }
+
+                // Get the File; the name of a unit compiled from a relative path may be
+                // relative to the compilation directory
+                let file = compilation_units.iter_mut().find(|f| {
+                    f.name == path.as_os_str().to_str().unwrap() || comp_dir.join(&f.name) == path
+                });
 
-                    // Get the File
-                    let file = compilation_units
-                        .iter_mut()
-                        .find(|f| f.name == path.as_os_str().to_str().unwrap());
-
-                    // Determine line/column. DWARF line/column is never 0, so we use that
-                    // but other applications may want to display this differently.
-                    let line = row.line().unwrap_or(0);
-
-                    if let Some(file) = file {
-                        file.lines.push(Line {
-                            file: file.name.clone(),
-                            number: line.try_into().unwrap(),
-                            address: row.address().try_into().unwrap(),
-                        });
+                // Determine line/column. DWARF line/column is never 0, so we use that
+                // but other applications may want to display this differently.
+                let line = row.line().unwrap_or(0);
+
+                if let Some(file) = file {
+                    file.lines.push(Line {
+                        file: file.name.clone(),
+                        number: line.try_into().unwrap(),
+                        address: row.address().try_into().unwrap(),
+                    });
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The skeleton of a unit compiled with -gsplit-dwarf, which says where the rest of it is.
+struct SplitUnit {
+    /// The .dwo file, as given by DW_AT_GNU_dwo_name (under DW_AT_comp_dir if relative)
+    path: path::PathBuf,
+    dwo_id: u64,
+    /// Start of the unit's addresses in .debug_addr, which the .dwo refers to by index
+    addr_base: gimli::DebugAddrBase<usize>,
+}
+
+/// Returns the split unit `unit` is the skeleton of, if it is one.
+fn get_split_unit<R: Reader>(
+    unit: &gimli::Unit<R>,
+    dwarf: &gimli::Dwarf<R>,
+) -> Result<Option<SplitUnit>, Error> {
+    let mut entries = unit.entries();
+    let root = match entries.next_dfs()? {
+        Some((_, root)) => root,
+        None => return Ok(None),
+    };
+    let dwo_name = match root.attr_value(gimli::DW_AT_GNU_dwo_name)? {
+        Some(value) => dwarf.attr_string(unit, value)?.to_string_lossy()?.into_owned(),
+        None => return Ok(None),
+    };
+    let mut path = path::PathBuf::new();
+    if let Some(comp_dir) = &unit.comp_dir {
+        path.push(comp_dir.to_string_lossy()?.as_ref());
+    }
+    path.push(dwo_name);
+    let dwo_id = match root.attr_value(gimli::DW_AT_GNU_dwo_id)? {
+        Some(gimli::AttributeValue::Data8(dwo_id)) => dwo_id,
+        _ => 0,
+    };
+    let addr_base = match root.attr_value(gimli::DW_AT_GNU_addr_base)? {
+        Some(gimli::AttributeValue::SecOffset(offset)) => offset,
+        _ => 0,
+    };
+    Ok(Some(SplitUnit { path, dwo_id, addr_base: gimli::DebugAddrBase(addr_base) }))
+}
+
+/// Loads the files and types of a split unit from the DWARF package, or from its .dwo file.
+/// Also returns the size of the .debug_info.dwo they come from. Returns None if neither has the
+/// unit.
+fn load_split_unit(
+    split: &SplitUnit,
+    dwarf: &gimli::Dwarf<gimli::EndianSlice<gimli::RunTimeEndian>>,
+    package: Option<&Package>,
+    endian: gimli::RunTimeEndian,
+) -> Result<Option<(Vec<File>, HashMap<usize, Type>, usize)>, Error> {
+    let sections = match package.and_then(|package| package.sections(split.dwo_id)) {
+        Some(sections) => sections,
+        None => match dwo_sections(&split.path) {
+            Some(sections) => sections,
+            None => return Ok(None),
+        },
+    };
+    let load_section = |id: gimli::SectionId| -> Result<borrow::Cow<[u8]>, gimli::Error> {
+        let data = id.dwo_name().and_then(|name| sections.get(name));
+        Ok(borrow::Cow::Borrowed(data.map_or(&[][..], |data| &data[..])))
+    };
+    let load_section_sup = |_| Ok(borrow::Cow::Borrowed(&[][..]));
+    let dwo_cow = gimli::Dwarf::load(&load_section, &load_section_sup)?;
+    let borrow_section: &dyn for<'a> Fn(
+        &'a borrow::Cow<[u8]>,
+    ) -> gimli::EndianSlice<'a, gimli::RunTimeEndian> =
+        &|section| gimli::EndianSlice::new(&*section, endian);
+    let mut dwo = dwo_cow.borrow(&borrow_section);
+    // Addresses stay in the executable, so that the .dwo doesn't need relocating
+    dwo.debug_addr = dwarf.debug_addr;
+
+    let mut files = Vec::new();
+    let mut types = HashMap::new();
+    let mut iter = dwo.units();
+    while let Some(header) = iter.next()? {
+        let mut unit = dwo.unit(header)?;
+        unit.addr_base = split.addr_base;
+        load_entries(&unit, &dwo, &mut files, &mut types)?;
+    }
+    let size = sections.get(".debug_info.dwo").map_or(0, |data| data.len());
+    Ok(Some((files, types, size)))
+}
+
+/// The split DWARF sections deet reads, from a .dwo file or a DWARF package.
+const DWO_SECTIONS: &[&str] = &[
+    ".debug_info.dwo",
+    ".debug_abbrev.dwo",
+    ".debug_str.dwo",
+    ".debug_str_offsets.dwo",
+    ".debug_line.dwo",
+    ".debug_cu_index",
+];
+
+/// Reads the split DWARF sections of the ELF file at `path`, by name.
+fn dwo_sections(path: &path::Path) -> Option<HashMap<&'static str, Vec<u8>>> {
+    let file = fs::File::open(path).ok()?;
+    let mmap = unsafe { memmap::Mmap::map(&file) }.ok()?;
+    let object = object::File::parse(&*mmap).ok()?;
+    let sections = DWO_SECTIONS
+        .iter()
+        .filter_map(|name| Some((*name, object.section_data_by_name(name)?.into_owned())))
+        .collect();
+    Some(sections)
+}
+
+/// A DWARF package, in which `dwp` combines the .dwo files of a program.
+struct Package {
+    sections: HashMap<&'static str, Vec<u8>>,
+}
+
+impl Package {
+    fn open(path: &str) -> Option<Package> {
+        let sections = dwo_sections(path::Path::new(path))?;
+        if !sections.contains_key(".debug_cu_index") {
+            return None;
+        }
+        Some(Package { sections })
+    }
+
+    /// Returns the parts of the package's sections that belong to the unit `dwo_id`, as listed
+    /// by .debug_cu_index.
+    fn sections(&self, dwo_id: u64) -> Option<HashMap<&'static str, Vec<u8>>> {
+        let index = &self.sections[".debug_cu_index"];
+        let word = |offset: usize| -> Option<usize> {
+            Some(u32::from_le_bytes(index.get(offset..offset + 4)?.try_into().unwrap()) as usize)
+        };
+        // The GNU (version 2) and DWARF 5 headers only differ in the width of the version
+        let (columns, units, slots) = (word(4)?, word(8)?, word(12)?);
+        // Hash table of unit IDs, then the row of each in the offset and size tables
+        let rows = 16 + 8 * slots;
+        let slot = (0..slots).find(|slot| {
+            let id = index.get(16 + 8 * slot..24 + 8 * slot);
+            id.map(|id| u64::from_le_bytes(id.try_into().unwrap())) == Some(dwo_id)
+                && word(rows + 4 * slot) != Some(0)
+        })?;
+        let row = word(rows + 4 * slot)?;
+        // The offset table starts with the section of each column, so its rows count from 1
+        let offsets = rows + 4 * slots;
+        let sizes = offsets + 4 * columns * (units + 1);
+        let mut sections = HashMap::new();
+        // Strings are shared by all units
+        sections.insert(".debug_str.dwo", self.sections.get(".debug_str.dwo")?.clone());
+        for column in 0..columns {
+            let name = match word(offsets + 4 * column)? {
+                1 => ".debug_info.dwo",
+                3 => ".debug_abbrev.dwo",
+                4 => ".debug_line.dwo",
+                6 => ".debug_str_offsets.dwo",
+                _ => continue,
+            };
+            let start = word(offsets + 4 * (row * columns + column))?;
+            let size = word(sizes + 4 * ((row - 1) * columns + column))?;
+            sections.insert(name, self.sections.get(name)?.get(start..start + size)?.to_vec());
+        }
+        Some(sections)
+    }
+}
+
+/// Moves the types loaded from a .dwo file, and the references to them, `base` further into
+/// the offsets that identify types.
+fn rebase(files: &mut [File], types: HashMap<usize, Type>, base: usize) -> HashMap<usize, Type> {
+    for file in files.iter_mut() {
+        for var in &mut file.global_variables {
+            var.type_offset += base;
+        }
+        for func in &mut file.functions {
+            func.return_type = func.return_type.map(|offset| offset + base);
+            for offset in &mut func.parameters {
+                *offset += base;
+            }
+            for var in &mut func.variables {
+                var.type_offset += base;
+            }
+        }
+    }
+    types
+        .into_iter()
+        .map(|(offset, mut dtype)| {
+            match &mut dtype.kind {
+                TypeKind::Pointer(Some(target))
+                | TypeKind::Alias(Some(target))
+                | TypeKind::Array(target, _) => *target += base,
+                TypeKind::Struct(members) => {
+                    for member in members {
+                        member.type_offset += base;
                     }
                 }
+                _ => {}
+            }
+            (offset + base, dtype)
+        })
+        .collect()
+}
+
+/// Fills in the names and sizes of types derived from other types, e.g. "int *" or the size of
+/// an array.
+fn resolve_types(types: &mut HashMap<usize, Type>) {
+    let resolved: Vec<(usize, String, usize)> = types
+        .keys()
+        .map(|&offset| {
+            let (name, size) = describe_type(types, Some(offset), 0);
+            (offset, name, size)
+        })
+        .collect();
+    for (offset, name, size) in resolved {
+        let dtype = types.get_mut(&offset).unwrap();
+        dtype.name = name;
+        dtype.size = size;
+    }
+}
+
+fn describe_type(
+    types: &HashMap<usize, Type>,
+    offset: Option<usize>,
+    depth: usize,
+) -> (String, usize) {
+    // Recursive types always go through a pointer, so a deep chain means malformed DWARF
+    let dtype = match offset.and_then(|offset| types.get(&offset)) {
+        Some(dtype) if depth < 16 => dtype,
+        _ => return ("void".to_string(), 0),
+    };
+    match &dtype.kind {
+        TypeKind::Pointer(target) => {
+            let (target_name, _) = describe_type(types, *target, depth + 1);
+            let name = if target_name.ends_with('*') {
+                format!("{}*", target_name)
+            } else {
+                format!("{} *", target_name)
+            };
+            (name, if dtype.size > 0 { dtype.size } else { 8 })
+        }
+        TypeKind::Array(elem, dims) => {
+            let (elem_name, elem_size) = describe_type(types, Some(*elem), depth + 1);
+            let dims_name: String = dims.iter().map(|dim| format!("[{}]", dim)).collect();
+            (format!("{} {}", elem_name, dims_name), elem_size * dims.iter().product::<usize>())
+        }
+        TypeKind::Alias(target) => {
+            let (target_name, size) = describe_type(types, *target, depth + 1);
+            match dtype.name.as_str() {
+                "const" | "volatile" | "restrict" => {
+                    (format!("{} {}", dtype.name, target_name), size)
+                }
+                _ => (dtype.name.clone(), size),
+            }
+        }
+        _ => (dtype.name.clone(), dtype.size),
+    }
+}
+
+/// Converts the offset of a DIE within its unit to its offset in .debug_info, which is how
+/// get_attr_value reports DW_AT_type references.
+fn section_offset<R: Reader>(offset: UnitOffset, unit: &gimli::Unit<R>) -> usize {
+    match offset.to_unit_section_offset(unit) {
+        UnitSectionOffset::DebugInfoOffset(goff) => goff.0,
+        UnitSectionOffset::DebugTypesOffset(goff) => goff.0,
+    }
+}
+
+/// The attributes of type DIEs and their children that we use.
+#[derive(Default)]
+struct DieAttrs {
+    name: Option<String>,
+    byte_size: Option<usize>,
+    type_offset: Option<usize>,
+    member_offset: Option<usize>,
+    upper_bound: Option<i64>,
+    count: Option<i64>,
+    const_value: Option<i64>,
+}
+
+/// Whether the values of an enum are signed, going by the encoding of its underlying integer type.
+/// Without one, the enum is taken to be an int, as in C.
+fn enum_is_signed<R: Reader>(
+    entry: &gimli::DebuggingInformationEntry<R>,
+    unit: &gimli::Unit<R>,
+) -> Result<bool, Error> {
+    let underlying = match entry.attr_value(gimli::DW_AT_type)? {
+        Some(gimli::AttributeValue::UnitRef(offset)) => unit.entry(offset)?,
+        _ => return Ok(true),
+    };
+    Ok(match underlying.attr_value(gimli::DW_AT_encoding)? {
+        Some(gimli::AttributeValue::Encoding(encoding)) => {
+            encoding == gimli::DW_ATE_signed || encoding == gimli::DW_ATE_signed_char
+        }
+        _ => true,
+    })
+}
+
+fn get_die_attrs<R: Reader>(
+    entry: &gimli::DebuggingInformationEntry<R>,
+    unit: &gimli::Unit<R>,
+    dwarf: &gimli::Dwarf<R>,
+) -> Result<DieAttrs, Error> {
+    let mut die_attrs = DieAttrs::default();
+    let mut attrs = entry.attrs();
+    while let Some(attr) = attrs.next()? {
+        let int_val = match get_attr_value(&attr, unit, dwarf) {
+            Ok(DebugValue::Str(name)) if attr.name() == gimli::DW_AT_name => {
+                die_attrs.name = Some(name);
+                continue;
+            }
+            Ok(DebugValue::Size(offset)) if attr.name() == gimli::DW_AT_type => {
+                die_attrs.type_offset = Some(offset);
+                continue;
             }
+            Ok(DebugValue::Uint(val)) => val as i64,
+            Ok(DebugValue::Int(val)) => val,
+            _ => continue,
+        };
+        match attr.name() {
+            gimli::DW_AT_byte_size => die_attrs.byte_size = Some(int_val as usize),
+            gimli::DW_AT_data_member_location => die_attrs.member_offset = Some(int_val as usize),
+            gimli::DW_AT_upper_bound => die_attrs.upper_bound = Some(int_val),
+            gimli::DW_AT_count => die_attrs.count = Some(int_val),
+            gimli::DW_AT_const_value => die_attrs.const_value = Some(int_val),
+            _ => {}
         }
     }
-    Ok(compilation_units)
+    Ok(die_attrs)
 }
 
 #[derive(Debug, Clone)]
@@ -291,7 +858,11 @@ impl<'input, Endian> Reader for gimli::EndianSlice<'input, Endian> where
 
 trait Reader: gimli::Reader<Offset = usize> + Send + Sync {}
 
-fn get_location<R: Reader>(attr: &gimli::Attribute<R>, unit: &gimli::Unit<R>) -> Option<Location> {
+fn get_location<R: Reader>(
+    attr: &gimli::Attribute<R>,
+    unit: &gimli::Unit<R>,
+    dwarf: &gimli::Dwarf<R>,
+) -> Option<Location> {
     if let gimli::AttributeValue::Exprloc(ref data) = attr.value() {
         let encoding = unit.encoding();
         let mut pc = data.0.clone();
@@ -304,6 +875,10 @@ fn get_location<R: Reader>(attr: &gimli::Attribute<R>, unit: &gimli::Unit<R>) ->
                     gimli::Operation::Address { address } => {
                         return Some(Location::Address(address.try_into().unwrap()));
                     }
+                    gimli::Operation::AddressIndex { index } => {
+                        let address = dwarf.address(unit, index).ok()?;
+                        return Some(Location::Address(address.try_into().unwrap()));
+                    }
                     _ => {}
                 }
             }
@@ -344,9 +919,20 @@ fn get_attr_value<R: Reader>(
                 Ok(DebugValue::Str(format!("<.debug_str+0x{:08x}>", offset.0)))
             }
         }
+        // Split DWARF refers to strings and addresses by index
+        gimli::AttributeValue::DebugStrOffsetsIndex(_) => {
+            Ok(DebugValue::Str(format!("{}", dwarf.attr_string(unit, value)?.to_string_lossy()?)))
+        }
+        gimli::AttributeValue::DebugAddrIndex(index) => {
+            Ok(DebugValue::Uint(dwarf.address(unit, index)?))
+        }
         gimli::AttributeValue::Sdata(data) => Ok(DebugValue::Int(data)),
         gimli::AttributeValue::Addr(data) => Ok(DebugValue::Uint(data)),
         gimli::AttributeValue::Udata(data) => Ok(DebugValue::Uint(data)),
+        gimli::AttributeValue::Data1(data) => Ok(DebugValue::Uint(data.into())),
+        gimli::AttributeValue::Data2(data) => Ok(DebugValue::Uint(data.into())),
+        gimli::AttributeValue::Data4(data) => Ok(DebugValue::Uint(data.into())),
+        gimli::AttributeValue::Data8(data) => Ok(DebugValue::Uint(data)),
 
         gimli::AttributeValue::String(s) => {
             Ok(DebugValue::Str(format!("{}", s.to_string_lossy()?)))
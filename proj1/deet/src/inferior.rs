@@ -1,14 +1,26 @@
+use nix::errno::Errno;
+use nix::libc::user_regs_struct;
 use nix::sys::ptrace;
 use nix::sys::signal;
 use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd::Pid;
+use serde_json::json;
 use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::fs::OpenOptions;
 use std::mem::size_of;
+use std::os::unix::fs::FileExt;
 use std::os::unix::process::CommandExt;
-use std::process::Child;
-use std::process::Command;
+use std::process::{Child, ChildStderr, ChildStdout, Command, Stdio};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
 
 use crate::dwarf_data::DwarfData;
+use crate::output;
+use crate::symbols::{LibraryCache, SymbolTable};
+use crate::target::{self, Target};
 
 fn align_addr_to_word(addr: u64) -> u64 {
     addr & (-(size_of::<u64>() as i64) as u64)
@@ -28,6 +40,19 @@ pub enum Status {
     Signaled(signal::Signal),
 }
 
+impl Status {
+    /// Returns the exit status deet should exit with when the program ends with this status: the
+    /// program's own, or 128 plus the signal that killed it. A program that only stopped has
+    /// none, and gives 1 as for an error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Status::Stopped(..) => 1,
+            Status::Exited(code) => *code,
+            Status::Signaled(sig) => 128 + *sig as i32,
+        }
+    }
+}
+
 /// This function calls ptrace with PTRACE_TRACEME to enable debugging on a process. You should use
 /// pre_exec with Command to call this in the child process.
 fn child_traceme() -> Result<(), std::io::Error> {
@@ -37,24 +62,89 @@ fn child_traceme() -> Result<(), std::io::Error> {
     )))
 }
 
+/// A system call the inferior is stopped at the entry to or exit from, when tracing them.
+#[derive(Debug, Clone)]
+pub struct SyscallStop {
+    pub number: u64,
+    pub args: [u64; 6],
+    /// The return value, at exit; None at entry
+    pub result: Option<i64>,
+}
+
 #[derive(Debug)]
 pub struct Inferior {
-    child: Child,
-    pub bp_map: HashMap<u64, u8>
+    /// The process we spawned, or None for a copy forked from the program (`checkpoint`)
+    child: Option<Child>,
+    pid: Pid,
+    pub bp_map: HashMap<u64, u8>,
+    /// True between resuming the inferior and reaping its next state change with waitpid.
+    running: bool,
+    /// Resume with PTRACE_SYSCALL rather than PTRACE_CONT, stopping at every system call entry
+    /// and exit.
+    pub trace_syscalls: bool,
+    /// True between a system call entry stop and the matching exit stop.
+    in_syscall: bool,
+    /// Set while the inferior is stopped at a system call entry or exit.
+    syscall_stop: Option<SyscallStop>,
+    /// Symbol tables of the shared libraries it has loaded
+    libraries: LibraryCache,
 }
 
 impl Inferior {
     /// Attempts to start a new inferior process. Returns Some(Inferior) if successful, or None if
     /// an error is encountered.
     pub fn new(target: &str, args: &Vec<String>, breaks: &Vec<u64>) -> Option<Inferior> {
+        Inferior::spawn(target, args, breaks, false)
+    }
+
+    /// Like `new`, for the command line tools such as `--strace`, printing an error to stderr if
+    /// the program can't be started.
+    pub fn start(target: &str, args: &Vec<String>, breaks: &Vec<u64>) -> Option<Inferior> {
+        let inferior = Inferior::new(target, args, breaks);
+        if inferior.is_none() {
+            eprintln!("Error starting subprocess");
+        }
+        inferior
+    }
+
+    /// Like `new`, but with the inferior's stdout and stderr connected to pipes, which can be
+    /// retrieved with `take_output`. Used when our own stdout carries a protocol.
+    pub fn new_with_captured_output(
+        target: &str,
+        args: &Vec<String>,
+        breaks: &Vec<u64>,
+    ) -> Option<Inferior> {
+        Inferior::spawn(target, args, breaks, true)
+    }
+
+    fn spawn(
+        target: &str,
+        args: &Vec<String>,
+        breaks: &Vec<u64>,
+        capture: bool,
+    ) -> Option<Inferior> {
         let mut cmd = Command::new(target);
         unsafe {
             cmd.args(args).pre_exec(child_traceme);
         }
+        if capture {
+            cmd.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
         let child = cmd.spawn().ok()?;
-        let mut inferior = Inferior { child , bp_map: HashMap::<u64, u8>::new() };
+        let mut inferior = Inferior {
+            pid: Pid::from_raw(child.id() as i32),
+            child: Some(child),
+            bp_map: HashMap::<u64, u8>::new(),
+            running: false,
+            trace_syscalls: false,
+            in_syscall: false,
+            syscall_stop: None,
+            libraries: LibraryCache::default(),
+        };
         match inferior.wait(None) {
             Ok(Status::Stopped(signal::SIGTRAP, _)) => {
+                // Tells system call stops apart from SIGTRAPs (WaitStatus::PtraceSyscall)
+                ptrace::setoptions(inferior.pid(), ptrace::Options::PTRACE_O_TRACESYSGOOD).ok()?;
                 for breakaddr in breaks {
                     let orig_byte = inferior.write_byte(*breakaddr, 0xcc).ok()?;
                     inferior.bp_map.insert(*breakaddr, orig_byte);
@@ -65,78 +155,441 @@ impl Inferior {
         }
     }
 
+    /// Takes the pipes connected to the inferior's stdout and stderr, if it was created with
+    /// `new_with_captured_output`.
+    pub fn take_output(&mut self) -> (Option<ChildStdout>, Option<ChildStderr>) {
+        match &mut self.child {
+            Some(child) => (child.stdout.take(), child.stderr.take()),
+            None => (None, None),
+        }
+    }
+
     /// Returns the pid of this inferior.
     pub fn pid(&self) -> Pid {
-        nix::unistd::Pid::from_raw(self.child.id() as i32)
+        self.pid
+    }
+
+    /// Returns true if the inferior has been resumed and has not been waited on since.
+    pub fn is_running(&self) -> bool {
+        self.running
     }
 
     /// Calls waitpid on this inferior and returns a Status to indicate the state of the process
     /// after the waitpid call.
-    pub fn wait(&self, options: Option<WaitPidFlag>) -> Result<Status, nix::Error> {
-        Ok(match waitpid(self.pid(), options)? {
+    pub fn wait(&mut self, options: Option<WaitPidFlag>) -> Result<Status, nix::Error> {
+        let status = waitpid(self.pid(), options)?;
+        Ok(self.status_from(status)?.expect("waitpid returned StillAlive without WNOHANG"))
+    }
+
+    /// Polls the inferior with WNOHANG, returning None if it has not changed state yet.
+    pub fn try_wait(&mut self) -> Result<Option<Status>, nix::Error> {
+        let status = waitpid(self.pid(), Some(WaitPidFlag::WNOHANG))?;
+        self.status_from(status)
+    }
+
+    /// Returns the system call the inferior is stopped at the entry to or exit from, if any.
+    pub fn syscall_stop(&self) -> Option<&SyscallStop> {
+        self.syscall_stop.as_ref()
+    }
+
+    fn status_from(&mut self, status: WaitStatus) -> Result<Option<Status>, nix::Error> {
+        self.syscall_stop = None;
+        let status = match status {
+            WaitStatus::StillAlive => return Ok(None),
             WaitStatus::Exited(_pid, exit_code) => Status::Exited(exit_code),
             WaitStatus::Signaled(_pid, signal, _core_dumped) => Status::Signaled(signal),
             WaitStatus::Stopped(_pid, signal) => {
                 let regs = ptrace::getregs(self.pid())?;
                 Status::Stopped(signal, regs.rip as usize)
             }
+            // Reported as a SIGTRAP, with the details left in syscall_stop
+            WaitStatus::PtraceSyscall(_pid) => {
+                let regs = ptrace::getregs(self.pid())?;
+                let result = if self.in_syscall { Some(regs.rax as i64) } else { None };
+                self.in_syscall = !self.in_syscall;
+                self.syscall_stop = Some(SyscallStop {
+                    number: regs.orig_rax,
+                    args: [regs.rdi, regs.rsi, regs.rdx, regs.r10, regs.r8, regs.r9],
+                    result,
+                });
+                Status::Stopped(signal::SIGTRAP, regs.rip as usize)
+            }
             other => panic!("waitpid returned unexpected status: {:?}", other),
-        })
+        };
+        self.running = false;
+        Ok(Some(status))
     }
 
-    // Continue stopped inferior and returns a Status to indicate the state of the process
-    pub fn cont(&mut self) -> Result<Status, nix::Error> {
+    /// If the inferior is stopped on one of our breakpoints, either just after trapping on it or
+    /// with rip pointing right at it, single-steps the original instruction with the breakpoint
+    /// temporarily removed and returns the status after the step. Returns None if there is no
+    /// breakpoint to step over.
+    fn step_over_breakpoint(&mut self) -> Result<Option<Status>, nix::Error> {
         let mut regs = ptrace::getregs(self.pid())?;
-        let rip = regs.rip;
-        if let Some(orig_byte) = self.bp_map.clone().get(&(rip - 1)) {  // double borrow if not clone
-            self.write_byte(rip - 1, *orig_byte)?;
-            regs.rip -= 1;
-            ptrace::setregs(self.pid(), regs)?;
-            ptrace::step(self.pid(), None)?;
-            match self.wait(None) {
-                Ok(Status::Stopped(signal::SIGTRAP, _addr)) => {
-                    self.write_byte(rip - 1, 0xcc)?;
-                }
-                others => { return others; }
+        let bp_addr = if self.bp_map.contains_key(&regs.rip.wrapping_sub(1)) {
+            regs.rip.wrapping_sub(1)
+        } else if self.bp_map.contains_key(&regs.rip) {
+            regs.rip
+        } else {
+            return Ok(None);
+        };
+        let orig_byte = self.bp_map[&bp_addr];
+        self.write_byte(bp_addr, orig_byte)?;
+        regs.rip = bp_addr;
+        ptrace::setregs(self.pid(), regs)?;
+        ptrace::step(self.pid(), None)?;
+        let status = self.wait(None)?;
+        if let Status::Stopped(_, _) = status {
+            self.write_byte(bp_addr, 0xcc)?;
+        }
+        Ok(Some(status))
+    }
+
+    /// Resumes the stopped inferior without waiting for it to stop again. If the inferior is
+    /// sitting on a breakpoint, the original instruction is stepped over first; should that
+    /// single step end in anything but a SIGTRAP, the resulting status is returned instead.
+    /// `signal`, if given, is delivered to the inferior as it resumes.
+    pub fn resume(&mut self, signal: Option<signal::Signal>) -> Result<Option<Status>, nix::Error> {
+        match self.step_over_breakpoint()? {
+            None | Some(Status::Stopped(signal::SIGTRAP, _)) => {}
+            Some(status) => return Ok(Some(status)),
+        }
+        if self.trace_syscalls {
+            ptrace::syscall(self.pid(), signal)?;
+        } else {
+            // A system call we were stopped in the entry of completes without another stop
+            self.in_syscall = false;
+            ptrace::cont(self.pid(), signal)?;
+        }
+        self.running = true;
+        Ok(None)
+    }
+
+    /// Runs the inferior until it exits or is killed, for the command line tools. `on_stop` is
+    /// called with the signal and instruction pointer of every stop, and returns the signal to
+    /// deliver as the inferior resumes. Returns how the inferior ended, or the first error.
+    pub fn run_to_exit<F>(&mut self, mut on_stop: F) -> Result<Status, String>
+    where
+        F: FnMut(&mut Inferior, signal::Signal, usize) -> Result<Option<signal::Signal>, String>,
+    {
+        let mut signal = None;
+        loop {
+            let status = match self.resume(signal.take()) {
+                Ok(Some(status)) => Ok(status),
+                Ok(None) => self.wait(None),
+                Err(err) => Err(err),
+            };
+            match status.map_err(|err| err.to_string())? {
+                Status::Stopped(sig, rip) => signal = on_stop(self, sig, rip)?,
+                status => return Ok(status),
             }
         }
-        ptrace::cont(self.pid(), None)?;
-        self.wait(None)
     }
 
-    // Kill stopped inferior and returns a Status to indicate the state of the process
-    pub fn kill(&mut self) -> Result<Status, nix::Error> {
-        self.child.kill().expect("Fail to kill inferior process");
+    /// Executes a single machine instruction, stepping over a breakpoint if stopped on one.
+    pub fn step_instruction(&mut self) -> Result<Status, nix::Error> {
+        if let Some(status) = self.step_over_breakpoint()? {
+            return Ok(status);
+        }
+        ptrace::step(self.pid(), None)?;
         self.wait(None)
     }
 
-    pub fn print_backtrace(&self, debug_data: &DwarfData) -> Result<(), nix::Error> {
+    /// Continues until execution reaches `addr`, using a temporary breakpoint there unless a
+    /// user breakpoint already exists. On arrival, rip points at `addr` itself.
+    fn run_to(&mut self, addr: u64) -> Result<Status, nix::Error> {
+        let temporary = !self.bp_map.contains_key(&addr);
+        if temporary {
+            let orig_byte = self.write_byte(addr, 0xcc)?;
+            self.bp_map.insert(addr, orig_byte);
+        }
+        // Stepping doesn't stop at system calls
+        self.trace_syscalls = false;
+        let status = self.cont();
+        if temporary {
+            let orig_byte = self.bp_map.remove(&addr).unwrap();
+            if let Ok(Status::Stopped(..)) = status {
+                self.write_byte(addr, orig_byte)?;
+            }
+        }
+        if let Ok(Status::Stopped(signal::SIGTRAP, rip)) = status {
+            if rip as u64 == addr + 1 && temporary {
+                let mut regs = ptrace::getregs(self.pid())?;
+                regs.rip = addr;
+                ptrace::setregs(self.pid(), regs)?;
+                return Ok(Status::Stopped(signal::SIGTRAP, addr as usize));
+            }
+        }
+        status
+    }
+
+    /// Runs until the current function returns to its caller (`finish`).
+    pub fn finish(&mut self) -> Result<Status, nix::Error> {
+        let rbp = ptrace::getregs(self.pid())?.rbp;
+        let return_addr = self.read_word(rbp + 8)?;
+        self.run_to(return_addr)
+    }
+
+    /// Steps until execution reaches a different source line. Calls into code without debugging
+    /// info (libc, PLT stubs) are always run to completion; calls into other functions are run
+    /// to completion too if `over_calls` is set (`next`), and stepped into otherwise (`step`).
+    pub fn step_line(
+        &mut self,
+        debug_data: &DwarfData,
+        over_calls: bool,
+    ) -> Result<Status, nix::Error> {
         let regs = ptrace::getregs(self.pid())?;
-        let mut rip = regs.rip as usize;
-        let mut rbp = regs.rbp as usize;
-        println!("%rip register: {:#x}", rip);
+        // After a breakpoint trap, rip is one past the breakpoint
+        let pc = if self.bp_map.contains_key(&regs.rip.wrapping_sub(1)) {
+            regs.rip - 1
+        } else {
+            regs.rip
+        };
+        let line_of = |addr| debug_data.get_line_from_addr(addr).map(|l| (l.file, l.number));
+        let start_line = line_of(pc as usize);
+        // Identifies the frame we're stepping in, so that a recursive call of the same function
+        // isn't mistaken for it
+        let start_rsp = regs.rsp;
         loop {
-          let func = debug_data.get_function_from_addr(rip).unwrap();
-          let line = debug_data.get_line_from_addr(rip).unwrap();
-          println!("{} ({})", func, line);
-          if func == "main" {
-              break;
-          }
-          rip = ptrace::read(self.pid(), (rbp + 8) as ptrace::AddressType)? as usize;
-          rbp = ptrace::read(self.pid(), rbp as ptrace::AddressType)? as usize;
+            let mut status = self.step_instruction()?;
+            let mut rip = match status {
+                Status::Stopped(signal::SIGTRAP, rip) => rip,
+                _ => return Ok(status),
+            };
+            let function = debug_data.get_function_at(rip);
+            let entered_call = match function {
+                // We only ever arrive at a function's first instruction by calling it, which
+                // pushes the return address below our frame
+                Some(func) => func.address == rip && ptrace::getregs(self.pid())?.rsp < start_rsp,
+                None => true,
+            };
+            if entered_call && (over_calls || function.is_none()) {
+                // At the first instruction of the callee, the return address is on top of the
+                // stack
+                let callee_rsp = ptrace::getregs(self.pid())?.rsp;
+                let return_addr = self.read_word(callee_rsp)?;
+                loop {
+                    status = self.run_to(return_addr)?;
+                    match status {
+                        // A deeper call of a recursive function returning to the same address
+                        Status::Stopped(signal::SIGTRAP, addr)
+                            if addr as u64 == return_addr
+                                && ptrace::getregs(self.pid())?.rsp <= callee_rsp => {}
+                        Status::Stopped(signal::SIGTRAP, addr) if addr as u64 == return_addr => {
+                            rip = addr;
+                            break;
+                        }
+                        // Stopped at a user breakpoint inside the call, or the process ended
+                        _ => return Ok(status),
+                    }
+                }
+            }
+            let line = line_of(rip);
+            // Only stop at the start of a line, not when returning into the middle of one, and
+            // not before the prologue of a function we stepped into has set up its frame
+            let at_entry = debug_data.get_function_at(rip).map_or(false, |f| f.address == rip);
+            if line.is_some() && line != start_line && debug_data.is_line_start(rip) && !at_entry {
+                return Ok(status);
+            }
         }
-        Ok(())
     }
 
-    pub fn print_stop(&self, debug_data: &DwarfData) -> Result<(), nix::Error> {
-        let rip = ptrace::getregs(self.pid())?.rip as usize;
-        let func = debug_data.get_function_from_addr(rip).unwrap();
-        let line = debug_data.get_line_from_addr(rip).unwrap();
-        println!("Stopped at {} ({})", func, line);
-        Ok(())
+    /// Pops the frame of the current function as if it had returned right away (`return`), with
+    /// the raw bits of `value` in rax, or in xmm0 if it is floating point. The frame is found by
+    /// following rbp, allowing for a stop before the prologue has set it up; callee-saved
+    /// registers other than rbp are left as they are.
+    pub fn return_from_function(
+        &mut self,
+        debug_data: &DwarfData,
+        value: Option<(u64, bool)>,
+    ) -> Result<(), nix::Error> {
+        let pid = self.pid();
+        let mut regs = ptrace::getregs(pid)?;
+        // After a breakpoint trap, rip is one past the breakpoint
+        let pc = match regs.rip.wrapping_sub(1) {
+            addr if self.bp_map.contains_key(&addr) => addr,
+            _ => regs.rip,
+        };
+        let entry = debug_data.get_function_at(pc as usize).map(|func| func.address as u64);
+        let (return_addr, caller_rbp, caller_rsp) = if entry == Some(pc) {
+            // Nothing pushed yet
+            (self.read_word(regs.rsp)?, regs.rbp, regs.rsp + 8)
+        } else if entry.is_some() && entry == pc.checked_sub(1) {
+            // Just after push %rbp
+            (self.read_word(regs.rsp + 8)?, self.read_word(regs.rsp)?, regs.rsp + 16)
+        } else {
+            (self.read_word(regs.rbp + 8)?, self.read_word(regs.rbp)?, regs.rbp + 16)
+        };
+        match value {
+            Some((bits, false)) => regs.rax = bits,
+            Some((bits, true)) => {
+                let mut fpregs = get_fpregs(pid)?;
+                fpregs.xmm_space[..2].copy_from_slice(&[bits as u32, (bits >> 32) as u32]);
+                set_fpregs(pid, &fpregs)?;
+            }
+            None => {}
+        }
+        regs.rip = return_addr;
+        regs.rbp = caller_rbp;
+        regs.rsp = caller_rsp;
+        // Don't let the kernel restart a system call the program was stopped in
+        regs.orig_rax = u64::MAX;
+        ptrace::setregs(pid, regs)
+    }
+
+    /// Returns the siginfo of the signal the inferior is stopped by.
+    pub fn siginfo(&self) -> Result<libc::siginfo_t, nix::Error> {
+        ptrace::getsiginfo(self.pid())
+    }
+
+    /// Returns the low 64 bits of xmm0 to xmm7, where floating point arguments are passed and
+    /// returned.
+    pub fn xmm_registers(&self) -> Result<[u64; 8], nix::Error> {
+        let xmm = get_fpregs(self.pid())?.xmm_space;
+        let mut low = [0u64; 8];
+        for (i, bits) in low.iter_mut().enumerate() {
+            *bits = xmm[i * 4] as u64 | (xmm[i * 4 + 1] as u64) << 32;
+        }
+        Ok(low)
+    }
+
+    /// Moves execution to `addr` without running anything in between (`jump`).
+    pub fn set_pc(&mut self, addr: u64) -> Result<(), nix::Error> {
+        let mut regs = ptrace::getregs(self.pid())?;
+        regs.rip = addr;
+        regs.orig_rax = u64::MAX;
+        ptrace::setregs(self.pid(), regs)
     }
 
-    pub fn write_byte(&mut self, addr: u64, val: u8) -> Result<u8, nix::Error> {
+    // Continue stopped inferior and returns a Status to indicate the state of the process
+    pub fn cont(&mut self) -> Result<Status, nix::Error> {
+        match self.resume(None)? {
+            Some(status) => Ok(status),
+            None => self.wait(None),
+        }
+    }
+
+    /// Spawns a thread that announces the next state change of the running inferior as soon as it
+    /// happens, so that the prompt can stay interactive in the meantime. The thread only peeks at
+    /// the event (WNOWAIT); reaping it is left to the debugger, which also bumps `epoch` whenever
+    /// it handles an event itself so that stale announcements are suppressed.
+    pub fn spawn_stop_notifier(&self, epoch: Arc<AtomicUsize>) {
+        let pid = self.pid();
+        let expected = epoch.load(Ordering::SeqCst);
+        thread::spawn(move || {
+            let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+            let ret = unsafe {
+                libc::waitid(
+                    libc::P_PID,
+                    pid.as_raw() as libc::id_t,
+                    &mut info,
+                    libc::WEXITED | libc::WSTOPPED | libc::WNOWAIT,
+                )
+            };
+            if ret != 0 || epoch.load(Ordering::SeqCst) != expected {
+                return;
+            }
+            // The SIGCHLD flavour of siginfo_t stores si_pid, si_uid and si_status right after
+            // the 16-byte header; libc doesn't expose accessors for them.
+            #[repr(C)]
+            struct SigchldInfo {
+                si_signo: i32,
+                si_errno: i32,
+                si_code: i32,
+                _pad: i32,
+                si_pid: i32,
+                si_uid: u32,
+                si_status: i32,
+            }
+            let info = unsafe { &*(&info as *const libc::siginfo_t as *const SigchldInfo) };
+            let signal_name = |num| {
+                signal::Signal::try_from(num)
+                    .map(|sig| format!("{}", sig))
+                    .unwrap_or_else(|_| format!("signal {}", num))
+            };
+            // si_code values from <signal.h>, which this version of libc doesn't define
+            const CLD_EXITED: i32 = 1;
+            const CLD_KILLED: i32 = 2;
+            const CLD_DUMPED: i32 = 3;
+            if output::is_json() {
+                let mut record = json!({ "type": "notify", "pid": pid.as_raw() });
+                match info.si_code {
+                    CLD_EXITED => {
+                        record["event"] = json!("exited");
+                        record["exit-code"] = json!(info.si_status);
+                    }
+                    CLD_KILLED | CLD_DUMPED => {
+                        record["event"] = json!("exited-signalled");
+                        record["signal"] = json!(signal_name(info.si_status));
+                    }
+                    _ => {
+                        record["event"] = json!("stopped");
+                        record["signal"] = json!(signal_name(info.si_status));
+                    }
+                }
+                output::emit(record);
+                return;
+            }
+            match info.si_code {
+                CLD_EXITED => {
+                    println!("\n[Process {} exited with code {}]", pid, info.si_status)
+                }
+                CLD_KILLED | CLD_DUMPED => {
+                    println!("\n[Process {} exited by {}]", pid, signal_name(info.si_status))
+                }
+                _ => println!("\n[Process {} stopped with {}]", pid, signal_name(info.si_status)),
+            }
+        });
+    }
+
+    /// Stops a running inferior by sending it SIGINT, the same signal ctrl+c would deliver, and
+    /// waits for the resulting stop.
+    pub fn interrupt(&mut self) -> Result<Status, nix::Error> {
+        signal::kill(self.pid(), signal::SIGINT)?;
+        self.wait(None)
+    }
+
+    /// Lists the thread ids of the inferior along with their name and scheduler state, as read
+    /// from /proc/<pid>/task. This works whether or not the inferior is running.
+    pub fn threads(&self) -> Vec<(i32, String, String)> {
+        let mut threads = Vec::new();
+        let task_dir = format!("/proc/{}/task", self.pid());
+        if let Ok(entries) = std::fs::read_dir(&task_dir) {
+            for entry in entries.filter_map(|e| e.ok()) {
+                let tid = match entry.file_name().to_str().and_then(|s| s.parse::<i32>().ok()) {
+                    Some(tid) => tid,
+                    None => continue,
+                };
+                // /proc/<pid>/task/<tid>/stat looks like "tid (comm) S ..."; comm may contain
+                // spaces, so split around the last closing paren.
+                let stat = std::fs::read_to_string(format!("{}/{}/stat", task_dir, tid))
+                    .unwrap_or_default();
+                let (name, state) = match (stat.find('('), stat.rfind(')')) {
+                    (Some(open), Some(close)) => (
+                        stat[open + 1..close].to_string(),
+                        stat[close + 1..].split_whitespace().next().unwrap_or("?").to_string(),
+                    ),
+                    _ => ("?".to_string(), "?".to_string()),
+                };
+                threads.push((tid, name, state));
+            }
+        }
+        threads.sort();
+        threads
+    }
+
+    // Kill stopped inferior and returns a Status to indicate the state of the process
+    pub fn kill(&mut self) -> Result<Status, nix::Error> {
+        signal::kill(self.pid(), signal::SIGKILL).expect("Fail to kill inferior process");
+        self.wait(None)
+    }
+
+    pub fn write_byte(&self, addr: u64, val: u8) -> Result<u8, nix::Error> {
+        if self.running {
+            // PTRACE_PEEKDATA/POKEDATA only work on a stopped tracee, but the tracer may write
+            // to /proc/<pid>/mem at any time.
+            return self.write_byte_running(addr, val);
+        }
         let aligned_addr = align_addr_to_word(addr);
         let byte_offset = addr - aligned_addr;
         let word = ptrace::read(self.pid(), aligned_addr as ptrace::AddressType)? as u64;
@@ -150,4 +603,300 @@ impl Inferior {
         )?;
         Ok(orig_byte as u8)
     }
+
+    /// Writes `bytes` to the inferior's memory. Where the range covers one of our breakpoints,
+    /// the saved original byte is updated instead so that the breakpoint stays in place.
+    pub fn write_memory(&mut self, addr: u64, bytes: &[u8]) -> Result<(), nix::Error> {
+        for (i, &byte) in bytes.iter().enumerate() {
+            let byte_addr = addr + i as u64;
+            match self.bp_map.get_mut(&byte_addr) {
+                Some(orig_byte) => *orig_byte = byte,
+                None => {
+                    self.write_byte(byte_addr, byte)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_byte_running(&self, addr: u64, val: u8) -> Result<u8, nix::Error> {
+        let to_nix = |err: std::io::Error| {
+            nix::Error::from_errno(nix::errno::Errno::from_i32(err.raw_os_error().unwrap_or(0)))
+        };
+        let mem = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(format!("/proc/{}/mem", self.pid()))
+            .map_err(to_nix)?;
+        let mut orig_byte = [0u8];
+        mem.read_exact_at(&mut orig_byte, addr).map_err(to_nix)?;
+        mem.write_all_at(&[val], addr).map_err(to_nix)?;
+        Ok(orig_byte[0])
+    }
+
+    /// Makes the stopped inferior fork a copy of itself, by running a clone system call at its
+    /// entry point. The copy is traced and stays stopped in the same state as the inferior,
+    /// breakpoints included, until it is resumed as an inferior of its own (`checkpoint`). It
+    /// is made a child of deet rather than of the program (CLONE_PARENT), so that the program
+    /// is not told when a copy exits.
+    pub fn fork_checkpoint(&mut self) -> Result<Inferior, String> {
+        let pid = self.pid();
+        let entry = self.entry_point()?;
+        let regs = ptrace::getregs(pid).map_err(|err| err.to_string())?;
+        let orig_bytes = [
+            self.write_byte(entry, 0x0f).map_err(|err| err.to_string())?,
+            self.write_byte(entry + 1, 0x05).map_err(|err| err.to_string())?,  // syscall
+        ];
+        let forked = self.run_fork(regs, entry);
+        // Put everything back, whether or not the fork worked
+        self.write_byte(entry, orig_bytes[0]).map_err(|err| err.to_string())?;
+        self.write_byte(entry + 1, orig_bytes[1]).map_err(|err| err.to_string())?;
+        ptrace::setregs(pid, regs).map_err(|err| err.to_string())?;
+        ptrace::setoptions(pid, ptrace::Options::PTRACE_O_TRACESYSGOOD)
+            .map_err(|err| err.to_string())?;
+        let copy = Inferior {
+            child: None,
+            pid: forked?,
+            bp_map: self.bp_map.clone(),
+            running: false,
+            trace_syscalls: false,
+            in_syscall: false,
+            syscall_stop: None,
+            libraries: LibraryCache::default(),
+        };
+        // The copy starts out as a fork of the patched code, running the system call
+        copy.write_byte(entry, orig_bytes[0]).map_err(|err| err.to_string())?;
+        copy.write_byte(entry + 1, orig_bytes[1]).map_err(|err| err.to_string())?;
+        ptrace::setregs(copy.pid(), regs).map_err(|err| err.to_string())?;
+        // Don't leave copies behind if we die
+        let options = ptrace::Options::PTRACE_O_TRACESYSGOOD | ptrace::Options::PTRACE_O_EXITKILL;
+        ptrace::setoptions(copy.pid(), options).map_err(|err| err.to_string())?;
+        Ok(copy)
+    }
+
+    /// Single-steps the syscall instruction at `entry` as a fork, returning the pid of the copy
+    /// once it has stopped. PTRACE_O_TRACEFORK makes the copy traced from its first instruction.
+    fn run_fork(&self, regs: user_regs_struct, entry: u64) -> Result<Pid, String> {
+        let pid = self.pid();
+        let mut fork_regs = regs;
+        fork_regs.rip = entry;
+        fork_regs.rax = libc::SYS_clone as u64;
+        fork_regs.rdi = (libc::CLONE_PARENT | libc::SIGCHLD) as u64;
+        // Same stack, no thread ids or TLS to set up
+        fork_regs.rsi = 0;
+        fork_regs.rdx = 0;
+        fork_regs.r10 = 0;
+        fork_regs.r8 = 0;
+        fork_regs.orig_rax = u64::MAX;
+        ptrace::setregs(pid, fork_regs).map_err(|err| err.to_string())?;
+        let options = ptrace::Options::PTRACE_O_TRACESYSGOOD | ptrace::Options::PTRACE_O_TRACEFORK;
+        ptrace::setoptions(pid, options).map_err(|err| err.to_string())?;
+        ptrace::step(pid, None).map_err(|err| err.to_string())?;
+        let mut copy = None;
+        loop {
+            match waitpid(pid, None).map_err(|err| err.to_string())? {
+                WaitStatus::PtraceEvent(_, _, libc::PTRACE_EVENT_FORK) => {
+                    let copy_pid = ptrace::getevent(pid).map_err(|err| err.to_string())?;
+                    copy = Some(Pid::from_raw(copy_pid as i32));
+                    ptrace::step(pid, None).map_err(|err| err.to_string())?;
+                }
+                WaitStatus::Stopped(_, signal::SIGTRAP) => break,
+                other => return Err(format!("The program stopped unexpectedly: {:?}", other)),
+            }
+        }
+        let copy = match copy {
+            Some(copy) => copy,
+            None => {
+                let result = ptrace::getregs(pid).map_err(|err| err.to_string())?.rax as i64;
+                return Err(format!("fork failed: {}", Errno::from_i32(-result as i32).desc()));
+            }
+        };
+        // A traced copy starts with a SIGSTOP, which resuming it later suppresses
+        match waitpid(copy, None).map_err(|err| err.to_string())? {
+            WaitStatus::Stopped(_, signal::SIGSTOP) => Ok(copy),
+            other => Err(format!("The forked copy stopped unexpectedly: {:?}", other)),
+        }
+    }
+
+    /// Makes the breakpoints in memory match `addrs`, adding and removing them as needed.
+    pub fn set_breakpoints(&mut self, addrs: &[u64]) -> Result<(), nix::Error> {
+        let stale: Vec<u64> =
+            self.bp_map.keys().filter(|addr| !addrs.contains(addr)).cloned().collect();
+        for addr in stale {
+            let orig_byte = self.bp_map.remove(&addr).unwrap();
+            self.write_byte(addr, orig_byte)?;
+        }
+        for addr in addrs {
+            if !self.bp_map.contains_key(addr) {
+                let orig_byte = self.write_byte(*addr, 0xcc)?;
+                self.bp_map.insert(*addr, orig_byte);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the address of the program's entry point (`_start`) from its auxiliary vector.
+    fn entry_point(&self) -> Result<u64, String> {
+        let auxv = std::fs::read(format!("/proc/{}/auxv", self.pid()))
+            .map_err(|err| format!("Cannot read auxiliary vector: {}", err))?;
+        auxv.chunks_exact(16)
+            .map(|entry| {
+                let word = |bytes: &[u8]| u64::from_le_bytes(bytes.try_into().unwrap());
+                (word(&entry[..8]), word(&entry[8..]))
+            })
+            .find(|&(key, _)| key == libc::AT_ENTRY)
+            .map(|(_, value)| value)
+            .ok_or_else(|| "Cannot find the entry point of the program".to_string())
+    }
+
+    /// Sets up the registers and stack for a call to `addr` with the given arguments, runs the
+    /// call until it returns to `return_addr` and returns rax and the low half of xmm0.
+    fn run_call(
+        &self,
+        regs: user_regs_struct,
+        fpregs: libc::user_fpregs_struct,
+        addr: u64,
+        return_addr: u64,
+        int_args: &[u64],
+        float_args: &[u64],
+    ) -> Result<(u64, u64), String> {
+        let pid = self.pid();
+        let mut regs = regs;
+        let mut fpregs = fpregs;
+        // Leave the red zone below the stack pointer alone, align the stack and push the return
+        // address, so that rsp + 8 is 16-byte aligned on entry as the ABI requires
+        let sp = ((regs.rsp - 256) & !0xf) - 8;
+        ptrace::write(pid, sp as ptrace::AddressType, return_addr as *mut std::ffi::c_void)
+            .map_err(|err| err.to_string())?;
+        let mut int_regs = [
+            &mut regs.rdi,
+            &mut regs.rsi,
+            &mut regs.rdx,
+            &mut regs.rcx,
+            &mut regs.r8,
+            &mut regs.r9,
+        ];
+        for (reg, &arg) in int_regs.iter_mut().zip(int_args) {
+            **reg = arg;
+        }
+        for (i, &arg) in float_args.iter().enumerate() {
+            fpregs.xmm_space[i * 4..i * 4 + 4]
+                .copy_from_slice(&[arg as u32, (arg >> 32) as u32, 0, 0]);
+        }
+        // Variadic functions expect the number of vector registers used in al
+        regs.rax = float_args.len() as u64;
+        regs.rsp = sp;
+        regs.rip = addr;
+        // Don't let the kernel restart a system call the program was stopped in
+        regs.orig_rax = u64::MAX;
+        ptrace::setregs(pid, regs).map_err(|err| err.to_string())?;
+        set_fpregs(pid, &fpregs).map_err(|err| err.to_string())?;
+        ptrace::cont(pid, None).map_err(|err| err.to_string())?;
+        match waitpid(pid, None).map_err(|err| err.to_string())? {
+            WaitStatus::Stopped(_, signal::SIGTRAP)
+                if ptrace::getregs(pid).map(|regs| regs.rip) == Ok(return_addr + 1) =>
+            {
+                let rax = ptrace::getregs(pid).map_err(|err| err.to_string())?.rax;
+                let xmm0 = get_fpregs(pid).map_err(|err| err.to_string())?.xmm_space;
+                Ok((rax, xmm0[0] as u64 | (xmm0[1] as u64) << 32))
+            }
+            WaitStatus::Stopped(_, signal) => Err(format!(
+                "The program being debugged was signaled while in a function called from deet \
+                 ({}). Its state has been restored.",
+                signal
+            )),
+            _ => Err("The program being debugged exited while in a function called from deet."
+                .to_string()),
+        }
+    }
+}
+
+fn get_fpregs(pid: Pid) -> Result<libc::user_fpregs_struct, nix::Error> {
+    let mut fpregs: libc::user_fpregs_struct = unsafe { std::mem::zeroed() };
+    let res = unsafe {
+        libc::ptrace(
+            libc::PTRACE_GETFPREGS,
+            pid.as_raw(),
+            std::ptr::null_mut::<libc::c_void>(),
+            &mut fpregs as *mut _ as *mut libc::c_void,
+        )
+    };
+    Errno::result(res).map(|_| fpregs)
+}
+
+fn set_fpregs(pid: Pid, fpregs: &libc::user_fpregs_struct) -> Result<(), nix::Error> {
+    let res = unsafe {
+        libc::ptrace(
+            libc::PTRACE_SETFPREGS,
+            pid.as_raw(),
+            std::ptr::null_mut::<libc::c_void>(),
+            fpregs as *const _ as *mut libc::c_void,
+        )
+    };
+    Errno::result(res).map(drop)
+}
+
+impl Target for Inferior {
+    fn regs(&self) -> Result<nix::libc::user_regs_struct, nix::Error> {
+        ptrace::getregs(self.pid())
+    }
+
+    fn library_symbols(&self) -> Vec<Rc<SymbolTable>> {
+        self.libraries.shared_libraries(self.pid())
+    }
+
+    /// Calls a function in the inferior following the System V AMD64 calling convention. The
+    /// function returns to a temporary breakpoint at the program's entry point, which is never
+    /// executed again once the program runs. Our breakpoints are lifted for the duration of the
+    /// call, and all registers are restored afterwards, even if the call fails.
+    fn call_function(&self, addr: u64, int_args: &[u64], float_args: &[u64])
+        -> Result<(u64, u64), String> {
+        if int_args.len() > 6 || float_args.len() > 8 {
+            return Err("Too many arguments in function call.".to_string());
+        }
+        let pid = self.pid();
+        let regs = ptrace::getregs(pid).map_err(|err| err.to_string())?;
+        let fpregs = get_fpregs(pid).map_err(|err| err.to_string())?;
+        let return_addr = self.entry_point()?;
+        for (&bp_addr, &orig_byte) in &self.bp_map {
+            self.write_byte(bp_addr, orig_byte).map_err(|err| err.to_string())?;
+        }
+        let result = self.write_byte(return_addr, 0xcc).map_err(|err| err.to_string()).and_then(
+            |orig_byte| {
+                let result =
+                    self.run_call(regs, fpregs, addr, return_addr, int_args, float_args);
+                self.write_byte(return_addr, orig_byte).ok();
+                result
+            },
+        );
+        for &bp_addr in self.bp_map.keys() {
+            self.write_byte(bp_addr, 0xcc).ok();
+        }
+        ptrace::setregs(pid, regs).ok();
+        set_fpregs(pid, &fpregs).ok();
+        result
+    }
+
+    fn read_memory(&self, addr: u64, len: usize) -> Result<Vec<u8>, nix::Error> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let mut bytes = Vec::with_capacity(len);
+        let mut word_addr = align_addr_to_word(addr);
+        while word_addr < addr + len as u64 {
+            let word = ptrace::read(self.pid(), word_addr as ptrace::AddressType)
+                .map_err(|_| target::unmapped())? as u64;
+            bytes.extend_from_slice(&word.to_le_bytes());
+            word_addr += size_of::<u64>() as u64;
+        }
+        let start = (addr - align_addr_to_word(addr)) as usize;
+        let mut bytes = bytes[start..start + len].to_vec();
+        // Hide our breakpoints from whoever is looking at the code
+        for (&bp_addr, &orig_byte) in &self.bp_map {
+            if addr <= bp_addr && bp_addr < addr + len as u64 {
+                bytes[(bp_addr - addr) as usize] = orig_byte;
+            }
+        }
+        Ok(bytes)
+    }
 }
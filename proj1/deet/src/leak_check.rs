@@ -0,0 +1,232 @@
+//! Heap leak checking (`--leak-check`): runs a program with breakpoints on malloc, calloc,
+//! realloc and free in libc, keeping track of every block the program allocates with the stack
+//! it was allocated from, and reports the blocks that were never freed when it exits, grouped by
+//! where they were allocated.
+//!
+//! libc is only loaded once the program starts, so the breakpoints are set when it reaches main,
+//! at the addresses of the functions in the dynamic symbol table of the libc it mapped. Only
+//! calls made directly from code with debugging information are tracked: libc allocates buffers
+//! for itself (stdio) that it never frees, and those are not the program's leaks. As a result,
+//! blocks allocated for the program by other library functions, such as strdup, are not tracked
+//! either.
+
+use std::collections::HashMap;
+
+use nix::sys::signal;
+use nix::unistd::Pid;
+
+use crate::dwarf_data::DwarfData;
+use crate::inferior::Inferior;
+use crate::symbols::{self, SymbolTable};
+use crate::target::{Frame, Target};
+
+/// The libc functions that are watched.
+const ALLOCATORS: &[&str] = &["malloc", "calloc", "realloc", "free"];
+
+/// A call to an allocation function that has not returned yet.
+struct Call {
+    allocator: &'static str,
+    size: u64,
+    /// Block given to realloc
+    old_ptr: u64,
+    /// Stack pointer once the call has returned
+    caller_rsp: u64,
+    stack: Vec<String>,
+}
+
+/// A block the program has allocated and not freed yet.
+struct Block {
+    allocator: &'static str,
+    size: u64,
+    stack: Vec<String>,
+}
+
+#[derive(Default)]
+struct Heap {
+    blocks: HashMap<u64, Block>,
+    allocs: usize,
+    frees: usize,
+    bytes: u64,
+}
+
+/// Runs `target` to completion, tracking its heap allocations, then prints the blocks it didn't
+/// free to stderr. Returns the exit status deet should exit with (see `Status::exit_code`).
+pub fn leak_check(target: &str, args: &Vec<String>) -> i32 {
+    let debug_data = match DwarfData::load_for_tool(target) {
+        Some(debug_data) => debug_data,
+        None => return 1,
+    };
+    let main = match debug_data.get_addr_for_function(None, "main") {
+        Some(addr) => addr as u64,
+        None => {
+            eprintln!("No main function in {}", target);
+            return 1;
+        }
+    };
+    let mut inferior = match Inferior::start(target, args, &vec![main]) {
+        Some(inferior) => inferior,
+        None => return 1,
+    };
+    // Allocation function at each entry breakpoint, once libc is loaded
+    let mut entries: HashMap<u64, &'static str> = HashMap::new();
+    let mut calls: Vec<Call> = Vec::new();
+    let mut heap = Heap::default();
+    let status = inferior.run_to_exit(|inferior, sig, rip| {
+        let addr = (rip as u64).wrapping_sub(1);
+        if sig != signal::SIGTRAP || !inferior.bp_map.contains_key(&addr) {
+            // Not ours
+            return Ok(Some(sig));
+        }
+        if addr == main && entries.is_empty() {
+            entries = watch_allocators(inferior)?;
+        } else {
+            match entries.get(&addr) {
+                Some(allocator) => enter(inferior, &debug_data, allocator, &mut calls, &mut heap)?,
+                None => leave(inferior, &mut calls, &mut heap).map_err(|err| err.to_string())?,
+            }
+        }
+        Ok(None)
+    });
+    let code = match status {
+        Ok(status) => status.exit_code(),
+        Err(err) => {
+            eprintln!("Error running subprocess: {}", err);
+            return 1;
+        }
+    };
+    print_report(&heap);
+    code
+}
+
+/// Sets breakpoints at the allocation functions of the libc the program has loaded, returning
+/// which function each is for.
+fn watch_allocators(inferior: &mut Inferior) -> Result<HashMap<u64, &'static str>, String> {
+    let (path, base) = find_libc(inferior.pid())?;
+    let libc = SymbolTable::from_file(&path, base)?;
+    let mut entries = HashMap::new();
+    for allocator in ALLOCATORS {
+        match libc.address_of(allocator) {
+            Some(addr) => entries.insert(addr, *allocator),
+            None => return Err(format!("Cannot find malloc and free in {}", path)),
+        };
+    }
+    for addr in entries.keys() {
+        let orig_byte = inferior.write_byte(*addr, 0xcc).map_err(|err| err.to_string())?;
+        inferior.bp_map.insert(*addr, orig_byte);
+    }
+    Ok(entries)
+}
+
+/// Returns the path of the libc mapped by process `pid` and the address it is loaded at.
+fn find_libc(pid: Pid) -> Result<(String, u64), String> {
+    symbols::mapped_files(pid)?
+        .into_iter()
+        .find(|(path, _)| {
+            let name = path.rsplit('/').next().unwrap_or("");
+            name.starts_with("libc.so") || (name.starts_with("libc-") && name.ends_with(".so"))
+        })
+        .ok_or_else(|| "The program is not dynamically linked with libc".to_string())
+}
+
+/// Handles a stop at the entry of an allocation function: frees the block given to free right
+/// away, or sets a breakpoint at the return address to see the block that was allocated.
+fn enter(
+    inferior: &mut Inferior,
+    debug_data: &DwarfData,
+    allocator: &'static str,
+    calls: &mut Vec<Call>,
+    heap: &mut Heap,
+) -> Result<(), String> {
+    let regs = inferior.regs().map_err(|err| err.to_string())?;
+    let frames = inferior.caller_frames(debug_data).map_err(|err| err.to_string())?;
+    // Called by libc itself, or by some other library
+    if frames.first().map_or(true, |frame| frame.function.is_none()) {
+        return Ok(());
+    }
+    if allocator == "free" {
+        if heap.blocks.remove(&regs.rdi).is_some() {
+            heap.frees += 1;
+        }
+        return Ok(());
+    }
+    let (size, old_ptr) = match allocator {
+        "calloc" => (regs.rdi.wrapping_mul(regs.rsi), 0),
+        "realloc" => (regs.rsi, regs.rdi),
+        _ => (regs.rdi, 0),
+    };
+    let return_addr = frames[0].rip as u64;
+    if !inferior.bp_map.contains_key(&return_addr) {
+        let orig_byte = inferior.write_byte(return_addr, 0xcc).map_err(|err| err.to_string())?;
+        inferior.bp_map.insert(return_addr, orig_byte);
+    }
+    let stack = frames.iter().map(describe_frame).collect();
+    calls.push(Call { allocator, size, old_ptr, caller_rsp: regs.rsp + 8, stack });
+    Ok(())
+}
+
+/// Handles a stop at a return address: records the block returned by the call that returned
+/// there, if any.
+fn leave(inferior: &Inferior, calls: &mut Vec<Call>, heap: &mut Heap) -> Result<(), nix::Error> {
+    let regs = inferior.regs()?;
+    // The same address is also reached by other calls from the same place
+    let index = match calls.iter().rposition(|call| call.caller_rsp == regs.rsp) {
+        Some(index) => index,
+        None => return Ok(()),
+    };
+    let call = calls.remove(index);
+    let ptr = regs.rax;
+    // realloc frees the old block if it returns a new one, or if the new size is 0
+    if call.old_ptr != 0 && (ptr != 0 || call.size == 0) {
+        if heap.blocks.remove(&call.old_ptr).is_some() {
+            heap.frees += 1;
+        }
+    }
+    if ptr != 0 {
+        heap.allocs += 1;
+        heap.bytes += call.size;
+        let block = Block { allocator: call.allocator, size: call.size, stack: call.stack };
+        heap.blocks.insert(ptr, block);
+    }
+    Ok(())
+}
+
+fn describe_frame(frame: &Frame) -> String {
+    match (&frame.function, &frame.line) {
+        (Some(func), Some(line)) => format!("{} ({})", func, line),
+        _ => format!("{:#x} (no debugging info)", frame.rip),
+    }
+}
+
+/// Prints the heap usage and the blocks that weren't freed, grouped by the stack they were
+/// allocated from, biggest first.
+fn print_report(heap: &Heap) {
+    eprintln!(
+        "Heap usage: {} allocations, {} frees, {} bytes allocated",
+        heap.allocs, heap.frees, heap.bytes
+    );
+    if heap.blocks.is_empty() {
+        eprintln!("All heap blocks were freed, no leaks are possible");
+        return;
+    }
+    // Bytes and number of blocks by allocation function and stack
+    let mut sites: HashMap<(&str, &Vec<String>), (u64, usize)> = HashMap::new();
+    for block in heap.blocks.values() {
+        let site = sites.entry((block.allocator, &block.stack)).or_insert((0, 0));
+        site.0 += block.size;
+        site.1 += 1;
+    }
+    let mut sites: Vec<_> = sites.into_iter().collect();
+    sites.sort_by(|a, b| (b.1).0.cmp(&(a.1).0).then(a.0.cmp(&b.0)));
+    for ((allocator, stack), (bytes, count)) in sites {
+        let blocks = if count == 1 { "block" } else { "blocks" };
+        eprintln!(
+            "{} bytes in {} {} not freed, allocated by {} at:",
+            bytes, count, blocks, allocator
+        );
+        for frame in stack {
+            eprintln!("    {}", frame);
+        }
+    }
+    let bytes: u64 = heap.blocks.values().map(|block| block.size).sum();
+    eprintln!("Leak summary: {} bytes in {} blocks not freed", bytes, heap.blocks.len());
+}
@@ -1,25 +1,159 @@
+mod completion;
+mod call_trace;
+mod core_file;
+mod coverage;
+mod crash;
+mod dap;
 mod debugger;
 mod debugger_command;
+mod gdbserver;
 mod inferior;
+mod leak_check;
+mod output;
+mod profile;
+mod record;
+mod symbols;
+mod syscall;
+mod target;
 
 use crate::debugger::Debugger;
 use nix::sys::signal::{signal, SigHandler, Signal};
 use std::env;
 
 mod dwarf_data;
+mod expr;
 mod gimli_wrapper;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: {} <target program>", args[0]);
+    if args.len() >= 4 && args[1] == "--gdbserver" {
+        // deet --gdbserver :PORT <target program> [args...]
+        if let Err(err) = gdbserver::serve(&args[2], &args[3], &args[4..].to_vec()) {
+            println!("gdbserver: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.len() >= 2 && args[1] == "--dap" {
+        // deet --dap [target program]; the program may also be given by the launch request
+        dap::serve(args.get(2).map(|s| s.as_str()));
+        return;
+    }
+    if args.len() >= 3 && args[1] == "--strace" {
+        // deet --strace <target program> [args...]
+        std::process::exit(syscall::strace(&args[2], &args[3..].to_vec()));
+    }
+    if args.len() >= 3 && (args[1] == "--trace-calls" || args[1].starts_with("--trace-calls=")) {
+        // deet --trace-calls[=REGEX] [--chrome-trace=FILE] <target program> [args...]
+        let pattern = value_of(&args[1], "--trace-calls=");
+        let chrome_path = value_of(&args[2], "--chrome-trace=");
+        let program = if chrome_path.is_some() { 3 } else { 2 };
+        if let Some(target) = args.get(program) {
+            let target_args = args[program + 1..].to_vec();
+            std::process::exit(call_trace::trace_calls(target, &target_args, pattern, chrome_path));
+        }
+    }
+    if args.len() >= 3 && (args[1] == "--coverage" || args[1].starts_with("--coverage=")) {
+        // deet --coverage[=FILE] <target program> [args...]
+        let output_path = value_of(&args[1], "--coverage=").unwrap_or("coverage.info");
+        std::process::exit(coverage::coverage(&args[2], &args[3..].to_vec(), output_path));
+    }
+    if args.len() >= 3 && (args[1] == "--profile" || args[1].starts_with("--profile=")) {
+        // deet --profile[=FILE] [--hz N] <target program> [args...]
+        let output_path = value_of(&args[1], "--profile=").unwrap_or("profile.folded");
+        let (hz, program) = match args[2].as_str() {
+            "--hz" => (args.get(3).and_then(|hz| hz.parse::<f64>().ok()), 4),
+            _ => (Some(100.0), 2),
+        };
+        match (hz, args.get(program)) {
+            (Some(hz), Some(target)) if hz >= profile::MIN_HZ => {
+                let target_args = args[program + 1..].to_vec();
+                std::process::exit(profile::profile(target, &target_args, hz, output_path));
+            }
+            (_, Some(_)) => {
+                println!("--hz takes a positive number of samples per second");
+                std::process::exit(1);
+            }
+            _ => {}
+        }
+    }
+    if args.len() >= 3 && args[1] == "--leak-check" {
+        // deet --leak-check <target program> [args...]
+        std::process::exit(leak_check::leak_check(&args[2], &args[3..].to_vec()));
+    }
+    // Script options run in command line order, after ~/.deetinit
+    let mut batch = false;
+    let mut load_init = true;
+    let mut scripts: Vec<(bool, String)> = Vec::new(); // (is file, file or command)
+    let mut positional: Vec<String> = Vec::new();
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--batch" | "-batch" => batch = true,
+            "-nx" | "--nx" => load_init = false,
+            "--interpreter=json" => output::enable_json(),
+            "--interpreter=console" => {}
+            opt @ "-x" | opt @ "-ex" if i + 1 < args.len() => {
+                scripts.push((opt == "-x", args[i + 1].clone()));
+                i += 1;
+            }
+            _ => positional.push(args[i].clone()),
+        }
+        i += 1;
+    }
+    if positional.len() != 1 && positional.len() != 2 {
+        println!(
+            "Usage: {} [--batch] [-nx] [--interpreter=console|json] [-x FILE]... [-ex COMMAND]...",
+            args[0]
+        );
+        println!(
+            "       {:width$} <target program> [core file]",
+            "",
+            width = args[0].len()
+        );
+        println!("       {} --gdbserver :PORT <target program> [args...]", args[0]);
+        println!("       {} --dap [target program]", args[0]);
+        println!("       {} --strace <target program> [args...]", args[0]);
+        println!(
+            "       {} --trace-calls[=REGEX] [--chrome-trace=FILE] <target program> [args...]",
+            args[0]
+        );
+        println!("       {} --coverage[=FILE] <target program> [args...]", args[0]);
+        println!("       {} --profile[=FILE] [--hz N] <target program> [args...]", args[0]);
+        println!("       {} --leak-check <target program> [args...]", args[0]);
         std::process::exit(1);
     }
-    let target = &args[1];
+    let target = &positional[0];
+    let core_path = positional.get(1).map(|s| s.as_str());
 
     // Disable handling of ctrl+c in this process (so that ctrl+c only gets delivered to child
     // processes)
     unsafe { signal(Signal::SIGINT, SigHandler::SigIgn) }.expect("Error disabling SIGINT handling");
 
-    Debugger::new(target).run();
+    let mut debugger = Debugger::new(target, core_path, batch);
+    if load_init {
+        // The init file is optional, so a missing one is not an error
+        let init_path = format!("{}/.deetinit", env::var("HOME").unwrap_or_default());
+        let _ = debugger.queue_file(&init_path);
+    }
+    for (is_file, script) in scripts {
+        if is_file {
+            if let Err(err) = debugger.queue_file(&script) {
+                println!("{}: {}", script, err);
+                std::process::exit(1);
+            }
+        } else {
+            debugger.queue_command(&script);
+        }
+    }
+    std::process::exit(debugger.run());
+}
+
+/// Returns the value of a `--name=value` option given as `prefix` ("--name="), if `arg` is one.
+fn value_of<'a>(arg: &'a str, prefix: &str) -> Option<&'a str> {
+    if arg.starts_with(prefix) {
+        Some(&arg[prefix.len()..])
+    } else {
+        None
+    }
 }
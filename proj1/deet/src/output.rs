@@ -0,0 +1,115 @@
+//! Output of the console debugger. By default this is human-readable text; with
+//! `--interpreter=json` every line deet prints is a JSON record instead, so that tools driving it
+//! don't have to scrape messages:
+//!
+//! * `{"type": "console", "text": ...}` for informational messages,
+//! * `{"type": "result", "command": ..., "class": "done" | "error", ...}` once per command, with
+//!   the structured `data` the command produced or the error `msg`,
+//! * `{"type": "stopped", "reason": ..., ...}` whenever the inferior stops or terminates,
+//! * `{"type": "notify", ...}` as soon as an inferior running in the background changes state;
+//!   the matching stopped record follows with the next command.
+
+use crate::dwarf_data::Line;
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// The result record of the command being executed.
+struct ResultRecord {
+    command: String,
+    data: Option<Value>,
+    error: Option<String>,
+}
+
+thread_local! {
+    /// Commands are only run by the main thread
+    static CURRENT: RefCell<Option<ResultRecord>> = RefCell::new(None);
+}
+
+pub fn enable_json() {
+    JSON.store(true, Ordering::SeqCst);
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::SeqCst)
+}
+
+/// Prints a JSON record on its own line.
+pub fn emit(record: Value) {
+    println!("{}", record);
+}
+
+/// Prints an informational message.
+pub fn console(text: &str) {
+    if is_json() {
+        emit(json!({ "type": "console", "text": text }));
+    } else {
+        println!("{}", text);
+    }
+}
+
+/// Reports that the current command failed. In JSON mode the first error becomes the `msg` of
+/// the command's result record.
+pub fn error(text: &str) {
+    if is_json() {
+        let collected = CURRENT.with(|current| match current.borrow_mut().as_mut() {
+            Some(current) => {
+                current.error.get_or_insert_with(|| text.to_string());
+                true
+            }
+            None => false,
+        });
+        if !collected {
+            console(text);
+        }
+    } else {
+        println!("{}", text);
+    }
+}
+
+/// Attaches structured data to the result record of the current command. Callers print the
+/// human-readable form themselves when not in JSON mode.
+pub fn set_result(data: Value) {
+    CURRENT.with(|current| {
+        if let Some(current) = current.borrow_mut().as_mut() {
+            current.data = Some(data);
+        }
+    });
+}
+
+/// Starts collecting the result of a command.
+pub fn begin(command: &str) {
+    if is_json() {
+        let record = ResultRecord { command: command.trim().to_string(), data: None, error: None };
+        CURRENT.with(|current| *current.borrow_mut() = Some(record));
+    }
+}
+
+/// Emits the result record of the current command, if one was begun.
+pub fn finish() {
+    let current = match CURRENT.with(|current| current.borrow_mut().take()) {
+        Some(current) => current,
+        None => return,
+    };
+    let mut record = json!({ "type": "result", "command": current.command });
+    match current.error {
+        Some(msg) => {
+            record["class"] = json!("error");
+            record["msg"] = json!(msg);
+        }
+        None => {
+            record["class"] = json!("done");
+            if let Some(data) = current.data {
+                record["data"] = data;
+            }
+        }
+    }
+    emit(record);
+}
+
+/// Converts a source location to its JSON form.
+pub fn line(line: &Line) -> Value {
+    json!({ "file": line.file, "number": line.number, "address": line.address })
+}
@@ -0,0 +1,138 @@
+//! A sampling profiler (`--profile`): the program is stopped at a fixed rate, its stack walked
+//! like `backtrace` does and symbolized with the debugging information. Stacks are written in
+//! the folded format of flamegraph.pl and inferno (`main;func1;func2 42`), and the functions
+//! seen most often are summarized at exit.
+//!
+//! PTRACE_INTERRUPT only works on tracees attached with PTRACE_SEIZE, while inferiors are
+//! started with PTRACE_TRACEME, so the program is stopped with a SIGSTOP that is suppressed when
+//! it resumes. Frames without debugging information show as [unknown]; the frame pointer walk
+//! may miss the callers of code compiled without frame pointers, such as libc.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::thread;
+use std::time::Duration;
+
+use nix::sys::signal::{self, Signal};
+
+use crate::dwarf_data::DwarfData;
+use crate::inferior::{Inferior, Status};
+use crate::target::Target;
+
+/// Number of functions in the summary
+const TOP_FUNCTIONS: usize = 20;
+
+/// Lowest sampling rate accepted; much lower and the interval no longer fits in a Duration
+pub const MIN_HZ: f64 = 0.001;
+
+/// Runs `target` to completion, sampling its stack `hz` times a second, then writes the folded
+/// stacks to `output_path` and prints the summary to stderr. Returns the exit status deet should
+/// exit with (see `Status::exit_code`).
+pub fn profile(target: &str, args: &Vec<String>, hz: f64, output_path: &str) -> i32 {
+    let debug_data = match DwarfData::load_for_tool(target) {
+        Some(debug_data) => debug_data,
+        None => return 1,
+    };
+    let mut inferior = match Inferior::start(target, args, &Vec::new()) {
+        Some(inferior) => inferior,
+        None => return 1,
+    };
+    let interval = Duration::from_secs_f64(1.0 / hz);
+    // Number of times each stack was seen, outermost frame first
+    let mut stacks: HashMap<Vec<String>, usize> = HashMap::new();
+    let mut signal = None;
+    // Whether we have sent a SIGSTOP that the program hasn't stopped for yet
+    let mut sampling = false;
+    let code = loop {
+        let status = match inferior.resume(signal.take()) {
+            Ok(Some(status)) => Ok(status),
+            Ok(None) => {
+                if !sampling {
+                    thread::sleep(interval);
+                    sampling = true;
+                    // The program may have exited in the meantime, which wait reports
+                    let _ = signal::kill(inferior.pid(), Signal::SIGSTOP);
+                }
+                inferior.wait(None)
+            }
+            Err(err) => Err(err),
+        };
+        let status = match status {
+            Ok(status) => status,
+            Err(err) => {
+                eprintln!("Error profiling subprocess: {}", err);
+                return 1;
+            }
+        };
+        match status {
+            Status::Stopped(Signal::SIGSTOP, _) if sampling => {
+                sampling = false;
+                if let Ok(frames) = inferior.backtrace(&debug_data) {
+                    let stack = frames
+                        .into_iter()
+                        .rev()
+                        .map(|frame| frame.function.unwrap_or_else(|| "[unknown]".to_string()))
+                        .collect();
+                    *stacks.entry(stack).or_insert(0) += 1;
+                }
+            }
+            // The program's own signal; our SIGSTOP is still on its way
+            Status::Stopped(sig, _) => signal = Some(sig),
+            status => break status.exit_code(),
+        }
+    };
+    if let Err(err) = write_folded(&stacks, output_path) {
+        eprintln!("Could not write {}: {}", output_path, err);
+        return 1;
+    }
+    print_summary(&stacks, hz, output_path);
+    code
+}
+
+fn write_folded(stacks: &HashMap<Vec<String>, usize>, output_path: &str) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(output_path)?);
+    let mut lines: Vec<(String, usize)> =
+        stacks.iter().map(|(stack, count)| (stack.join(";"), *count)).collect();
+    lines.sort();
+    for (stack, count) in lines {
+        writeln!(out, "{} {}", stack, count)?;
+    }
+    out.flush()
+}
+
+/// Prints the functions that were seen the most, with the share of samples where each was
+/// running itself (self) or anywhere on the stack (total).
+fn print_summary(stacks: &HashMap<Vec<String>, usize>, hz: f64, output_path: &str) {
+    let samples: usize = stacks.values().sum();
+    eprintln!("{} samples at {} Hz, folded stacks written to {}", samples, hz, output_path);
+    if samples == 0 {
+        return;
+    }
+    // Function name to (self, total) samples
+    let mut functions: HashMap<&str, (usize, usize)> = HashMap::new();
+    for (stack, count) in stacks {
+        let mut seen: Vec<&str> = Vec::new();
+        for function in stack {
+            // Count recursive functions once per sample
+            if !seen.contains(&function.as_str()) {
+                seen.push(function);
+                functions.entry(function).or_insert((0, 0)).1 += count;
+            }
+        }
+        if let Some(leaf) = stack.last() {
+            functions.entry(leaf).or_insert((0, 0)).0 += count;
+        }
+    }
+    let mut functions: Vec<_> = functions.into_iter().collect();
+    functions.sort_by(|a, b| (b.1).0.cmp(&(a.1).0).then((b.1).1.cmp(&(a.1).1)).then(a.0.cmp(b.0)));
+    eprintln!("{:>7} {:>7}  Function", "Self", "Total");
+    for (function, (self_count, total)) in functions.into_iter().take(TOP_FUNCTIONS) {
+        eprintln!(
+            "{:>6.1}% {:>6.1}%  {}",
+            self_count as f64 * 100.0 / samples as f64,
+            total as f64 * 100.0 / samples as f64,
+            function
+        );
+    }
+}
@@ -0,0 +1,461 @@
+//! Record and replay (`record`, `reverse-stepi`, `reverse-step`, `reverse-continue`): while
+//! recording, the program is single-stepped, logging the address of every instruction, the
+//! results of system calls with the memory they wrote, and the signals it stopped with. Paused
+//! copies of the program (as made by `checkpoint`) are taken at the start and every so often.
+//! Going back to an earlier instruction forks the latest copy before it and replays forward from
+//! there, emulating system calls from the log instead of running them, so the program reads the
+//! same input and prints nothing twice. Going back to the end switches to the live process.
+//! Like gdb, the log keeps the last 200000 instructions unless told otherwise with
+//! `set record full insn-number-max`.
+//!
+//! Only single-threaded programs are supported. Calls that only change the process itself, such
+//! as mmap, are run again; results of the vDSO (clock_gettime without a system call) are not
+//! logged, and files opened while recording are not open in the replayed copies, so replays
+//! stop with "Replay diverged" if the program behaves differently. Function calls made from
+//! expressions while recording are not recorded.
+
+use std::collections::HashMap;
+
+use nix::sys::signal::{self, Signal};
+
+use crate::dwarf_data::DwarfData;
+use crate::inferior::{Inferior, Status};
+use crate::output;
+use crate::syscall;
+use crate::target::Target;
+
+/// Number of instructions between snapshots, bounding how far a replay has to go
+const SNAPSHOT_INTERVAL: usize = 50000;
+
+/// Default for the most instructions the log keeps (`set record full insn-number-max`), as in
+/// gdb
+pub const DEFAULT_MAX_INSTRUCTIONS: usize = 200000;
+
+/// System calls that replaying runs again rather than emulating, since the program needs their
+/// effects on itself, not just their results.
+const RERUN_SYSCALLS: &[&str] = &[
+    "brk", "mmap", "munmap", "mprotect", "mremap", "madvise", "arch_prctl", "set_tid_address",
+    "set_robust_list", "rseq", "rt_sigaction", "rt_sigprocmask", "sigaltstack",
+];
+
+/// A system call made while recording.
+struct SyscallRecord {
+    number: u64,
+    result: u64,
+    /// Memory the call wrote, as address and contents
+    writes: Vec<(u64, Vec<u8>)>,
+}
+
+pub struct Recording {
+    /// The live process while an earlier instruction is being replayed
+    head: Option<Inferior>,
+    /// Whether the debugger's inferior is a replayed copy rather than the live process
+    replaying: bool,
+    /// Whether the live process has terminated
+    exited: bool,
+    /// rip before each recorded instruction, and where the program is at the end of the log
+    rips: Vec<u64>,
+    /// Index in `rips` of the instruction the debugger's inferior is at
+    now: usize,
+    /// System calls by the index of their syscall instruction
+    syscalls: HashMap<usize, SyscallRecord>,
+    /// Signals the program stopped with, by the index of the instruction they interrupted
+    signals: HashMap<usize, Signal>,
+    /// Paused copies of the program at the given indexes, first of them at the start
+    snapshots: Vec<(usize, Inferior)>,
+    /// Most instructions to keep, if limited. The log can only start at a snapshot, so past the
+    /// limit, the oldest instructions are dropped up to the next one.
+    max_instructions: Option<usize>,
+}
+
+impl Recording {
+    /// Starts recording the stopped `inferior`, keeping at most `max_instructions` if given.
+    pub fn start(
+        inferior: &mut Inferior,
+        max_instructions: Option<usize>,
+    ) -> Result<Recording, String> {
+        let mut rip = inferior.regs().map_err(|err| err.to_string())?.rip;
+        // Just past the int3 of a breakpoint that was hit: back up to the real instruction
+        if inferior.bp_map.contains_key(&(rip - 1)) {
+            rip -= 1;
+            inferior.set_pc(rip).map_err(|err| err.to_string())?;
+        }
+        let snapshot = inferior.fork_checkpoint()?;
+        Ok(Recording {
+            head: None,
+            replaying: false,
+            exited: false,
+            rips: vec![rip],
+            now: 0,
+            syscalls: HashMap::new(),
+            signals: HashMap::new(),
+            snapshots: vec![(0, snapshot)],
+            max_instructions,
+        })
+    }
+
+    /// Changes the most instructions the log keeps, dropping the oldest if there are too many.
+    pub fn set_max_instructions(&mut self, max_instructions: Option<usize>) {
+        self.max_instructions = max_instructions;
+        if !self.replaying {
+            self.trim();
+        }
+    }
+
+    /// Number of instructions between snapshots: a quarter of the limit, so that dropping the
+    /// instructions before a snapshot keeps most of the log.
+    fn snapshot_interval(&self) -> usize {
+        let quarter = |max: usize| (max / 4).max(1).min(SNAPSHOT_INTERVAL);
+        self.max_instructions.map_or(SNAPSHOT_INTERVAL, quarter)
+    }
+
+    /// Drops the oldest snapshots and the part of the log before the next one while the log is
+    /// longer than the limit. Only done at the end of the log, as positions shift.
+    fn trim(&mut self) {
+        let max = match self.max_instructions {
+            Some(max) => max,
+            None => return,
+        };
+        while self.end() - self.snapshots[0].0 > max && self.snapshots.len() > 1 {
+            let (_, mut oldest) = self.snapshots.remove(0);
+            oldest.kill().ok();
+            let start = self.snapshots[0].0;
+            self.rips.drain(..start);
+            self.now -= start;
+            for (tick, _) in self.snapshots.iter_mut() {
+                *tick -= start;
+            }
+            self.syscalls = self
+                .syscalls
+                .drain()
+                .filter(|(tick, _)| *tick >= start)
+                .map(|(tick, record)| (tick - start, record))
+                .collect();
+            self.signals = self
+                .signals
+                .drain()
+                .filter(|(tick, _)| *tick >= start)
+                .map(|(tick, signal)| (tick - start, signal))
+                .collect();
+        }
+    }
+
+    /// Index of the last position in the log.
+    fn end(&self) -> usize {
+        self.rips.len() - 1
+    }
+
+    /// Continues forward from the current position of `current`: replaying up to the next
+    /// breakpoint or signal in the log, or recording until the live process stops.
+    pub fn resume(
+        &mut self,
+        current: &mut Option<Inferior>,
+        breaks: &[u64],
+    ) -> Result<Status, String> {
+        if !self.replaying {
+            let inferior = current.as_mut().ok_or("The program is not being run.")?;
+            return self.record(inferior, breaks);
+        }
+        let end = self.end();
+        let next = (self.now + 1..=end)
+            .find(|tick| breaks.contains(&self.rips[*tick]) || self.signals.contains_key(tick));
+        match next {
+            Some(tick) => {
+                self.goto(current, tick, breaks)?;
+                Ok(self.status(breaks, true))
+            }
+            None => {
+                self.goto(current, end, breaks)?;
+                if self.replaying {
+                    output::console("No more reverse-execution history.");
+                    return Ok(self.status(breaks, false));
+                }
+                // Back at the live process: carry on recording
+                self.record(current.as_mut().unwrap(), breaks)
+            }
+        }
+    }
+
+    /// Single-steps the live process, logging every instruction, until it reaches a breakpoint,
+    /// stops with a signal or terminates.
+    fn record(&mut self, inferior: &mut Inferior, breaks: &[u64]) -> Result<Status, String> {
+        // Breakpoints are checked before each step instead, since stepping over one would run
+        // the instruction before it again when it is a single byte long
+        inferior.set_breakpoints(&[]).map_err(|err| err.to_string())?;
+        let status = self.record_steps(inferior, breaks);
+        if let Ok(Status::Stopped(..)) = status {
+            inferior.set_breakpoints(breaks).map_err(|err| err.to_string())?;
+        }
+        status
+    }
+
+    fn record_steps(&mut self, inferior: &mut Inferior, breaks: &[u64]) -> Result<Status, String> {
+        loop {
+            if self.end() - self.snapshots.last().unwrap().0 >= self.snapshot_interval() {
+                let snapshot = inferior.fork_checkpoint()?;
+                self.snapshots.push((self.end(), snapshot));
+            }
+            self.trim();
+            let tick = self.end();
+            let status = inferior.step_instruction().map_err(|err| err.to_string())?;
+            let rip = match status {
+                Status::Stopped(signal::SIGTRAP, rip) => rip as u64,
+                Status::Stopped(signal, _) => {
+                    // Like other stops, the signal is not delivered when the program resumes
+                    self.signals.insert(tick, signal);
+                    return Ok(status);
+                }
+                Status::Exited(_) | Status::Signaled(_) => {
+                    self.exited = true;
+                    return Ok(status);
+                }
+            };
+            let regs = inferior.regs().map_err(|err| err.to_string())?;
+            // orig_rax is -1 after anything but a system call
+            if regs.orig_rax != u64::MAX {
+                let args = [regs.rdi, regs.rsi, regs.rdx, regs.r10, regs.r8, regs.r9];
+                let written = syscall::written_memory(regs.orig_rax, &args, regs.rax as i64);
+                let writes = written
+                    .into_iter()
+                    .map(|(addr, len)| Ok((addr, inferior.read_memory(addr, len)?)))
+                    .collect::<Result<_, nix::Error>>()
+                    .map_err(|err| err.to_string())?;
+                let record = SyscallRecord { number: regs.orig_rax, result: regs.rax, writes };
+                self.syscalls.insert(tick, record);
+            }
+            self.rips.push(rip);
+            self.now = self.end();
+            if breaks.contains(&rip) {
+                // Reported like a breakpoint hit in a normal run, just past the int3
+                return Ok(Status::Stopped(signal::SIGTRAP, rip as usize + 1));
+            }
+        }
+    }
+
+    /// Goes back one instruction (`reverse-stepi`).
+    pub fn reverse_stepi(
+        &mut self,
+        current: &mut Option<Inferior>,
+        breaks: &[u64],
+    ) -> Result<Status, String> {
+        match self.now.checked_sub(1) {
+            Some(tick) => {
+                self.goto(current, tick, breaks)?;
+                Ok(self.status(breaks, false))
+            }
+            None => self.reverse_to_start(current, breaks),
+        }
+    }
+
+    /// Goes back to the start of the source line that ran before the current one
+    /// (`reverse-step`). Instructions without line information, such as library code, are
+    /// stepped through.
+    pub fn reverse_step(
+        &mut self,
+        current: &mut Option<Inferior>,
+        breaks: &[u64],
+        debug_data: &DwarfData,
+    ) -> Result<Status, String> {
+        let line_at = |tick: usize| {
+            let line = debug_data.get_line_from_addr(self.rips[tick] as usize);
+            line.map(|line| (line.file, line.number))
+        };
+        let current_line = line_at(self.now);
+        let mut tick = self.now;
+        let previous = loop {
+            if tick == 0 {
+                break None;
+            }
+            tick -= 1;
+            match line_at(tick) {
+                Some(line) if Some(&line) != current_line.as_ref() => break Some(line),
+                _ => {}
+            }
+        };
+        let line = previous;
+        if line.is_none() {
+            return self.reverse_to_start(current, breaks);
+        }
+        // Back through the instructions of that line, and any code without lines it called
+        while tick > 0 {
+            match line_at(tick - 1) {
+                Some(other) if Some(&other) != line.as_ref() => break,
+                _ => tick -= 1,
+            }
+        }
+        while line_at(tick) != line {
+            tick += 1;
+        }
+        self.goto(current, tick, breaks)?;
+        Ok(self.status(breaks, false))
+    }
+
+    /// Goes back to the last breakpoint hit before the current position (`reverse-continue`).
+    pub fn reverse_continue(
+        &mut self,
+        current: &mut Option<Inferior>,
+        breaks: &[u64],
+    ) -> Result<Status, String> {
+        match (0..self.now).rev().find(|tick| breaks.contains(&self.rips[*tick])) {
+            Some(tick) => {
+                self.goto(current, tick, breaks)?;
+                Ok(self.status(breaks, true))
+            }
+            None => self.reverse_to_start(current, breaks),
+        }
+    }
+
+    fn reverse_to_start(
+        &mut self,
+        current: &mut Option<Inferior>,
+        breaks: &[u64],
+    ) -> Result<Status, String> {
+        self.goto(current, 0, breaks)?;
+        output::console("No more reverse-execution history.");
+        Ok(self.status(breaks, false))
+    }
+
+    /// Describes the current position as a stop: a breakpoint hit if `hit` and there is one
+    /// there, or the signal the program stopped with there.
+    fn status(&self, breaks: &[u64], hit: bool) -> Status {
+        let rip = self.rips[self.now];
+        match self.signals.get(&self.now) {
+            Some(signal) if hit => Status::Stopped(*signal, rip as usize),
+            _ if hit && breaks.contains(&rip) => Status::Stopped(signal::SIGTRAP, rip as usize + 1),
+            _ => Status::Stopped(signal::SIGTRAP, rip as usize),
+        }
+    }
+
+    /// Makes `current` the program as it was at position `target` of the log.
+    fn goto(
+        &mut self,
+        current: &mut Option<Inferior>,
+        target: usize,
+        breaks: &[u64],
+    ) -> Result<(), String> {
+        if target == self.end() && !self.exited {
+            if self.replaying {
+                let mut head = self.head.take().unwrap();
+                head.set_breakpoints(breaks).map_err(|err| err.to_string())?;
+                if let Some(mut copy) = current.replace(head) {
+                    copy.kill().map_err(|err| err.to_string())?;
+                }
+                self.replaying = false;
+            }
+            self.now = target;
+            return Ok(());
+        }
+        // Replay from where we are if that is on the way, otherwise from a snapshot
+        if !self.replaying || self.now > target || current.is_none() {
+            let index = self.snapshots.iter().rposition(|(tick, _)| *tick <= target).unwrap();
+            let (tick, snapshot) = &mut self.snapshots[index];
+            let mut copy = snapshot.fork_checkpoint()?;
+            copy.set_breakpoints(breaks).map_err(|err| err.to_string())?;
+            self.now = *tick;
+            match current.replace(copy) {
+                Some(mut copy) if self.replaying => {
+                    copy.kill().map_err(|err| err.to_string())?;
+                }
+                head => self.head = head,
+            }
+            self.replaying = true;
+        }
+        self.replay(current.as_mut().unwrap(), target)
+    }
+
+    /// Runs the replayed `copy` forward from the current position to `target`, checking that it
+    /// goes the same way as when it was recorded.
+    fn replay(&mut self, copy: &mut Inferior, target: usize) -> Result<(), String> {
+        // As when recording
+        let breaks: Vec<u64> = copy.bp_map.keys().cloned().collect();
+        copy.set_breakpoints(&[]).map_err(|err| err.to_string())?;
+        let replayed = self.replay_steps(copy, target);
+        copy.set_breakpoints(&breaks).map_err(|err| err.to_string())?;
+        replayed
+    }
+
+    fn replay_steps(&mut self, copy: &mut Inferior, target: usize) -> Result<(), String> {
+        while self.now < target {
+            let emulated = self.syscalls.get(&self.now).filter(|record| {
+                !RERUN_SYSCALLS.contains(&syscall::name(record.number).unwrap_or(""))
+            });
+            match emulated {
+                Some(record) => {
+                    let mut regs = copy.regs().map_err(|err| err.to_string())?;
+                    regs.rax = record.result;
+                    // Past the syscall instruction
+                    regs.rip += 2;
+                    nix::sys::ptrace::setregs(copy.pid(), regs).map_err(|err| err.to_string())?;
+                    for (addr, bytes) in &record.writes {
+                        copy.write_memory(*addr, bytes).map_err(|err| err.to_string())?;
+                    }
+                }
+                None => match copy.step_instruction().map_err(|err| err.to_string())? {
+                    Status::Stopped(signal::SIGTRAP, _) => {}
+                    status => {
+                        return Err(format!(
+                            "Replay diverged at instruction {}: {:?}",
+                            self.now, status
+                        ))
+                    }
+                },
+            }
+            self.now += 1;
+            let rip = copy.regs().map_err(|err| err.to_string())?.rip;
+            if rip != self.rips[self.now] {
+                return Err(format!(
+                    "Replay diverged at instruction {}: expected rip {:#x}, got {:#x}",
+                    self.now, self.rips[self.now], rip
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Throws the log away, killing the snapshots and the live process if an earlier position
+    /// is being replayed: a replayed copy carries on as the program (`record stop`).
+    pub fn discard(mut self) {
+        for (_, mut snapshot) in self.snapshots.drain(..) {
+            snapshot.kill().ok();
+        }
+        if let Some(mut head) = self.head.take() {
+            head.kill().ok();
+        }
+    }
+
+    /// Prints a description of the log for `info record`.
+    pub fn print_info(&self) {
+        println!(
+            "Recorded {} instructions, {} system calls and {} signals; {} snapshots.",
+            self.end(),
+            self.syscalls.len(),
+            self.signals.len(),
+            self.snapshots.len()
+        );
+        match self.max_instructions {
+            Some(max) => println!("Max recorded instructions: {}", max),
+            None => println!("Max recorded instructions: unlimited"),
+        }
+        if self.replaying {
+            println!("Replaying instruction {} of {}.", self.now, self.end());
+        } else if self.exited {
+            println!("The program exited at the end of the recording.");
+        } else {
+            println!("Recording at the end of the log.");
+        }
+    }
+
+    /// Describes the log for `info record`.
+    pub fn info(&self) -> serde_json::Value {
+        serde_json::json!({
+            "instructions": self.end(),
+            "position": self.now,
+            "replaying": self.replaying,
+            "syscalls": self.syscalls.len(),
+            "signals": self.signals.len(),
+            "snapshots": self.snapshots.len(),
+            "exited": self.exited,
+            "max-instructions": self.max_instructions,
+        })
+    }
+}
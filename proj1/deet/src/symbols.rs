@@ -0,0 +1,163 @@
+//! ELF symbol tables, used for code without DWARF debugging information: stripped programs, and
+//! shared libraries such as libc, whose functions still have names in `.symtab` or `.dynsym`.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::rc::Rc;
+
+use nix::unistd::Pid;
+use object::{Object, ObjectSegment, SymbolKind};
+
+/// A function symbol, at the address it is loaded at.
+#[derive(Debug, Clone)]
+struct Symbol {
+    name: String,
+    address: u64,
+    size: u64,
+}
+
+/// The function symbols of one ELF file.
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    /// Sorted by address
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    /// Reads the function symbols of `object`, from both `.symtab` and `.dynsym`, adding `bias`
+    /// to their addresses.
+    pub fn from_object(object: &object::File, bias: u64) -> SymbolTable {
+        let mut symbols: Vec<Symbol> = object
+            .symbols()
+            .chain(object.dynamic_symbols())
+            .filter(|(_, symbol)| symbol.kind() == SymbolKind::Text && symbol.address() != 0)
+            .filter_map(|(_, symbol)| {
+                Some(Symbol {
+                    name: symbol.name().filter(|name| !name.is_empty())?.to_string(),
+                    address: bias + symbol.address(),
+                    size: symbol.size(),
+                })
+            })
+            .collect();
+        // A function is usually in both tables
+        symbols.sort_by(|a, b| (a.address, &a.name).cmp(&(b.address, &b.name)));
+        symbols.dedup_by(|a, b| a.address == b.address && a.name == b.name);
+        SymbolTable { symbols }
+    }
+
+    /// Reads the function symbols of the ELF file at `path`, which is mapped at `start`.
+    pub fn from_file(path: &str, start: u64) -> Result<SymbolTable, String> {
+        let file = fs::File::open(path).map_err(|err| format!("{}: {}", path, err))?;
+        let mmap = unsafe { memmap::Mmap::map(&file) }.map_err(|err| format!("{}: {}", path, err))?;
+        let object = object::File::parse(&*mmap).map_err(|err| format!("{}: {}", path, err))?;
+        // Shared libraries are linked at 0 and loaded anywhere; executables that aren't
+        // position independent are loaded where they were linked
+        let linked_at = object.segments().map(|segment| segment.address()).min().unwrap_or(0);
+        Ok(SymbolTable::from_object(&object, start.wrapping_sub(linked_at & !0xfff)))
+    }
+
+    /// Returns the address of the function called `name`.
+    pub fn address_of(&self, name: &str) -> Option<u64> {
+        self.symbols.iter().find(|symbol| symbol.name == name).map(|symbol| symbol.address)
+    }
+
+    /// Returns the function containing `addr` and the offset of `addr` in it. Symbols without a
+    /// size (in hand-written assembly) are taken to extend up to the next symbol, so the last
+    /// one, whose end is unknown, only covers its own address.
+    pub fn lookup(&self, addr: u64) -> Option<(&str, u64)> {
+        let index = match self.symbols.binary_search_by(|symbol| symbol.address.cmp(&addr)) {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        // Aliases share an address; prefer one whose size covers addr
+        let address = self.symbols[index].address;
+        let symbol = self.symbols[..=index]
+            .iter()
+            .rev()
+            .take_while(|symbol| symbol.address == address)
+            .find(|symbol| addr < symbol.address + symbol.size)
+            .or_else(|| {
+                let next = self.symbols.get(index + 1).map_or(address + 1, |next| next.address);
+                Some(&self.symbols[index]).filter(|symbol| symbol.size == 0 && addr < next)
+            })?;
+        Some((&symbol.name, addr - symbol.address))
+    }
+}
+
+/// Returns the ELF files mapped by process `pid` with the address each is loaded at, in the
+/// order of /proc/pid/maps.
+pub fn mapped_files(pid: Pid) -> Result<Vec<(String, u64)>, String> {
+    Ok(mappings(pid)?.into_iter().map(|(path, base, _)| (path, base)).collect())
+}
+
+/// Path, load address and inode of a mapped file. The inode tells a file replaced on disk from
+/// the one that was mapped.
+type Mapping = (String, u64, u64);
+
+/// Like `mapped_files`, with the inode of each file.
+fn mappings(pid: Pid) -> Result<Vec<Mapping>, String> {
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid))
+        .map_err(|err| format!("Cannot read memory mappings: {}", err))?;
+    let mut files = Vec::new();
+    for line in maps.lines() {
+        // e.g. "7f1c2a400000-7f1c2a428000 r--p 00000000 08:01 1234   /usr/lib/libc.so.6"
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let path = match fields.get(5) {
+            Some(path) if path.starts_with('/') => path,
+            _ => continue,
+        };
+        // The mapping of the start of the file is the load address
+        if fields[2].chars().all(|c| c == '0') {
+            let start = fields[0].split('-').next().unwrap_or("");
+            let base = u64::from_str_radix(start, 16).map_err(|err| err.to_string())?;
+            let inode = fields[4].parse().map_err(|_| format!("Bad inode in {}", line))?;
+            files.push((path.to_string(), base, inode));
+        }
+    }
+    Ok(files)
+}
+
+/// The symbol tables of the shared libraries a process has mapped, kept between stops. Tables
+/// are keyed by the path, load address and inode of their mapping, so only the libraries whose
+/// mappings are new, as after exec or dlopen, are read again.
+#[derive(Debug, Default)]
+pub struct LibraryCache {
+    /// None for the executable and for files that can't be read
+    tables: RefCell<HashMap<Mapping, Option<Rc<SymbolTable>>>>,
+}
+
+impl LibraryCache {
+    /// Returns the symbol tables of the shared libraries mapped by process `pid`, in the order
+    /// of their mappings, skipping those that can't be read.
+    pub fn shared_libraries(&self, pid: Pid) -> Vec<Rc<SymbolTable>> {
+        let mut old = self.tables.replace(HashMap::new());
+        let mut tables = self.tables.borrow_mut();
+        let mut executable = None;
+        let mut libraries = Vec::new();
+        for key in mappings(pid).unwrap_or_default() {
+            let table = old.remove(&key).unwrap_or_else(|| {
+                let executable = executable.get_or_insert_with(|| {
+                    fs::canonicalize(format!("/proc/{}/exe", pid)).ok()
+                });
+                if fs::canonicalize(&key.0).ok() == *executable {
+                    return None;
+                }
+                SymbolTable::from_file(&key.0, key.1).ok().map(Rc::new)
+            });
+            libraries.extend(table.clone());
+            tables.insert(key, table);
+        }
+        libraries
+    }
+}
+
+/// Names the code at `addr` by the symbol containing it, as "name+0xoffset", looking in `tables`
+/// in order.
+pub fn describe(tables: &[&SymbolTable], addr: u64) -> Option<String> {
+    tables.iter().find_map(|table| table.lookup(addr)).map(|(name, offset)| match offset {
+        0 => name.to_string(),
+        _ => format!("{}+{:#x}", name, offset),
+    })
+}
@@ -0,0 +1,316 @@
+//! System call names and argument decoding for x86_64 Linux, used by `catch syscall` and by the
+//! `--strace` mode, which runs a program to completion logging every system call it makes.
+//!
+//! Arguments are decoded for the common calls only; anything else is shown by name with its
+//! return value. Each argument is described by one letter:
+//!
+//! * `i` an int (file descriptors, flags shown in decimal), `l` a long (sizes, offsets)
+//! * `d` a directory file descriptor, shown as AT_FDCWD when it is one
+//! * `x` flags in hex, `o` a mode in octal, `p` a pointer (NULL when zero)
+//! * `s` a NUL-terminated string
+//! * `b` a buffer the call reads, whose length is the next argument
+//! * `r` a buffer the call fills, whose length is the return value; it can only be shown once
+//!   the call has returned
+
+use nix::errno::Errno;
+
+use crate::expr;
+use crate::inferior::{Inferior, Status, SyscallStop};
+use crate::target::Target;
+
+/// How many bytes of a string or buffer argument are shown
+const STRING_LIMIT: usize = 32;
+
+/// Names of system calls 0 to 334, indexed by number
+const NAMES: &[&str] = &[
+    "read", "write", "open", "close", "stat", "fstat", "lstat", "poll", "lseek", "mmap",
+    "mprotect", "munmap", "brk", "rt_sigaction", "rt_sigprocmask", "rt_sigreturn", "ioctl",
+    "pread64", "pwrite64", "readv", "writev", "access", "pipe", "select", "sched_yield", "mremap",
+    "msync", "mincore", "madvise", "shmget", "shmat", "shmctl", "dup", "dup2", "pause",
+    "nanosleep", "getitimer", "alarm", "setitimer", "getpid", "sendfile", "socket", "connect",
+    "accept", "sendto", "recvfrom", "sendmsg", "recvmsg", "shutdown", "bind", "listen",
+    "getsockname", "getpeername", "socketpair", "setsockopt", "getsockopt", "clone", "fork",
+    "vfork", "execve", "exit", "wait4", "kill", "uname", "semget", "semop", "semctl", "shmdt",
+    "msgget", "msgsnd", "msgrcv", "msgctl", "fcntl", "flock", "fsync", "fdatasync", "truncate",
+    "ftruncate", "getdents", "getcwd", "chdir", "fchdir", "rename", "mkdir", "rmdir", "creat",
+    "link", "unlink", "symlink", "readlink", "chmod", "fchmod", "chown", "fchown", "lchown",
+    "umask", "gettimeofday", "getrlimit", "getrusage", "sysinfo", "times", "ptrace", "getuid",
+    "syslog", "getgid", "setuid", "setgid", "geteuid", "getegid", "setpgid", "getppid",
+    "getpgrp", "setsid", "setreuid", "setregid", "getgroups", "setgroups", "setresuid",
+    "getresuid", "setresgid", "getresgid", "getpgid", "setfsuid", "setfsgid", "getsid", "capget",
+    "capset", "rt_sigpending", "rt_sigtimedwait", "rt_sigqueueinfo", "rt_sigsuspend",
+    "sigaltstack", "utime", "mknod", "uselib", "personality", "ustat", "statfs", "fstatfs",
+    "sysfs", "getpriority", "setpriority", "sched_setparam", "sched_getparam",
+    "sched_setscheduler", "sched_getscheduler", "sched_get_priority_max",
+    "sched_get_priority_min", "sched_rr_get_interval", "mlock", "munlock", "mlockall",
+    "munlockall", "vhangup", "modify_ldt", "pivot_root", "_sysctl", "prctl", "arch_prctl",
+    "adjtimex", "setrlimit", "chroot", "sync", "acct", "settimeofday", "mount", "umount2",
+    "swapon", "swapoff", "reboot", "sethostname", "setdomainname", "iopl", "ioperm",
+    "create_module", "init_module", "delete_module", "get_kernel_syms", "query_module",
+    "quotactl", "nfsservctl", "getpmsg", "putpmsg", "afs_syscall", "tuxcall", "security",
+    "gettid", "readahead", "setxattr", "lsetxattr", "fsetxattr", "getxattr", "lgetxattr",
+    "fgetxattr", "listxattr", "llistxattr", "flistxattr", "removexattr", "lremovexattr",
+    "fremovexattr", "tkill", "time", "futex", "sched_setaffinity", "sched_getaffinity",
+    "set_thread_area", "io_setup", "io_destroy", "io_getevents", "io_submit", "io_cancel",
+    "get_thread_area", "lookup_dcookie", "epoll_create", "epoll_ctl_old", "epoll_wait_old",
+    "remap_file_pages", "getdents64", "set_tid_address", "restart_syscall", "semtimedop",
+    "fadvise64", "timer_create", "timer_settime", "timer_gettime", "timer_getoverrun",
+    "timer_delete", "clock_settime", "clock_gettime", "clock_getres", "clock_nanosleep",
+    "exit_group", "epoll_wait", "epoll_ctl", "tgkill", "utimes", "vserver", "mbind",
+    "set_mempolicy", "get_mempolicy", "mq_open", "mq_unlink", "mq_timedsend", "mq_timedreceive",
+    "mq_notify", "mq_getsetattr", "kexec_load", "waitid", "add_key", "request_key", "keyctl",
+    "ioprio_set", "ioprio_get", "inotify_init", "inotify_add_watch", "inotify_rm_watch",
+    "migrate_pages", "openat", "mkdirat", "mknodat", "fchownat", "futimesat", "newfstatat",
+    "unlinkat", "renameat", "linkat", "symlinkat", "readlinkat", "fchmodat", "faccessat",
+    "pselect6", "ppoll", "unshare", "set_robust_list", "get_robust_list", "splice", "tee",
+    "sync_file_range", "vmsplice", "move_pages", "utimensat", "epoll_pwait", "signalfd",
+    "timerfd_create", "eventfd", "fallocate", "timerfd_settime", "timerfd_gettime", "accept4",
+    "signalfd4", "eventfd2", "epoll_create1", "dup3", "pipe2", "inotify_init1", "preadv",
+    "pwritev", "rt_tgsigqueueinfo", "perf_event_open", "recvmmsg", "fanotify_init",
+    "fanotify_mark", "prlimit64", "name_to_handle_at", "open_by_handle_at", "clock_adjtime",
+    "syncfs", "sendmmsg", "setns", "getcpu", "process_vm_readv", "process_vm_writev", "kcmp",
+    "finit_module", "sched_setattr", "sched_getattr", "renameat2", "seccomp", "getrandom",
+    "memfd_create", "kexec_file_load", "bpf", "execveat", "userfaultfd", "membarrier", "mlock2",
+    "copy_file_range", "preadv2", "pwritev2", "pkey_mprotect", "pkey_alloc", "pkey_free",
+    "statx", "io_pgetevents", "rseq",
+];
+
+/// Names of system calls from 424 on, after the gap left for 32-bit compatibility calls
+const NAMES_FROM_424: &[&str] = &[
+    "pidfd_send_signal", "io_uring_setup", "io_uring_enter", "io_uring_register", "open_tree",
+    "move_mount", "fsopen", "fsconfig", "fsmount", "fspick", "pidfd_open", "clone3",
+    "close_range", "openat2", "pidfd_getfd", "faccessat2", "process_madvise", "epoll_pwait2",
+    "mount_setattr", "quotactl_fd", "landlock_create_ruleset", "landlock_add_rule",
+    "landlock_restrict_self", "memfd_secret", "process_mrelease", "futex_waitv",
+    "set_mempolicy_home_node",
+];
+
+/// Argument kinds of the calls whose arguments are decoded (see the module documentation)
+const SIGNATURES: &[(&str, &str)] = &[
+    ("read", "irl"), ("write", "ibl"), ("open", "sxo"), ("close", "i"), ("stat", "sp"),
+    ("fstat", "ip"), ("lstat", "sp"), ("poll", "pii"), ("lseek", "ili"), ("mmap", "plxxil"),
+    ("mprotect", "plx"), ("munmap", "pl"), ("brk", "p"), ("rt_sigaction", "ippl"),
+    ("rt_sigprocmask", "ippl"), ("rt_sigreturn", ""), ("ioctl", "ixp"), ("pread64", "irll"),
+    ("pwrite64", "ibll"), ("readv", "ipi"), ("writev", "ipi"), ("access", "so"), ("pipe", "p"),
+    ("select", "ipppp"), ("sched_yield", ""), ("mremap", "pllxp"), ("madvise", "plx"),
+    ("dup", "i"), ("dup2", "ii"), ("pause", ""), ("nanosleep", "pp"), ("alarm", "i"),
+    ("getpid", ""), ("sendfile", "iipl"), ("socket", "iii"), ("connect", "ipi"),
+    ("accept", "ipp"), ("sendto", "iblxpi"), ("recvfrom", "irlxpp"), ("shutdown", "ii"),
+    ("bind", "ipi"), ("listen", "ii"), ("clone", "xpppp"), ("fork", ""), ("vfork", ""),
+    ("execve", "spp"), ("exit", "i"), ("wait4", "ipxp"), ("kill", "ii"), ("uname", "p"),
+    ("fcntl", "iix"), ("fsync", "i"), ("truncate", "sl"), ("ftruncate", "il"),
+    ("getcwd", "rl"), ("chdir", "s"), ("fchdir", "i"), ("rename", "ss"), ("mkdir", "so"),
+    ("rmdir", "s"), ("creat", "so"), ("link", "ss"), ("unlink", "s"), ("symlink", "ss"),
+    ("readlink", "srl"), ("chmod", "so"), ("fchmod", "io"), ("chown", "sii"),
+    ("fchown", "iii"), ("umask", "o"), ("gettimeofday", "pp"), ("getrlimit", "ip"),
+    ("sysinfo", "p"), ("times", "p"), ("getuid", ""), ("getgid", ""), ("geteuid", ""),
+    ("getegid", ""), ("getppid", ""), ("sigaltstack", "pp"), ("prctl", "xxxxx"),
+    ("arch_prctl", "xp"), ("setrlimit", "ip"), ("gettid", ""), ("futex", "pxippi"),
+    ("getdents64", "irl"), ("set_tid_address", "p"), ("clock_gettime", "ip"),
+    ("clock_nanosleep", "iipp"), ("exit_group", "i"), ("epoll_wait", "ipii"),
+    ("epoll_ctl", "iiip"), ("tgkill", "iii"), ("openat", "dsxo"), ("mkdirat", "dso"),
+    ("newfstatat", "dspx"), ("unlinkat", "dsx"), ("renameat", "dsds"),
+    ("readlinkat", "dsrl"), ("fchmodat", "dso"), ("faccessat", "dso"), ("pselect6", "ippppp"),
+    ("ppoll", "pippl"), ("set_robust_list", "pl"), ("epoll_create1", "x"), ("dup3", "iix"),
+    ("pipe2", "px"), ("prlimit64", "iipp"), ("getrandom", "rlx"), ("statx", "dsxxp"),
+    ("rseq", "plxx"), ("close_range", "iix"), ("openat2", "dspl"), ("faccessat2", "dsox"),
+];
+
+/// Structures that calls fill in through a pointer argument: call, argument index and size in
+/// bytes. Buffers whose length is the return value are described by the signatures (`r`).
+const OUTPUT_STRUCTS: &[(&str, usize, usize)] = &[
+    ("stat", 1, 144), ("fstat", 1, 144), ("lstat", 1, 144), ("newfstatat", 2, 144),
+    ("statx", 4, 256), ("clock_gettime", 1, 16), ("gettimeofday", 0, 16), ("uname", 0, 390),
+    ("sysinfo", 0, 112), ("times", 0, 32), ("getrlimit", 1, 16), ("prlimit64", 3, 16),
+    ("pipe", 0, 8), ("pipe2", 0, 8), ("wait4", 1, 4), ("nanosleep", 1, 16),
+    ("clock_nanosleep", 3, 16), ("rt_sigaction", 2, 32), ("rt_sigprocmask", 2, 8),
+    ("accept", 1, 128), ("getrusage", 1, 144),
+];
+
+/// Returns the name of system call `number`, if it has one.
+pub fn name(number: u64) -> Option<&'static str> {
+    let number = number as usize;
+    if number < NAMES.len() {
+        Some(NAMES[number])
+    } else {
+        NAMES_FROM_424.get(number.checked_sub(424)?).copied()
+    }
+}
+
+/// Looks up a system call number by name.
+pub fn number(name: &str) -> Option<u64> {
+    if let Some(index) = NAMES.iter().position(|n| *n == name) {
+        return Some(index as u64);
+    }
+    NAMES_FROM_424.iter().position(|n| *n == name).map(|index| index as u64 + 424)
+}
+
+/// Describes a system call number as its name, or syscall_N if it has none.
+pub fn describe(number: u64) -> String {
+    name(number).map(|name| name.to_string()).unwrap_or_else(|| format!("syscall_{}", number))
+}
+
+fn signature(number: u64) -> Option<&'static str> {
+    let name = name(number)?;
+    SIGNATURES.iter().find(|(n, _)| *n == name).map(|(_, kinds)| *kinds)
+}
+
+/// True if an argument of the call can only be decoded once it has returned.
+fn fills_buffer(number: u64) -> bool {
+    signature(number).map(|kinds| kinds.contains('r')).unwrap_or(false)
+}
+
+/// Reads a NUL-terminated string, returning it quoted.
+fn read_string<T: Target + ?Sized>(target: &T, addr: u64) -> Option<String> {
+    let mut text = Vec::new();
+    let mut word_addr = addr;
+    while text.len() <= STRING_LIMIT {
+        // Never read past the word the terminator is in, which might end a mapping
+        let word = target.read_memory(word_addr, (8 - word_addr % 8) as usize).ok()?;
+        word_addr += word.len() as u64;
+        match word.iter().position(|byte| *byte == 0) {
+            Some(end) => {
+                text.extend_from_slice(&word[..end]);
+                break;
+            }
+            None => text.extend_from_slice(&word),
+        }
+    }
+    let truncated = text.len() > STRING_LIMIT;
+    text.truncate(STRING_LIMIT);
+    Some(expr::quote(&text, truncated))
+}
+
+/// Reads a buffer of `len` bytes, returning (the start of) it quoted.
+fn read_buffer<T: Target + ?Sized>(target: &T, addr: u64, len: u64) -> Option<String> {
+    let shown = len.min(STRING_LIMIT as u64) as usize;
+    let bytes = target.read_memory(addr, shown).ok()?;
+    Some(expr::quote(&bytes, len > shown as u64))
+}
+
+fn pointer(value: u64) -> String {
+    if value == 0 {
+        "NULL".to_string()
+    } else {
+        format!("{:#x}", value)
+    }
+}
+
+fn format_argument<T: Target + ?Sized>(target: &T, stop: &SyscallStop, index: usize) -> String {
+    let value = stop.args[index];
+    let kinds = signature(stop.number).unwrap_or("");
+    let decoded = match kinds.as_bytes()[index] {
+        b'i' => Some((value as i32).to_string()),
+        b'l' => Some((value as i64).to_string()),
+        b'd' if value as i32 == libc::AT_FDCWD => Some("AT_FDCWD".to_string()),
+        b'd' => Some((value as i32).to_string()),
+        b'x' => Some(format!("{:#x}", value)),
+        b'o' => Some(format!("0{:o}", value)),
+        b's' if value != 0 => read_string(target, value),
+        b'b' if value != 0 => read_buffer(target, value, stop.args[index + 1]),
+        b'r' if value != 0 => match stop.result {
+            Some(len) if len >= 0 => read_buffer(target, value, len as u64),
+            _ => None,
+        },
+        _ => None,
+    };
+    decoded.unwrap_or_else(|| pointer(value))
+}
+
+/// Formats a system call with its arguments, like `write(1, "hello\n", 6)`.
+pub fn format_call<T: Target + ?Sized>(target: &T, stop: &SyscallStop) -> String {
+    let count = signature(stop.number).map(|kinds| kinds.len()).unwrap_or(0);
+    let args: Vec<String> = (0..count).map(|index| format_argument(target, stop, index)).collect();
+    format!("{}({})", describe(stop.number), args.join(", "))
+}
+
+/// Formats the return value of a system call: an error as `-1 ENOENT (No such file or
+/// directory)`, addresses in hex and anything else in decimal.
+pub fn format_result(number: u64, result: i64) -> String {
+    if result < 0 && result >= -4095 {
+        let errno = Errno::from_i32(-result as i32);
+        return format!("-1 {:?} ({})", errno, errno.desc());
+    }
+    match name(number) {
+        Some("mmap") | Some("mremap") | Some("brk") => format!("{:#x}", result),
+        _ => result.to_string(),
+    }
+}
+
+/// Runs `target` to completion, logging every system call it makes to stderr along with its
+/// return value and any signals it receives, like strace. Returns the exit status deet should
+/// exit with (see `Status::exit_code`).
+pub fn strace(target: &str, args: &Vec<String>) -> i32 {
+    let mut inferior = match Inferior::start(target, args, &Vec::new()) {
+        Some(inferior) => inferior,
+        None => return 1,
+    };
+    inferior.trace_syscalls = true;
+    // A call that has been entered but has not returned yet, already formatted unless its
+    // arguments can only be decoded on return
+    let mut pending: Option<Option<String>> = None;
+    let status = inferior.run_to_exit(|inferior, sig, _| {
+        let stop = match inferior.syscall_stop() {
+            Some(stop) => stop,
+            None => {
+                eprintln!("--- {} ---", sig);
+                return Ok(Some(sig));
+            }
+        };
+        match stop.result {
+            None if fills_buffer(stop.number) => pending = Some(None),
+            None => pending = Some(Some(format_call(inferior, stop))),
+            Some(result) => {
+                let call = pending.take().flatten();
+                let call = call.unwrap_or_else(|| format_call(inferior, stop));
+                eprintln!("{} = {}", call, format_result(stop.number, result));
+            }
+        }
+        Ok(None)
+    });
+    let status = match status {
+        Ok(status) => status,
+        Err(err) => {
+            eprintln!("Error tracing subprocess: {}", err);
+            return 1;
+        }
+    };
+    // The program's last call (exit_group) never returns
+    if let Some(call) = pending.take().flatten() {
+        eprintln!("{} = ?", call);
+    }
+    match status {
+        Status::Exited(code) => eprintln!("+++ exited with {} +++", code),
+        Status::Signaled(sig) => eprintln!("+++ killed by {} +++", sig),
+        Status::Stopped(..) => {}
+    }
+    status.exit_code()
+}
+
+/// Lists the memory a system call that returned `result` has written to, as address and
+/// length, as far as is known for the common calls.
+pub fn written_memory(number: u64, args: &[u64; 6], result: i64) -> Vec<(u64, usize)> {
+    if result < 0 {
+        return Vec::new();
+    }
+    let mut written = Vec::new();
+    for (index, kind) in signature(number).unwrap_or("").chars().enumerate() {
+        if kind == 'r' && args[index] != 0 {
+            written.push((args[index], result as usize));
+        }
+    }
+    let name = name(number).unwrap_or("");
+    for (_, index, size) in OUTPUT_STRUCTS.iter().filter(|(call, _, _)| *call == name) {
+        if args[*index] != 0 {
+            written.push((args[*index], *size));
+        }
+    }
+    written
+}
+
+/// Tells whether the inferior is stopped at a system call matching one of `numbers` (any system
+/// call when empty).
+pub fn matches(stop: &SyscallStop, numbers: &[u64]) -> bool {
+    numbers.is_empty() || numbers.contains(&stop.number)
+}
+
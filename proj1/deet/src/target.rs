@@ -0,0 +1,336 @@
+//! Read-only access to the state of a debugged program. This is implemented both by a live
+//! `Inferior` and by a `CoreFile` loaded for post-mortem debugging, so that commands which only
+//! inspect the program (backtrace, print, x) work the same way for both.
+
+use nix::errno::Errno;
+use nix::libc::user_regs_struct;
+use std::cell::RefCell;
+use std::convert::TryInto;
+use std::rc::Rc;
+
+use crate::dwarf_data::{DwarfData, Line, Variable};
+use crate::expr::{CType, Evaluator, Session, Value};
+use crate::output;
+use crate::symbols::{self, SymbolTable};
+use serde_json::json;
+
+/// Follows the chain of frame pointers from the frame running at `rip` up to main, where
+/// `is_return_addr` tells whether `rip` is a return address rather than the current instruction.
+fn walk_stack<T: Target + ?Sized>(
+    target: &T,
+    debug_data: &DwarfData,
+    mut rip: usize,
+    mut rbp: usize,
+    is_return_addr: bool,
+) -> Result<Vec<Frame>, nix::Error> {
+    let mut frames = Vec::new();
+    loop {
+        let mut frame = Frame {
+            rip,
+            rbp,
+            function: None,
+            line: None,
+            is_return_addr: is_return_addr || !frames.is_empty(),
+        };
+        let lookup_addr = frame.lookup_addr();
+        frame.function = debug_data.get_function_from_addr(lookup_addr);
+        frame.line = debug_data.get_line_from_addr(lookup_addr);
+        // Without DWARF, main may still have a symbol
+        let name = frame.function.as_deref().or_else(|| {
+            debug_data.get_symbols().lookup(lookup_addr as u64).map(|(name, _)| name)
+        });
+        let is_main = name == Some("main");
+        frames.push(frame);
+        if is_main || rbp == 0 {
+            break;
+        }
+        match (target.read_word((rbp + 8) as u64), target.read_word(rbp as u64)) {
+            // rbp isn't a frame pointer at all in code that doesn't keep one (ld.so, libc), in
+            // which case the chain may go anywhere, even around in circles; callers' frames are
+            // always higher up the stack
+            (Ok(return_addr), Ok(saved_rbp)) if return_addr != 0 && saved_rbp as usize > rbp => {
+                rip = return_addr as usize;
+                rbp = saved_rbp as usize;
+            }
+            _ => break,
+        }
+    }
+    Ok(frames)
+}
+
+/// Names the code at `addr` by the ELF symbol containing it, "name+0xoffset", looking in the
+/// executable and then in `libraries`.
+fn describe_symbol(debug_data: &DwarfData, libraries: &[Rc<SymbolTable>], addr: usize)
+    -> Option<String> {
+    let mut tables = vec![debug_data.get_symbols()];
+    tables.extend(libraries.iter().map(|table| &**table));
+    symbols::describe(&tables, addr as u64)
+}
+
+/// One frame of the call stack.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub rip: usize,
+    pub rbp: usize,
+    pub function: Option<String>,
+    pub line: Option<Line>,
+    /// Whether rip is a return address, as in every frame but the innermost
+    pub is_return_addr: bool,
+}
+
+impl Frame {
+    /// Returns the address to look the frame's code up by. The return address of a caller may
+    /// already belong to the line after the call, or even to the next function when the call
+    /// is the last instruction of its own, so the call instruction is looked up instead.
+    pub fn lookup_addr(&self) -> usize {
+        if self.is_return_addr {
+            self.rip.wrapping_sub(1)
+        } else {
+            self.rip
+        }
+    }
+}
+
+pub trait Target {
+    /// Returns the general purpose registers of the stopped program.
+    fn regs(&self) -> Result<user_regs_struct, nix::Error>;
+
+    /// Reads `len` bytes of the program's memory starting at `addr`.
+    fn read_memory(&self, addr: u64, len: usize) -> Result<Vec<u8>, nix::Error>;
+
+    /// Calls the function at `addr` with integer and pointer arguments in `int_args` and the raw
+    /// bits of floating point arguments in `float_args`, returning rax and the low 64 bits of
+    /// xmm0. Only a live process can run code.
+    fn call_function(&self, _addr: u64, _int_args: &[u64], _float_args: &[u64])
+        -> Result<(u64, u64), String> {
+        Err("You can't do that without a process to debug.".to_string())
+    }
+
+    /// Returns the symbol tables of the shared libraries the program has loaded, to name code
+    /// without debugging information. Only a live process tells where they are loaded.
+    fn library_symbols(&self) -> Vec<Rc<SymbolTable>> {
+        Vec::new()
+    }
+
+    fn read_word(&self, addr: u64) -> Result<u64, nix::Error> {
+        let bytes = self.read_memory(addr, 8)?;
+        Ok(u64::from_le_bytes(bytes[..].try_into().unwrap()))
+    }
+
+    /// Walks the stack by following the chain of saved frame pointers, up to and including
+    /// main. The innermost frame comes first. The walk ends early if the chain leads somewhere
+    /// unreadable, as it can in code compiled without frame pointers.
+    fn backtrace(&self, debug_data: &DwarfData) -> Result<Vec<Frame>, nix::Error> {
+        let regs = self.regs()?;
+        walk_stack(self, debug_data, regs.rip as usize, regs.rbp as usize, false)
+    }
+
+    /// Returns the frames of the callers of a function stopped at its first instruction, whose
+    /// prologue hasn't pushed a frame yet: the return address is at the top of the stack and rbp
+    /// still belongs to the caller.
+    fn caller_frames(&self, debug_data: &DwarfData) -> Result<Vec<Frame>, nix::Error> {
+        let regs = self.regs()?;
+        let return_addr = self.read_word(regs.rsp)?;
+        walk_stack(self, debug_data, return_addr as usize, regs.rbp as usize, true)
+    }
+
+    fn print_backtrace(&self, debug_data: &DwarfData) -> Result<(), nix::Error> {
+        let libraries = self.library_symbols();
+        if output::is_json() {
+            let frames: Vec<_> = self
+                .backtrace(debug_data)?
+                .iter()
+                .map(|frame| {
+                    json!({
+                        "rip": frame.rip,
+                        "rbp": frame.rbp,
+                        "function": frame.function,
+                        "line": frame.line.as_ref().map(output::line),
+                        "symbol": describe_symbol(debug_data, &libraries, frame.lookup_addr()),
+                    })
+                })
+                .collect();
+            output::set_result(json!({ "frames": frames }));
+            return Ok(());
+        }
+        println!("%rip register: {:#x}", self.regs()?.rip);
+        for frame in self.backtrace(debug_data)? {
+            match (&frame.function, &frame.line) {
+                (Some(func), Some(line)) => println!("{} ({})", func, line),
+                // e.g. inside libc, which has no debugging info
+                _ => match describe_symbol(debug_data, &libraries, frame.lookup_addr()) {
+                    Some(symbol) => println!("{:#x} in {} (no debugging info)", frame.rip, symbol),
+                    None => println!("{:#x} (no debugging info)", frame.rip),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn print_stop(&self, debug_data: &DwarfData) -> Result<(), nix::Error> {
+        let rip = self.regs()?.rip as usize;
+        match (debug_data.get_function_from_addr(rip), debug_data.get_line_from_addr(rip)) {
+            (Some(func), Some(line)) => output::console(&format!("Stopped at {} ({})", func, line)),
+            _ => match describe_symbol(debug_data, &self.library_symbols(), rip) {
+                Some(symbol) => output::console(&format!(
+                    "Stopped at {:#x} in {} (no debugging info)",
+                    rip, symbol
+                )),
+                None => output::console(&format!("Stopped at {:#x} (no debugging info)", rip)),
+            },
+        }
+        Ok(())
+    }
+
+    /// Reads a variable in the frame whose frame pointer is `rbp` and formats its value.
+    fn read_variable(&self, debug_data: &DwarfData, var: &Variable, rbp: usize)
+        -> Result<String, nix::Error> {
+        let evaluator = Evaluator::new(self, debug_data, 0, rbp);
+        let value = evaluator.variable(var).map_err(|_| unmapped())?;
+        Ok(evaluator.format(&value))
+    }
+
+    /// Evaluates a C expression in the current frame, returning its value and the value
+    /// formatted for printing. `format` is a gdb format letter for integers, as in `print/x`.
+    fn evaluate(
+        &self,
+        debug_data: &DwarfData,
+        session: &RefCell<Session>,
+        expression: &str,
+        format: Option<char>,
+    ) -> Result<(Value, String), String> {
+        let (rip, rbp) = match self.regs() {
+            Ok(regs) => (regs.rip as usize, regs.rbp as usize),
+            // Before the program runs, expressions that don't need it can still be evaluated
+            Err(err) if err == no_process() => (0, 0),
+            Err(err) => return Err(format!("Cannot read registers: {}", err)),
+        };
+        let evaluator = Evaluator::new(self, debug_data, rip, rbp)
+            .with_format(format)
+            .with_session(session);
+        let value = evaluator.evaluate(expression)?;
+        let text = evaluator.format(&value);
+        Ok((value, text))
+    }
+
+    /// Evaluates a C expression in the current frame and prints its value, recording it in the
+    /// value history. With `quiet_void` (for `call`), void results are not printed.
+    fn print_expression(
+        &self,
+        debug_data: &DwarfData,
+        session: &RefCell<Session>,
+        expression: &str,
+        format: Option<char>,
+        quiet_void: bool,
+    ) {
+        let (value, text) = match self.evaluate(debug_data, session, expression, format) {
+            Ok((value, _)) if quiet_void && matches!(value.ctype, CType::Void) => return,
+            Ok(result) => result,
+            Err(msg) => return output::error(&msg),
+        };
+        let ctype = value.ctype.name(debug_data);
+        let number = session.borrow_mut().record(value);
+        if output::is_json() {
+            output::set_result(json!({
+                "expression": expression,
+                "value": text,
+                "type": ctype,
+                "history": number,
+            }));
+        } else {
+            println!("${} = {}", number, text);
+        }
+    }
+
+    /// Prints `count` units of `size` bytes starting at `addr`, gdb `x` style.
+    fn examine(&self, addr: u64, count: usize, format: char, size: usize)
+        -> Result<(), nix::Error> {
+        let bytes = self.read_memory(addr, count * size)?;
+        let values: Vec<String> = bytes
+            .chunks(size)
+            .map(|unit| {
+                let mut buf = [0u8; 8];
+                buf[..size].copy_from_slice(unit);
+                let value = u64::from_le_bytes(buf);
+                match format {
+                    'x' => format!("{:#0width$x}", value, width = 2 + 2 * size),
+                    'd' => format!("{}", sign_extend(value, size)),
+                    'u' => format!("{}", value),
+                    'c' => format!("{:?}", value as u8 as char),
+                    _ => format!("{:#x}", value),
+                }
+            })
+            .collect();
+        if output::is_json() {
+            output::set_result(json!({ "address": addr, "size": size, "values": values }));
+            return Ok(());
+        }
+        let per_line = 16 / size.max(1);
+        for (i, line) in values.chunks(per_line).enumerate() {
+            println!("{:#x}:\t{}", addr + (i * per_line * size) as u64, line.join("\t"));
+        }
+        Ok(())
+    }
+}
+
+/// Looks up a register by its name without the `$`, e.g. "rip".
+pub fn register_value(regs: &user_regs_struct, name: &str) -> Option<u64> {
+    Some(match name {
+        "rax" => regs.rax,
+        "rbx" => regs.rbx,
+        "rcx" => regs.rcx,
+        "rdx" => regs.rdx,
+        "rsi" => regs.rsi,
+        "rdi" => regs.rdi,
+        "rbp" | "fp" => regs.rbp,
+        "rsp" | "sp" => regs.rsp,
+        "r8" => regs.r8,
+        "r9" => regs.r9,
+        "r10" => regs.r10,
+        "r11" => regs.r11,
+        "r12" => regs.r12,
+        "r13" => regs.r13,
+        "r14" => regs.r14,
+        "r15" => regs.r15,
+        "rip" | "pc" => regs.rip,
+        "eflags" => regs.eflags,
+        "fs_base" => regs.fs_base,
+        "gs_base" => regs.gs_base,
+        _ => return None,
+    })
+}
+
+/// Returns whether `name` (without the `$`) names a register.
+pub fn is_register(name: &str) -> bool {
+    let regs: user_regs_struct = unsafe { std::mem::zeroed() };
+    register_value(&regs, name).is_some()
+}
+
+/// Stands in for the program when there is none, so that `set` and `print` can still work with
+/// convenience variables, the value history and constants, as in gdb.
+pub struct NoProcess;
+
+impl Target for NoProcess {
+    fn regs(&self) -> Result<user_regs_struct, nix::Error> {
+        Err(no_process())
+    }
+
+    fn read_memory(&self, _addr: u64, _len: usize) -> Result<Vec<u8>, nix::Error> {
+        Err(unmapped())
+    }
+}
+
+/// Returns the error NoProcess reports for its registers.
+pub fn no_process() -> nix::Error {
+    nix::Error::Sys(Errno::ESRCH)
+}
+
+/// Returns the error a Target reports when reading memory that isn't mapped.
+pub fn unmapped() -> nix::Error {
+    nix::Error::Sys(Errno::EFAULT)
+}
+
+pub fn sign_extend(value: u64, size: usize) -> i64 {
+    let shift = 64 - 8 * size as u32;
+    ((value << shift) as i64) >> shift
+}
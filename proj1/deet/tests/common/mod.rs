@@ -0,0 +1,28 @@
+//! Helpers shared by the integration tests.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static BUILDS: AtomicUsize = AtomicUsize::new(0);
+
+/// Compiles samples/`name`.c the way the Makefile does, into a temporary directory so that the
+/// source tree stays clean, and returns the path of the program.
+pub fn build_sample(name: &str) -> PathBuf {
+    let source = Path::new(env!("CARGO_MANIFEST_DIR")).join("samples").join(format!("{}.c", name));
+    let dir = env::temp_dir().join(format!("deet-tests-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    // Tests run in parallel and may build the same sample
+    let program = dir.join(format!("{}-{}", name, BUILDS.fetch_add(1, Ordering::SeqCst)));
+    let cc = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let status = Command::new(cc)
+        .args(&["-O0", "-g", "-gdwarf-4", "-no-pie", "-fno-omit-frame-pointer", "-o"])
+        .arg(&program)
+        .arg(&source)
+        .status()
+        .expect("could not run the C compiler");
+    assert!(status.success(), "could not compile {}", source.display());
+    program
+}
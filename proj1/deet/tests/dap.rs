@@ -0,0 +1,187 @@
+//! Drives `deet --dap` the way an editor would, over Content-Length framed JSON on its stdin and
+//! stdout.
+
+mod common;
+
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+struct DapClient {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    seq: i64,
+    /// Events read while waiting for something else
+    events: VecDeque<Value>,
+}
+
+impl DapClient {
+    fn start(program: &Path) -> DapClient {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_deet"))
+            .arg("--dap")
+            .arg(program)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("could not start deet");
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        DapClient { child, stdin, stdout, seq: 0, events: VecDeque::new() }
+    }
+
+    fn read_message(&mut self) -> Value {
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            assert!(self.stdout.read_line(&mut header).unwrap() > 0, "deet closed stdout");
+            let header = header.trim();
+            if header.is_empty() {
+                break;
+            }
+            if header.starts_with("Content-Length:") {
+                content_length = header["Content-Length:".len()..].trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0u8; content_length];
+        self.stdout.read_exact(&mut body).unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// Sends a request and returns its response, keeping the events that arrive meanwhile.
+    fn request(&mut self, command: &str, arguments: Value) -> Value {
+        self.seq += 1;
+        let body = json!({
+            "seq": self.seq,
+            "type": "request",
+            "command": command,
+            "arguments": arguments,
+        })
+        .to_string();
+        write!(self.stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        self.stdin.flush().unwrap();
+        loop {
+            let message = self.read_message();
+            if message["type"] == "response" && message["request_seq"] == self.seq {
+                return message;
+            }
+            if message["type"] == "event" {
+                self.events.push_back(message);
+            }
+        }
+    }
+
+    /// Waits for the next event called `name`, skipping others (such as program output).
+    fn event(&mut self, name: &str) -> Value {
+        while let Some(event) = self.events.pop_front() {
+            if event["event"] == name {
+                return event;
+            }
+        }
+        loop {
+            let message = self.read_message();
+            if message["type"] == "event" && message["event"] == name {
+                return message;
+            }
+        }
+    }
+
+    fn stack_trace(&mut self) -> Vec<Value> {
+        let response = self.request("stackTrace", json!({ "threadId": 0 }));
+        assert_eq!(response["success"], true);
+        response["body"]["stackFrames"].as_array().unwrap().clone()
+    }
+
+    fn variables(&mut self, reference: u64) -> Vec<Value> {
+        let response = self.request("variables", json!({ "variablesReference": reference }));
+        assert_eq!(response["success"], true, "{}", response);
+        response["body"]["variables"].as_array().unwrap().clone()
+    }
+
+    /// Launches the program with breakpoints at `lines` of samples/`source` and runs it to the
+    /// first stop.
+    fn launch(&mut self, source: &str, lines: &[u64]) -> Value {
+        assert_eq!(self.request("initialize", json!({ "adapterID": "deet" }))["success"], true);
+        assert_eq!(self.request("launch", json!({}))["success"], true);
+        self.event("initialized");
+        let breakpoints: Vec<Value> = lines.iter().map(|line| json!({ "line": line })).collect();
+        let response = self.request(
+            "setBreakpoints",
+            json!({ "source": { "path": source }, "breakpoints": breakpoints }),
+        );
+        for breakpoint in response["body"]["breakpoints"].as_array().unwrap() {
+            assert_eq!(breakpoint["verified"], true);
+        }
+        self.request("configurationDone", json!({}));
+        self.event("stopped")
+    }
+}
+
+impl Drop for DapClient {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn value_of<'a>(variables: &'a [Value], name: &str) -> &'a Value {
+    &variables.iter().find(|var| var["name"] == name).unwrap()["value"]
+}
+
+#[test]
+fn breakpoint_stack_and_variables() {
+    let program = common::build_sample("function_calls");
+    let mut client = DapClient::start(&program);
+    // int sum = a + b; in func2
+    let stopped = client.launch("function_calls.c", &[11]);
+    assert_eq!(stopped["body"]["reason"], "breakpoint");
+
+    let frames = client.stack_trace();
+    let names: Vec<&str> = frames.iter().map(|frame| frame["name"].as_str().unwrap()).collect();
+    assert_eq!(names, ["func2", "func1", "main"]);
+    assert_eq!(frames[0]["line"], 11);
+
+    let scopes = client.request("scopes", json!({ "frameId": 0 }));
+    let locals = scopes["body"]["scopes"][0]["variablesReference"].as_u64().unwrap();
+    let variables = client.variables(locals);
+    assert_eq!(value_of(&variables, "a"), "42");
+    assert_eq!(value_of(&variables, "b"), "5");
+    let globals = scopes["body"]["scopes"][1]["variablesReference"].as_u64().unwrap();
+    assert_eq!(value_of(&client.variables(globals), "global"), "5");
+    // The caller's locals, as seen from its own frame
+    assert_eq!(value_of(&client.variables(2), "a"), "42");
+    assert_eq!(client.request("variables", json!({ "variablesReference": 0 }))["success"], false);
+    assert_eq!(client.request("variables", json!({ "variablesReference": 9 }))["success"], false);
+
+    let result = client.request("evaluate", json!({ "expression": "a + b", "frameId": 0 }));
+    assert_eq!(result["body"]["result"], "47");
+
+    assert_eq!(client.request("continue", json!({ "threadId": 0 }))["success"], true);
+    assert_eq!(client.event("exited")["body"]["exitCode"], 0);
+    client.event("terminated");
+    client.request("disconnect", json!({}));
+}
+
+#[test]
+fn next_steps_over_recursive_calls() {
+    let program = common::build_sample("factorial");
+    let mut client = DapClient::start(&program);
+    // int result = n * factorial(n - 1);
+    client.launch("factorial.c", &[7]);
+    let depth = client.stack_trace().len();
+    // Without the breakpoint, the recursive call runs to completion
+    let no_breakpoints = json!({ "source": { "path": "factorial.c" }, "breakpoints": [] });
+    client.request("setBreakpoints", no_breakpoints);
+    client.request("next", json!({ "threadId": 0 }));
+    assert_eq!(client.event("stopped")["body"]["reason"], "step");
+
+    let frames = client.stack_trace();
+    assert_eq!(frames.len(), depth);
+    assert_eq!(frames[0]["line"], 8);
+    let variables = client.variables(1);
+    assert_eq!(value_of(&variables, "n"), "5");
+    assert_eq!(value_of(&variables, "result"), "120");
+    client.request("disconnect", json!({}));
+}
@@ -0,0 +1,129 @@
+//! Runs the reverse commands over recordings of the samples, with deet in batch mode.
+
+mod common;
+
+use std::process::Command;
+
+/// Runs deet on `program` with `commands`, returning what it printed.
+fn run_batch(program: &str, commands: &[&str]) -> String {
+    let program = common::build_sample(program);
+    let mut deet = Command::new(env!("CARGO_BIN_EXE_deet"));
+    deet.arg(&program).args(&["-batch", "-nx"]);
+    for command in commands {
+        deet.args(&["-ex", command]);
+    }
+    let output = deet.output().expect("could not run deet");
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Returns the lines of `output` starting with `prefix`.
+fn lines_starting<'a>(output: &'a str, prefix: &str) -> Vec<&'a str> {
+    output.lines().filter(|line| line.starts_with(prefix)).collect()
+}
+
+#[test]
+fn reverse_continue_and_step() {
+    let output = run_batch(
+        "function_calls",
+        &[
+            "break main",
+            "run",
+            "break func3",
+            "record",
+            // func3 is called from func2, then from func1
+            "continue",
+            "continue",
+            "backtrace",
+            "reverse-continue",
+            "backtrace",
+            // Back over the call to func3 on line 13, to printf("sum = %d\n", sum)
+            "reverse-step",
+            "reverse-step",
+            "print sum",
+            // Forward again through both calls, replaying, then to the end, recording
+            "continue",
+            "backtrace",
+            "continue",
+            "backtrace",
+            "continue",
+        ],
+    );
+    let backtraces: Vec<&str> = output
+        .split("%rip register")
+        .skip(1)
+        .map(|backtrace| backtrace.lines().nth(2).unwrap_or(""))
+        .collect();
+    assert_eq!(backtraces.len(), 4, "{}", output);
+    // At its entry, func3's caller has not pushed a frame, so the frame after it is func2's caller
+    assert!(backtraces[0].starts_with("main ("), "{}", output);
+    assert!(backtraces[1].starts_with("func1 ("), "{}", output);
+    assert!(backtraces[2].starts_with("func1 ("), "{}", output);
+    assert!(backtraces[3].starts_with("main ("), "{}", output);
+    let stops = lines_starting(&output, "Stopped at func2");
+    assert_eq!(stops.len(), 2, "{}", output);
+    assert!(stops[0].ends_with("function_calls.c:13)"), "{}", output);
+    assert!(stops[1].ends_with("function_calls.c:12)"), "{}", output);
+    assert!(output.contains("$1 = 47"), "{}", output);
+    // Replaying emulates the writes instead of printing again
+    assert_eq!(lines_starting(&output, "sum = 47").len(), 1, "{}", output);
+    assert!(output.contains("Process exited with code 0"), "{}", output);
+}
+
+#[test]
+fn reverse_past_the_start() {
+    let output = run_batch(
+        "count",
+        &[
+            "break main",
+            "run",
+            "record",
+            "break 6",
+            "break 8",
+            "continue",
+            "continue",
+            "reverse-continue",
+            // Back to main's breakpoint, where recording started, then no further
+            "reverse-continue",
+            "reverse-stepi",
+            "print $rip",
+            // Forward again, replaying
+            "continue",
+        ],
+    );
+    assert!(!output.contains("Undefined command"), "{}", output);
+    let lines: Vec<&str> = lines_starting(&output, "Stopped at main (")
+        .iter()
+        .map(|stop| stop.rsplit(':').next().unwrap())
+        .collect();
+    assert_eq!(lines, ["3)", "6)", "8)", "6)", "3)", "3)", "6)"], "{}", output);
+    let history = lines_starting(&output, "No more reverse-execution history.");
+    assert_eq!(history.len(), 1, "{}", output);
+    // At the start of the recording, back before the breakpoint's int3
+    assert!(output.contains("$1 = (void (*)()) 0x"), "{}", output);
+    assert!(lines_starting(&output, "$1 = ")[0].ends_with(" <main>"), "{}", output);
+}
+
+#[test]
+fn record_keeps_at_most_the_limit() {
+    let output = run_batch(
+        "function_calls",
+        &[
+            "set record full insn-number-max 100",
+            "break main",
+            "run",
+            "break func3",
+            "record",
+            "continue",
+            "continue",
+            "info record",
+            "reverse-continue",
+        ],
+    );
+    let recorded = lines_starting(&output, "Recorded ");
+    assert_eq!(recorded.len(), 1, "{}", output);
+    let count: usize = recorded[0]["Recorded ".len()..].split(' ').next().unwrap().parse().unwrap();
+    assert!(count <= 100, "{}", output);
+    assert!(output.contains("Max recorded instructions: 100"), "{}", output);
+    // The first call to func3 has been dropped from the log
+    assert!(output.contains("No more reverse-execution history."), "{}", output);
+}